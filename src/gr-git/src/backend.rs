@@ -0,0 +1,78 @@
+/// Backends answer the read-only questions `Git` asks about a repository.
+/// Mutating commands (rebase, pull, push, ...) always go through the git executable.
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use crate::cli_backend::CliBackend;
+use crate::git2_backend::Git2Backend;
+
+/// Environment variable used to pick a backend at runtime: `cli` or `libgit2`
+pub const BACKEND_ENV_VAR: &str = "GR_GIT_BACKEND";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Spawn a `git` process for every query
+    Cli,
+    /// Answer queries in-process via libgit2
+    Libgit2,
+}
+
+impl Backend {
+    /// Picks the backend requested via `GR_GIT_BACKEND`, defaulting to libgit2
+    pub fn from_env() -> Result<Backend> {
+        match std::env::var(BACKEND_ENV_VAR) {
+            Ok(name) => Backend::parse(&name),
+            Err(_) => Ok(Backend::Libgit2),
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Backend> {
+        match name.trim().to_lowercase().as_str() {
+            "cli" | "git" => Ok(Backend::Cli),
+            "libgit2" | "git2" | "" => Ok(Backend::Libgit2),
+            other => Err(anyhow!("Unknown {} '{}' - expected 'cli' or 'libgit2'", BACKEND_ENV_VAR, other)),
+        }
+    }
+
+    pub(crate) fn build(&self) -> Box<dyn GitBackend> {
+        match self {
+            Backend::Cli => Box::new(CliBackend::new()),
+            Backend::Libgit2 => match Git2Backend::open() {
+                Some(b) => Box::new(b),
+                // Not in a repo (or libgit2 can't read it) - let git report the problem
+                None => Box::new(CliBackend::new()),
+            }
+        }
+    }
+}
+
+pub(crate) trait GitBackend {
+    /// Is the current directory inside a git work tree?
+    fn in_repo(&self) -> Result<bool>;
+
+    /// Short name of the checked out branch - or "HEAD" when detached
+    fn current_branch(&self) -> Result<String>;
+
+    /// Short names of all local branches, sorted by name
+    fn branches(&self) -> Result<Vec<String>>;
+
+    /// Map of local branch -> upstream branch, as displayed by `git branch -vv`.
+    /// Branches without an upstream map to an empty string.
+    fn upstreams(&self) -> Result<HashMap<String, String>>;
+
+    /// Map of branch -> value for every `branch.<branch>.<key>` set in git config
+    fn branch_config(&self, key: &str) -> Result<HashMap<String, String>>;
+
+    /// Value of a git config key, if set
+    fn config_get(&self, key: &str) -> Result<Option<String>>;
+
+    /// Map of local branch -> sha of the commit at its tip
+    fn tips(&self) -> Result<HashMap<String, String>>;
+
+    fn rev_parse(&self, args: Vec<&str>) -> Result<String>;
+
+    /// Is `ancestor` reachable from `descendant`? (a commit is its own ancestor)
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool>;
+
+    /// Commits in `branch` but not `parent`, formatted like `git log --format=oneline`
+    fn commit_diff(&self, branch: &str, parent: &str) -> Result<String>;
+}
@@ -1,10 +1,20 @@
 use std::collections::HashMap;
 use anyhow::Result;
-use regex::{Match, Regex};
 use crate::Git;
 
 // TODO: Standardize on Vec<String> for args
 
+/// Stack parents live in `branch.<name>.gr-parent`, independent of upstream tracking
+pub const PARENT_CONFIG_KEY: &str = "gr-parent";
+
+/// The parent commit a branch was last stacked on lives in `branch.<name>.gr-base`.
+/// Restacks replay only the commits after it, so amending or squashing a parent
+/// doesn't drag the parent's old commits along.
+pub const BASE_CONFIG_KEY: &str = "gr-base";
+
+/// Set once upstream-based parents have been imported into `gr-parent` config
+const PARENTS_MIGRATED_KEY: &str = "gr.parentsMigrated";
+
 pub enum BranchType {
     Local,
     Remote,
@@ -16,12 +26,66 @@ impl Git {
 
     pub fn checkout(&self, args: Vec<&str>) -> Result<()> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("checkout", args)?;
         Ok(())
     }
 
+    /// Creates `branch` on top of `parent` and records `parent` as its stack parent
+    pub fn create_branch(&self, branch: &str, parent: &str) -> Result<()> {
+        self.checkout(vec!["-b", branch, parent])?;
+        self.set_parent(branch, parent)?;
+        self.set_base(branch, &self.rev_parse(vec![parent])?)
+    }
+
+    /// Records `sha` as the parent commit `branch` is stacked on
+    pub fn set_base(&self, branch: &str, sha: &str) -> Result<()> {
+        self.assert_in_repo()?;
+        self.invalidate_stack_graph();
+        self.git("config", vec![&base_key(branch), sha])?;
+        Ok(())
+    }
+
+    /// Records `parent` as the stack parent of `branch`
+    pub fn set_parent(&self, branch: &str, parent: &str) -> Result<()> {
+        self.assert_in_repo()?;
+        self.invalidate_stack_graph();
+        self.git("config", vec![&parent_key(branch), parent])?;
+        Ok(())
+    }
+
+    /// Removes `branch` from its stack, making it a root
+    pub fn unset_parent(&self, branch: &str) -> Result<()> {
+        self.assert_in_repo()?;
+        self.invalidate_stack_graph();
+        self.unset_branch_config(branch, PARENT_CONFIG_KEY);
+        Ok(())
+    }
+
+    /// Imports stack parents from upstream tracking - the way gr recorded stacks before
+    /// `gr-parent` existed. Only runs once per repo; returns the (branch, parent) pairs imported.
+    pub fn migrate_parents(&self) -> Result<Vec<(String, String)>> {
+        self.assert_in_repo()?;
+        if self.backend.config_get(PARENTS_MIGRATED_KEY)?.is_some() { return Ok(Vec::new()); }
+
+        let branches = self.branches()?;
+        let recorded = self.backend.branch_config(PARENT_CONFIG_KEY)?;
+        let mut migrated = Vec::new();
+        for (branch, upstream) in self.backend.upstreams()? {
+            // Only local upstreams were stack parents - remote ones are plain tracking branches
+            if !branches.contains(&upstream) || recorded.contains_key(&branch) { continue; }
+            self.set_parent(&branch, &upstream)?;
+            migrated.push((branch, upstream));
+        }
+        migrated.sort();
+
+        self.git("config", vec![PARENTS_MIGRATED_KEY, "true"])?;
+        Ok(migrated)
+    }
+
     pub fn switch(&self, branch: &str) -> Result<()> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("switch", vec![branch])?;
         Ok(())
     }
@@ -29,100 +93,88 @@ impl Git {
     /**** Information ***/
     pub fn current_branch(&self) -> Result<String> {
         self.assert_in_repo()?;
-        self.git("rev-parse", vec!["--abbrev-ref", "HEAD"])
+        self.backend.current_branch()
     }
 
     pub fn root_branches(&self) -> Result<Vec<String>> {
-        self.assert_in_repo()?;
-        let mut roots = Vec::new();
-        for b in self.branches()? {
-            if self.parent_of(&b, BranchType::Local)?.is_none() { roots.push(b.clone()) }
-        }
-        Ok(roots)
+        Ok(self.stack_graph()?.roots())
     }
 
     pub fn branches(&self) -> Result<Vec<String>> {
         self.assert_in_repo()?;
-        let output = self.git("for-each-ref", vec!["--format=%(refname:short)", "refs/heads/"])?
-            .lines()
-            .map(|s| s.to_string())
-            .collect();
-
-        Ok(output)
+        self.backend.branches()
     }
 
     pub fn branch(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("branch", args)
     }
 
+    /// Map of every local branch to its stack parent ("" for branches without one)
     pub fn parents(&self) -> Result<HashMap<String, String>> {
-        // Formatted output of `git branch -vv` looks like this:
-        //   main      483f881 Add: Branch movement commands
-        // * movements 483f881 [main] Add: Branch movement commands
-        //
-        // Our regex here extracts the parent branch name from the output.
-        // First group is the name of the branch, second group is the parent branch name
         self.assert_in_repo()?;
-        let parent_regex = Regex::new(r"\s*(\S+\s+[a-f0-9]+)(\s+\[(.*)\])?\s+(.*)")?;
-
-        let output = self.branch(vec!["-vv"])?
-            .lines()
-            .map(|s| parent_regex.captures(s))
-            .filter(|cap| cap.is_some())
-            .map(|cap| cap.unwrap())
-            .map (|cap| (
-                cap.get(1)
-                    .unwrap().as_str()
-                    .split(" ")
-                    .into_iter()
-                    .filter(|s| *s != "*")
-                    .next()
-                    .unwrap().to_string(),
-                cap.get(3)))
-            .map (|(name, parent)| (name, self.extract_parent(parent)))
-            .collect::<HashMap<String, String>>();
-
+        let mut parents = self.backend.branch_config(PARENT_CONFIG_KEY)?;
+        let output = self.branches()?
+            .into_iter()
+            .map(|b| { let p = parents.remove(&b).unwrap_or_default(); (b, p) })
+            .collect();
         Ok(output)
     }
 
-    pub fn parent_of(&self, branch: &str, branch_type: BranchType) -> Result<Option<String>> {
+    /// Map of every local branch to the branch it tracks ("" for branches without one)
+    pub fn upstreams(&self) -> Result<HashMap<String, String>> {
         self.assert_in_repo()?;
-        let maybe_parent = self.parents()?.get(branch).cloned();
-        let parent_is_remote = maybe_parent.is_some() && !self.branches()?.contains(maybe_parent.as_ref().unwrap());
-
-        match maybe_parent {
-            None => Ok(None),
-            Some(parent) => {
-                match branch_type {
-                    BranchType::All => Ok(Some(parent)),
-                    BranchType::Local => if parent_is_remote { Ok(None) } else { Ok(Some(parent)) },
-                    BranchType::Remote => if parent_is_remote { Ok(Some(parent)) } else { Ok(None) }
-                }
-            }
-        }
+        self.backend.upstreams()
     }
 
-    fn extract_parent(&self, parent: Option<Match>) -> String {
-        match parent {
-            Some(parent) => {
-                parent.as_str().split(":").next().unwrap().to_string()
-            },
-            None => "".to_string(),
-        }
+    pub fn parent_of(&self, branch: &str, branch_type: BranchType) -> Result<Option<String>> {
+        Ok(self.stack_graph()?.parent_of(branch, branch_type))
     }
 
     /// Returns all direct children of the given branch
     pub fn children_of(&self, branch: &str) -> Result<Vec<String>> {
-        self.assert_in_repo()?;
-        // invert `parents` and take all children that belong to `branch` directly
-        let output = self.parents()?
-            .into_iter()
-            .filter(|(_name, parent)| parent == branch)
-            .map(|(name, _parent)| name)
-            .filter (|name| !name.is_empty())
-            .collect::<Vec<String>>();
+        Ok(self.stack_graph()?.children_of(branch))
+    }
+}
 
-        Ok(output)
+fn parent_key(branch: &str) -> String {
+    format!("branch.{}.{}", branch, PARENT_CONFIG_KEY)
+}
+
+fn base_key(branch: &str) -> String {
+    format!("branch.{}.{}", branch, BASE_CONFIG_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    #[test]
+    fn test_migrate_parents() {
+        let repo = TestRepo::new();
+        repo.commit("README");
+        repo.add_remote("origin");
+        repo.git(&["branch", "--quiet", "--track", "a", "main"]);
+        repo.git(&["branch", "--quiet", "--track", "b", "a"]);
+        // Plain tracking branches aren't stacked on anything
+        repo.git(&["branch", "--quiet", "--track", "tracking", "origin/main"]);
+        // Parents recorded already win
+        repo.git(&["branch", "--quiet", "--track", "recorded", "main"]);
+        repo.git(&["config", "branch.recorded.gr-parent", "a"]);
+
+        let git = Git::new();
+        assert_eq!(git.migrate_parents().unwrap(), vec![("a".to_string(), "main".to_string()), ("b".to_string(), "a".to_string())]);
+        let parents = git.parents().unwrap();
+        assert_eq!(parents["b"], "a");
+        assert_eq!(parents["tracking"], "");
+        assert_eq!(parents["recorded"], "a");
+        assert_eq!(repo.git(&["config", PARENTS_MIGRATED_KEY]), "true");
+
+        // Only ever once
+        repo.git(&["branch", "--quiet", "--track", "later", "main"]);
+        assert!(git.migrate_parents().unwrap().is_empty());
+        assert_eq!(git.parents().unwrap()["later"], "");
     }
-}
\ No newline at end of file
+}
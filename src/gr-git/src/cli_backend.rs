@@ -0,0 +1,144 @@
+/// Answers repository queries by spawning the git executable
+use std::collections::HashMap;
+use std::process::Command;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use regex::{Match, Regex};
+use crate::backend::GitBackend;
+
+pub(crate) struct CliBackend;
+
+impl CliBackend {
+    pub fn new() -> CliBackend {
+        CliBackend {}
+    }
+
+    fn extract_parent(&self, parent: Option<Match>) -> String {
+        match parent {
+            Some(parent) => {
+                parent.as_str().split(':').next().unwrap().to_string()
+            },
+            None => "".to_string(),
+        }
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn in_repo(&self) -> Result<bool> {
+        let output = run_git("rev-parse", vec!["--is-inside-work-tree"])?;
+        Ok(output == "true")
+    }
+
+    fn current_branch(&self) -> Result<String> {
+        // Unlike `rev-parse --abbrev-ref`, this still names the branch before its first commit
+        match run_git("symbolic-ref", vec!["--short", "-q", "HEAD"]) {
+            Ok(branch) => Ok(branch),
+            // Detached - fails outside a repo all the same
+            Err(_) => run_git("rev-parse", vec!["--verify", "-q", "HEAD"]).map(|_| "HEAD".to_string()),
+        }
+    }
+
+    fn branches(&self) -> Result<Vec<String>> {
+        let output = run_git("for-each-ref", vec!["--format=%(refname:short)", "refs/heads/"])?
+            .lines()
+            .map(|s| s.to_string())
+            .collect();
+
+        Ok(output)
+    }
+
+    fn upstreams(&self) -> Result<HashMap<String, String>> {
+        // Formatted output of `git branch -vv` looks like this:
+        //   main      483f881 Add: Branch movement commands
+        // * movements 483f881 [main] Add: Branch movement commands
+        //
+        // Our regex here extracts the parent branch name from the output.
+        // First group is the name of the branch, second group is the parent branch name
+        let parent_regex = Regex::new(r"\s*(\S+\s+[a-f0-9]+)(\s+\[(.*)\])?\s+(.*)")?;
+
+        let output = run_git("branch", vec!["-vv"])?
+            .lines()
+            .filter_map(|s| parent_regex.captures(s))
+            .map (|cap| (
+                cap.get(1)
+                    .unwrap().as_str()
+                    .split(' ')
+                    .find(|s| *s != "*")
+                    .unwrap().to_string(),
+                cap.get(3)))
+            .map (|(name, parent)| (name, self.extract_parent(parent)))
+            .collect::<HashMap<String, String>>();
+
+        Ok(output)
+    }
+
+    fn branch_config(&self, key: &str) -> Result<HashMap<String, String>> {
+        let pattern = format!(r"^branch\..*\.{}$", regex::escape(key));
+        let suffix = format!(".{}", key);
+
+        // `git config` exits 1 when nothing matches - which just means nothing is set
+        let output = run_git("config", vec!["--get-regexp", &pattern]).unwrap_or_default();
+        let values = output.lines()
+            .filter_map(|l| l.split_once(' '))
+            .filter_map(|(k, v)| {
+                let branch = k.strip_prefix("branch.")?.strip_suffix(&suffix)?;
+                Some((branch.to_string(), v.to_string()))
+            })
+            .collect();
+
+        Ok(values)
+    }
+
+    fn config_get(&self, key: &str) -> Result<Option<String>> {
+        // exit code 1 == key not set
+        Ok(run_git("config", vec!["--get", key]).ok())
+    }
+
+    fn tips(&self) -> Result<HashMap<String, String>> {
+        let output = run_git("for-each-ref", vec!["--format=%(refname:short) %(objectname)", "refs/heads/"])?
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .map(|(name, sha)| (name.to_string(), sha.to_string()))
+            .collect();
+
+        Ok(output)
+    }
+
+    fn rev_parse(&self, args: Vec<&str>) -> Result<String> {
+        run_git("rev-parse", args)
+    }
+
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        // exits 0 when it is an ancestor, 1 when it isn't
+        Ok(run_git("merge-base", vec!["--is-ancestor", ancestor, descendant]).is_ok())
+    }
+
+    fn commit_diff(&self, branch: &str, parent: &str) -> Result<String> {
+        let dotdot= format!("{}..{}", parent, branch);
+        run_git("log", vec![&dotdot, "--format=oneline"])
+    }
+}
+
+pub(crate) fn run_git(command: &str, args: Vec<&str>) -> Result<String> {
+    // Execute the git executable with the given command and arguments
+    // and return the output
+    let mut g = Command::new("git");
+    let mut cmd = g.arg(command);
+
+    if !args.is_empty() { cmd = cmd.args(args.clone()); }
+
+    // Execute the command and get the output
+    let output = cmd.output()?;
+
+    // Check the exit code
+    if !output.status.success() {
+        let msg = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(anyhow!("{}\n{}", format!("> git {} {}", command, args.join(" ")).red(), msg.yellow()));
+    }
+
+    let out = String::from_utf8_lossy(&output.stdout).to_string();
+    let err = String::from_utf8_lossy(&output.stderr).to_string();
+    let text = out + "\n" + &err;
+    let text = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect::<Vec<&str>>().join("\n");
+    Ok(text)
+}
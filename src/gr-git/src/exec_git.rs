@@ -4,6 +4,12 @@ use exec::Command;
 
 pub struct ExecGit;
 
+impl Default for ExecGit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ExecGit {
     pub fn new() -> ExecGit {
         ExecGit {}
@@ -24,13 +30,18 @@ impl ExecGit {
         Ok(())
     }
 
+    pub fn rebase(&self, args: Vec<String>) -> Result<()> {
+        self.git("rebase", args)?;
+        Ok(())
+    }
+
     // TODO: git mergetool?
 
     fn git(&self, command: &str, args: Vec<String>) -> Result<()> {
         let mut cmd = Command::new("git");
         cmd.arg(command);
         if !args.is_empty() {
-            cmd.args(&*args);
+            cmd.args(&args);
         }
         let err = cmd.exec();
 
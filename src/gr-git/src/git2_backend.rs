@@ -0,0 +1,217 @@
+/// Answers repository queries in-process via libgit2 - no `git` processes are spawned
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use git2::{ErrorCode, Repository};
+use crate::backend::GitBackend;
+use crate::cli_backend::run_git;
+
+pub(crate) struct Git2Backend {
+    repo: Repository,
+}
+
+impl Git2Backend {
+    /// Opens the repository containing the current directory (honoring GIT_DIR & friends)
+    pub fn open() -> Option<Git2Backend> {
+        Repository::open_from_env().ok().map(|repo| Git2Backend { repo })
+    }
+}
+
+impl GitBackend for Git2Backend {
+    fn in_repo(&self) -> Result<bool> {
+        Ok(!self.repo.is_bare())
+    }
+
+    fn current_branch(&self) -> Result<String> {
+        let head = match self.repo.head() {
+            Ok(head) => head,
+            // No commits yet - HEAD still names the branch they'll go on
+            Err(e) if e.code() == ErrorCode::UnbornBranch => {
+                let head = self.repo.find_reference("HEAD")?;
+                return match head.symbolic_target() {
+                    Some(target) => Ok(short_ref_name(target)),
+                    None => Err(anyhow!("HEAD points at a branch with a non-utf8 name")),
+                };
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !head.is_branch() { return Ok("HEAD".to_string()); }
+
+        match head.shorthand() {
+            Some(name) => Ok(name.to_string()),
+            None => Err(anyhow!("HEAD points at a branch with a non-utf8 name")),
+        }
+    }
+
+    fn branches(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for branch in self.repo.branches(Some(git2::BranchType::Local))? {
+            let (branch, _) = branch?;
+            if let Some(name) = branch.name()? { names.push(name.to_string()); }
+        }
+        // Match `git for-each-ref` ordering
+        names.sort();
+        Ok(names)
+    }
+
+    fn upstreams(&self) -> Result<HashMap<String, String>> {
+        let mut upstreams = HashMap::new();
+        for name in self.branches()? {
+            let refname = format!("refs/heads/{}", name);
+            let upstream = match self.repo.branch_upstream_name(&refname) {
+                Ok(buf) => short_ref_name(buf.as_str().unwrap_or("")),
+                Err(e) if e.code() == ErrorCode::NotFound => String::new(),
+                Err(e) => return Err(e.into()),
+            };
+            upstreams.insert(name, upstream);
+        }
+        Ok(upstreams)
+    }
+
+    fn branch_config(&self, key: &str) -> Result<HashMap<String, String>> {
+        let config = self.repo.config()?.snapshot()?;
+        let pattern = format!(r"^branch\..*\.{}$", regex::escape(key));
+        let suffix = format!(".{}", key);
+
+        let mut values = HashMap::new();
+        let mut entries = config.entries(Some(&pattern))?;
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            let (Some(name), Some(value)) = (entry.name(), entry.value()) else { continue };
+            // git lowercases the section and key but keeps the branch name as is
+            let branch = name.strip_prefix("branch.").and_then(|n| n.strip_suffix(&suffix.to_lowercase()));
+            if let Some(branch) = branch {
+                values.insert(branch.to_string(), value.to_string());
+            }
+        }
+        Ok(values)
+    }
+
+    fn config_get(&self, key: &str) -> Result<Option<String>> {
+        let config = self.repo.config()?.snapshot()?;
+        match config.get_string(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn tips(&self) -> Result<HashMap<String, String>> {
+        let mut tips = HashMap::new();
+        for branch in self.repo.branches(Some(git2::BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = match branch.name()? {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let sha = branch.get().peel_to_commit()?.id().to_string();
+            tips.insert(name, sha);
+        }
+        Ok(tips)
+    }
+
+    fn rev_parse(&self, args: Vec<&str>) -> Result<String> {
+        // Only plain revisions are resolved in-process, anything fancier goes to git
+        match args.as_slice() {
+            [spec] if !spec.starts_with('-') => Ok(self.repo.revparse_single(spec)?.id().to_string()),
+            _ => run_git("rev-parse", args),
+        }
+    }
+
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        let ancestor = self.repo.revparse_single(ancestor)?.peel_to_commit()?.id();
+        let descendant = self.repo.revparse_single(descendant)?.peel_to_commit()?.id();
+        Ok(ancestor == descendant || self.repo.graph_descendant_of(descendant, ancestor)?)
+    }
+
+    fn commit_diff(&self, branch: &str, parent: &str) -> Result<String> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push(self.repo.revparse_single(branch)?.peel_to_commit()?.id())?;
+        walk.hide(self.repo.revparse_single(parent)?.peel_to_commit()?.id())?;
+
+        let mut lines = Vec::new();
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            lines.push(format!("{} {}", commit.id(), commit.summary().unwrap_or("")).trim().to_string());
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Shortens a full ref name the way `git branch -vv` displays upstreams
+/// e.g. refs/heads/main => main, refs/remotes/origin/main => origin/main
+fn short_ref_name(refname: &str) -> String {
+    refname.strip_prefix("refs/heads/")
+        .or_else(|| refname.strip_prefix("refs/remotes/"))
+        .unwrap_or(refname)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli_backend::CliBackend;
+    use crate::PARENT_CONFIG_KEY;
+    use crate::test_repo::TestRepo;
+
+    /// Both backends, opened on the current repo as it is now
+    fn backends() -> (CliBackend, Git2Backend) {
+        (CliBackend::new(), Git2Backend::open().unwrap())
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let repo = TestRepo::new();
+
+        // No commits yet - HEAD names a branch which doesn't exist
+        let (cli, git2) = backends();
+        assert_eq!(cli.current_branch().unwrap(), "main");
+        assert_eq!(git2.current_branch().unwrap(), "main");
+        assert_eq!(git2.branches().unwrap(), cli.branches().unwrap());
+
+        repo.commit("README");
+        repo.add_remote("origin");
+        repo.git(&["switch", "--quiet", "--track", "-c", "feature", "main"]);
+        // Several commits made in the same second - only their parentage orders them
+        let first = repo.commit("one");
+        repo.commit("two");
+        repo.commit("three");
+        repo.git(&["branch", "--quiet", "--track", "tracking", "origin/main"]);
+        repo.git(&["branch", "--quiet", "nested/branch", "main"]);
+        repo.git(&["config", "branch.feature.gr-parent", "main"]);
+        repo.git(&["config", "branch.nested/branch.gr-parent", "feature"]);
+
+        let (cli, git2) = backends();
+        assert!(git2.in_repo().unwrap() && cli.in_repo().unwrap());
+        assert_eq!(git2.current_branch().unwrap(), "feature");
+        assert_eq!(git2.current_branch().unwrap(), cli.current_branch().unwrap());
+        assert_eq!(git2.branches().unwrap(), vec!["feature", "main", "nested/branch", "tracking"]);
+        assert_eq!(git2.branches().unwrap(), cli.branches().unwrap());
+        assert_eq!(git2.upstreams().unwrap()["tracking"], "origin/main");
+        assert_eq!(git2.upstreams().unwrap(), cli.upstreams().unwrap());
+        assert_eq!(git2.branch_config(PARENT_CONFIG_KEY).unwrap(), cli.branch_config(PARENT_CONFIG_KEY).unwrap());
+        assert_eq!(git2.config_get("branch.feature.gr-parent").unwrap(), cli.config_get("branch.feature.gr-parent").unwrap());
+        assert_eq!(git2.config_get("gr.unset").unwrap(), cli.config_get("gr.unset").unwrap());
+        assert_eq!(git2.tips().unwrap(), cli.tips().unwrap());
+        for spec in ["feature", "main", "HEAD~2", "origin/main"] {
+            assert_eq!(git2.rev_parse(vec![spec]).unwrap(), cli.rev_parse(vec![spec]).unwrap(), "{}", spec);
+        }
+        assert_eq!(git2.is_ancestor("main", "feature").unwrap(), cli.is_ancestor("main", "feature").unwrap());
+        assert_eq!(git2.is_ancestor("feature", "main").unwrap(), cli.is_ancestor("feature", "main").unwrap());
+        let diff = git2.commit_diff("feature", "main").unwrap();
+        assert_eq!(diff.lines().map(|l| l.split_once(' ').unwrap().1).collect::<Vec<&str>>(), vec!["Add three", "Add two", "Add one"]);
+        assert_eq!(diff, cli.commit_diff("feature", "main").unwrap());
+
+        repo.git(&["switch", "--quiet", "--detach", &first]);
+        let (cli, git2) = backends();
+        assert_eq!(git2.current_branch().unwrap(), "HEAD");
+        assert_eq!(cli.current_branch().unwrap(), "HEAD");
+    }
+
+    #[test]
+    fn test_short_ref_name() {
+        assert_eq!(short_ref_name("refs/heads/main"), "main");
+        assert_eq!(short_ref_name("refs/heads/feature/nested"), "feature/nested");
+        assert_eq!(short_ref_name("refs/remotes/origin/main"), "origin/main");
+        assert_eq!(short_ref_name("main"), "main");
+    }
+}
@@ -1,22 +1,82 @@
 mod exec_git;
 mod branches;
+mod backend;
+mod cli_backend;
+mod git2_backend;
+mod stack_graph;
+mod snapshot;
+#[cfg(test)]
+mod test_repo;
 pub use exec_git::ExecGit;  // export for consumers of this crate
 
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
 use anyhow::{anyhow, Result};
-use std::process::Command;
-use colored::Colorize;
-pub use branches::BranchType;
+pub use branches::{BranchType, BASE_CONFIG_KEY, PARENT_CONFIG_KEY};
+pub use stack_graph::StackGraph;
+pub use snapshot::{BranchSnapshot, Snapshot};
+pub use backend::{Backend, BACKEND_ENV_VAR};
+use backend::GitBackend;
+use cli_backend::run_git;
 
-pub struct Git;
+pub struct Git {
+    backend: Box<dyn GitBackend>,
+    /// Cached stack snapshot - cleared by every mutating command
+    graph: RefCell<Option<Rc<StackGraph>>>,
+}
+
+impl Default for Git {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Git {
+    /// Creates a Git using the backend selected by `GR_GIT_BACKEND` (libgit2 by default)
     pub fn new() -> Git {
-        Git {}
+        let backend = Backend::from_env().unwrap_or_else(|e| {
+            eprintln!("{} - falling back to libgit2", e);
+            Backend::Libgit2
+        });
+        Git::with_backend(backend)
+    }
+
+    pub fn with_backend(backend: Backend) -> Git {
+        Git { backend: backend.build(), graph: RefCell::new(None) }
+    }
+
+    /***** Stack Graph *****/
+
+    /// Snapshot of all local branches and their parentage, built on first use and reused
+    /// until `invalidate_stack_graph` is called.
+    /// Commands run through this Git invalidate it themselves.
+    pub fn stack_graph(&self) -> Result<Rc<StackGraph>> {
+        if let Some(graph) = self.graph.borrow().as_ref() {
+            return Ok(graph.clone());
+        }
+
+        self.assert_in_repo()?;
+        let graph = Rc::new(StackGraph::new(
+            &self.backend.current_branch()?,
+            self.backend.tips()?,
+            self.backend.branch_config(PARENT_CONFIG_KEY)?,
+            self.backend.upstreams()?,
+            self.backend.branch_config(BASE_CONFIG_KEY)?,
+        ));
+        *self.graph.borrow_mut() = Some(graph.clone());
+        Ok(graph)
+    }
+
+    /// Drops the cached stack graph - call after changing branches outside of this Git
+    pub fn invalidate_stack_graph(&self) {
+        self.graph.borrow_mut().take();
     }
 
     /***** Transformative *****/
     pub fn pull(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("pull", args)
     }
 
@@ -25,29 +85,87 @@ impl Git {
         self.git("push", args)
     }
 
+    pub fn fetch(&self, args: Vec<&str>) -> Result<String> {
+        self.assert_in_repo()?;
+        self.git("fetch", args)
+    }
+
     pub fn rebase(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("rebase", args)
     }
 
+    /// Continues a rebase stopped by conflicts, keeping each commit's message as is
+    pub fn rebase_continue(&self) -> Result<String> {
+        self.assert_in_repo()?;
+        self.invalidate_stack_graph();
+        self.git("-c", vec!["core.editor=true", "rebase", "--continue"])
+    }
+
+    /// Is a rebase stopped part way through - e.g. waiting for conflicts to be resolved?
+    pub fn rebase_in_progress(&self) -> Result<bool> {
+        self.assert_in_repo()?;
+        for state_dir in ["rebase-merge", "rebase-apply"] {
+            let path = self.rev_parse(vec!["--git-path", state_dir])?;
+            if std::env::current_dir()?.join(path).exists() { return Ok(true); }
+        }
+        Ok(false)
+    }
+
+    /// Rebases `branch` onto its stack parent, then does the same for all of its descendants.
+    /// `args` are passed to `git rebase` ahead of the parent.
     pub fn recursive_rebase(&self, branch: &str, args: Vec<&str>) -> Result<()> {
         self.assert_in_repo()?;
+        // rebasing doesn't change parentage - grab the children before the graph is invalidated
+        let children = self.stack_graph()?.children_of(branch);
 
         self.switch(branch)?;
-        self.rebase(args.clone())?;
-
+        self.restack_with(branch, args.clone())?;
 
-        for child in self.children_of(branch)? {
+        for child in children {
             self.recursive_rebase(&child, args.clone())?;
         }
 
         Ok(())
     }
 
+    /// Rebases `branch` onto the tip of its stack parent and records the new base.
+    /// Only the commits made on top of the branch's recorded base are replayed
+    /// (`git rebase --onto <parent> <base>`). Branches without a usable base fall back
+    /// to a plain `git rebase <parent>`.
+    pub fn restack(&self, branch: &str) -> Result<()> {
+        self.restack_with(branch, vec![])
+    }
+
+    fn restack_with(&self, branch: &str, args: Vec<&str>) -> Result<()> {
+        let graph = self.stack_graph()?;
+        let Some(parent) = graph.parent_of(branch, BranchType::Local) else { return Ok(()) };
+        let new_base = self.rev_parse(vec![&parent])?;
+
+        // A base that's no longer in the branch's history (e.g. the branch was rebased by hand) is useless
+        let base = graph.base_of(branch)
+            .filter(|base| self.is_ancestor(base, branch).unwrap_or(false));
+
+        let mut rebase_args = args;
+        match base {
+            Some(base) => rebase_args.extend(["--onto", parent.as_str(), base, branch]),
+            None => rebase_args.extend([parent.as_str(), branch]),
+        }
+        self.rebase(rebase_args)?;
+        self.set_base(branch, &new_base)
+    }
+
+    /// Updates `branch` and its descendants: stack roots pull from their remote,
+    /// everything else is rebased onto its stack parent.
     pub fn sync(&self, branch: &str) -> Result<()> {
+        let graph = self.stack_graph()?;
         self.switch(branch)?;
-        self.pull(vec!["--rebase"])?;
-        for child in self.children_of(branch)? {
+        match graph.parent_of(branch, BranchType::Local) {
+            Some(_) => { self.restack(branch)?; },
+            None => if graph.upstream_of(branch).is_some() { self.pull(vec!["--rebase"])?; },
+        }
+        for child in graph.children_of(branch) {
             self.sync(&child)?;
         }
         self.switch(branch)?;
@@ -56,11 +174,19 @@ impl Git {
 
     pub fn merge(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("merge", args)
     }
 
+    /// Writes a commit object without touching any refs - returns its sha
+    pub fn commit_tree(&self, args: Vec<&str>) -> Result<String> {
+        self.assert_in_repo()?;
+        self.git("commit-tree", args)
+    }
+
     pub fn cherry_pick(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
+        self.invalidate_stack_graph();
         self.git("cherry-pick", args)
     }
 
@@ -68,8 +194,7 @@ impl Git {
 
     pub fn in_repo(&self) -> Result<bool> {
         // Check if the current directory is a git repository
-        let output = self.git("rev-parse", vec!["--is-inside-work-tree"])?;
-        Ok(output == "true")
+        self.backend.in_repo()
     }
 
     pub fn log(&self, args: Vec<&str>) -> Result<Vec<String>> {
@@ -84,7 +209,25 @@ impl Git {
 
     pub fn rev_parse(&self, args: Vec<&str>) -> Result<String> {
         self.assert_in_repo()?;
-        self.git("rev-parse", args)
+        self.backend.rev_parse(args)
+    }
+
+    /// Lists commits in `head` and whether an equivalent patch (by patch-id) is in `upstream`
+    /// `+ <sha>` = not in upstream, `- <sha>` = already in upstream
+    pub fn cherry(&self, args: Vec<&str>) -> Result<String> {
+        self.assert_in_repo()?;
+        self.git("cherry", args)
+    }
+
+    pub fn merge_base(&self, args: Vec<&str>) -> Result<String> {
+        self.assert_in_repo()?;
+        self.git("merge-base", args)
+    }
+
+    /// Is `ancestor` part of `descendant`'s history?
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        self.assert_in_repo()?;
+        self.backend.is_ancestor(ancestor, descendant)
     }
 
     pub fn status(&self) -> Result<String> {
@@ -94,8 +237,17 @@ impl Git {
 
     pub fn commit_diff(&self, branch: &str, parent: &str) -> Result<String> {
         self.assert_in_repo()?;
-        let dotdot= format!("{}..{}", parent, branch);
-        self.git("log", vec![&dotdot, "--format=oneline"])
+        self.backend.commit_diff(branch, parent)
+    }
+
+    /// Directory for gr's per-repo state (`<git common dir>/gr`), created on demand.
+    /// Shared by all worktrees of the repo.
+    pub fn gr_dir(&self) -> Result<PathBuf> {
+        self.assert_in_repo()?;
+        let common_dir = PathBuf::from(self.rev_parse(vec!["--git-common-dir"])?);
+        let dir = std::env::current_dir()?.join(common_dir).join("gr");
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
     }
 
     /***** Remotes *****/
@@ -117,27 +269,7 @@ impl Git {
     /***** Utilities *****/
 
     fn git(&self, command: &str, args: Vec<&str>) -> Result<String> {
-        // Execute the git executable with the given command and arguments
-        // and return the output
-        let mut g = Command::new("git");
-        let mut cmd = g.arg(command);
-
-        if args.len() != 0 { cmd = cmd.args(args.clone()); }
-
-        // Execute the command and get the output
-        let output = cmd.output()?;
-
-        // Check the exit code
-        if !output.status.success() {
-            let msg = String::from_utf8_lossy(&output.stderr).to_string();
-            return Err(anyhow!("{}\n{}", format!("> git {} {}", command, args.join(" ")).red(), msg.yellow()));
-        }
-
-        let out = String::from_utf8_lossy(&output.stdout).to_string();
-        let err = String::from_utf8_lossy(&output.stderr).to_string();
-        let text = out + "\n" + &err;
-        let text = text.lines().into_iter().map(|l| l.trim()).filter(|l| !l.is_empty()).collect::<Vec<&str>>().join("\n");
-        Ok(text)
+        run_git(command, args)
     }
 
     fn assert_in_repo(&self) -> Result<()> {
@@ -0,0 +1,98 @@
+/// Point-in-time copies of every local branch, gr's stack metadata and HEAD,
+/// so multistep operations can be rolled back.
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::{Git, BASE_CONFIG_KEY, PARENT_CONFIG_KEY};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BranchSnapshot {
+    pub name: String,
+    pub sha: String,
+    pub parent: Option<String>,
+    pub base: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Name of the checked out branch - or a sha when HEAD was detached
+    pub head: String,
+    pub detached: bool,
+    pub branches: Vec<BranchSnapshot>,
+}
+
+impl Snapshot {
+    pub fn branch(&self, name: &str) -> Option<&BranchSnapshot> {
+        self.branches.iter().find(|b| b.name == name)
+    }
+}
+
+impl Git {
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.assert_in_repo()?;
+        let mut parents = self.backend.branch_config(PARENT_CONFIG_KEY)?;
+        let mut bases = self.backend.branch_config(BASE_CONFIG_KEY)?;
+
+        let mut branches = self.backend.tips()?
+            .into_iter()
+            .map(|(name, sha)| BranchSnapshot {
+                parent: parents.remove(&name),
+                base: bases.remove(&name),
+                name,
+                sha,
+            })
+            .collect::<Vec<BranchSnapshot>>();
+        branches.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let current = self.backend.current_branch()?;
+        let detached = current == "HEAD";
+        let head = if detached { self.rev_parse(vec!["HEAD"])? } else { current };
+
+        Ok(Snapshot { head, detached, branches })
+    }
+
+    /// Resets every local branch, its stack metadata and HEAD to `snapshot`.
+    /// Branches created since the snapshot are deleted. Refuses to run with uncommitted changes.
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<()> {
+        self.assert_in_repo()?;
+        if !self.git("status", vec!["--porcelain"])?.is_empty() {
+            return Err(anyhow!("You have uncommitted changes - commit or stash them first"));
+        }
+        self.invalidate_stack_graph();
+
+        // Detach so we are free to move or delete whatever branch is checked out
+        self.git("checkout", vec!["--quiet", "--detach"])?;
+
+        for current in self.backend.tips()?.keys() {
+            if snapshot.branch(current).is_none() {
+                self.git("update-ref", vec!["-d", &format!("refs/heads/{}", current)])?;
+                self.unset_branch_config(current, PARENT_CONFIG_KEY);
+                self.unset_branch_config(current, BASE_CONFIG_KEY);
+            }
+        }
+
+        for b in &snapshot.branches {
+            self.git("update-ref", vec![&format!("refs/heads/{}", b.name), &b.sha])?;
+            match &b.parent {
+                Some(parent) => { self.git("config", vec![&format!("branch.{}.{}", b.name, PARENT_CONFIG_KEY), parent])?; },
+                None => self.unset_branch_config(&b.name, PARENT_CONFIG_KEY),
+            }
+            match &b.base {
+                Some(base) => { self.git("config", vec![&format!("branch.{}.{}", b.name, BASE_CONFIG_KEY), base])?; },
+                None => self.unset_branch_config(&b.name, BASE_CONFIG_KEY),
+            }
+        }
+
+        if snapshot.detached {
+            self.git("checkout", vec!["--quiet", "--force", "--detach", &snapshot.head])?;
+        } else {
+            self.git("checkout", vec!["--quiet", "--force", &snapshot.head])?;
+        }
+        self.invalidate_stack_graph();
+        Ok(())
+    }
+
+    pub(crate) fn unset_branch_config(&self, branch: &str, key: &str) {
+        // exit code 5 == there was nothing to unset
+        let _ = self.git("config", vec!["--unset", &format!("branch.{}.{}", branch, key)]);
+    }
+}
@@ -0,0 +1,260 @@
+/// A snapshot of the repo's stacks: every local branch, its tip and its place in the stack.
+///
+/// Stack parents come from gr's own `branch.<name>.gr-parent` config, so a branch is free to
+/// track its own remote branch for plain `git pull`/`git push`.
+///
+/// Building a graph costs a handful of git queries, after which all parent/child lookups
+/// are free. The snapshot does NOT follow the repo - rebuild it (or call
+/// `Git::invalidate_stack_graph`) after anything that creates, deletes or reparents branches.
+use std::collections::{HashMap, HashSet};
+use crate::BranchType;
+
+#[derive(Debug, Clone, Default)]
+pub struct StackGraph {
+    current: String,
+    branches: Vec<String>,
+    tips: HashMap<String, String>,
+    /// branch -> stack parent, as recorded in `branch.<name>.gr-parent`
+    parents: HashMap<String, String>,
+    /// branch -> remote branch it tracks, e.g. origin/main
+    upstreams: HashMap<String, String>,
+    /// branch -> parent commit it was last stacked on, as recorded in `branch.<name>.gr-base`
+    bases: HashMap<String, String>,
+    /// branch -> local children, sorted by name
+    children: HashMap<String, Vec<String>>,
+}
+
+impl StackGraph {
+    /// Builds a graph from raw repo data.
+    /// `parents` maps branches to their stack parent, `upstreams` to the branch they track and
+    /// `bases` to the parent commit they were last stacked on. Empty strings mean "none".
+    pub fn new(current: &str,
+               tips: HashMap<String, String>,
+               parents: HashMap<String, String>,
+               upstreams: HashMap<String, String>,
+               bases: HashMap<String, String>) -> StackGraph {
+        let mut branches = tips.keys().cloned().collect::<Vec<String>>();
+        branches.sort();
+
+        let known = |(name, target): &(String, String)| !target.is_empty() && tips.contains_key(name);
+        let parents = parents.into_iter().filter(known).collect::<HashMap<String, String>>();
+        // Tracking a local branch is how stacks used to be recorded - only remote upstreams count now
+        let upstreams = upstreams.into_iter()
+            .filter(known)
+            .filter(|(_name, upstream)| !tips.contains_key(upstream))
+            .collect::<HashMap<String, String>>();
+        let bases = bases.into_iter().filter(known).collect::<HashMap<String, String>>();
+
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for (child, parent) in &parents {
+            if tips.contains_key(parent) {
+                children.entry(parent.clone()).or_default().push(child.clone());
+            }
+        }
+        children.values_mut().for_each(|c| c.sort());
+
+        StackGraph { current: current.to_string(), branches, tips, parents, upstreams, bases, children }
+    }
+
+    /**** Nodes ****/
+
+    /// The branch that was checked out when this snapshot was taken
+    pub fn current_branch(&self) -> &str {
+        &self.current
+    }
+
+    /// All local branches, sorted by name
+    pub fn branches(&self) -> &[String] {
+        &self.branches
+    }
+
+    pub fn contains(&self, branch: &str) -> bool {
+        self.tips.contains_key(branch)
+    }
+
+    /// Sha of the commit at the tip of `branch`
+    pub fn tip_of(&self, branch: &str) -> Option<&str> {
+        self.tips.get(branch).map(|s| s.as_str())
+    }
+
+    /// Sha of the parent commit `branch` was last stacked on, if gr recorded one
+    pub fn base_of(&self, branch: &str) -> Option<&str> {
+        self.bases.get(branch).map(|s| s.as_str())
+    }
+
+    /// Local branches at the top of a stack - i.e. with no local children
+    pub fn tips(&self) -> Vec<String> {
+        self.branches.iter()
+            .filter(|b| self.children_of(b).is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Local branches at the bottom of a stack - i.e. without a local parent
+    pub fn roots(&self) -> Vec<String> {
+        self.branches.iter()
+            .filter(|b| self.parent_of(b, BranchType::Local).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /**** Edges ****/
+
+    /// Local: the branch's stack parent.
+    /// Remote: a remote stack parent, or else the remote branch it tracks.
+    /// All: the stack parent if there is one, otherwise the remote one.
+    pub fn parent_of(&self, branch: &str, branch_type: BranchType) -> Option<String> {
+        let local = self.parents.get(branch).filter(|p| self.contains(p)).cloned();
+        let remote = || self.parents.get(branch)
+            .filter(|p| !self.contains(p))
+            .or_else(|| self.upstreams.get(branch))
+            .cloned();
+
+        match branch_type {
+            BranchType::Local => local,
+            BranchType::Remote => remote(),
+            BranchType::All => local.or_else(remote),
+        }
+    }
+
+    /// The remote branch `branch` tracks for plain `git pull`/`git push`, if any
+    pub fn upstream_of(&self, branch: &str) -> Option<String> {
+        self.upstreams.get(branch).cloned()
+    }
+
+    /// Direct (local) children of `branch`, sorted by name
+    pub fn children_of(&self, branch: &str) -> Vec<String> {
+        self.children.get(branch).cloned().unwrap_or_default()
+    }
+
+    /**** Traversals ****/
+
+    /// Local ancestors of `branch`, nearest first - parent, grandparent, ... root
+    pub fn ancestors(&self, branch: &str) -> Vec<String> {
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::from([branch.to_string()]);
+        let mut cur = branch.to_string();
+
+        while let Some(parent) = self.parent_of(&cur, BranchType::Local) {
+            // Misconfigured parents can form a cycle - stop instead of spinning forever
+            if !seen.insert(parent.clone()) { break; }
+            ancestors.push(parent.clone());
+            cur = parent;
+        }
+        ancestors
+    }
+
+    /// All descendants of `branch`, depth first with every parent before its children
+    pub fn descendants(&self, branch: &str) -> Vec<String> {
+        let mut seen = HashSet::from([branch.to_string()]);
+        let mut out = Vec::new();
+        self.collect_descendants(branch, &mut seen, &mut out);
+        out
+    }
+
+    fn collect_descendants(&self, branch: &str, seen: &mut HashSet<String>, out: &mut Vec<String>) {
+        for child in self.children_of(branch) {
+            if !seen.insert(child.clone()) { continue; }
+            out.push(child.clone());
+            self.collect_descendants(&child, seen, out);
+        }
+    }
+
+    /// The stack `branch` belongs to, from the root up: ancestors, the branch and its descendants
+    pub fn stack_of(&self, branch: &str) -> Vec<String> {
+        let mut stack = self.ancestors(branch);
+        stack.reverse();
+        stack.push(branch.to_string());
+        stack.append(&mut self.descendants(branch));
+        stack
+    }
+
+    /// The stack of the branch that was checked out when this snapshot was taken
+    pub fn current_stack(&self) -> Vec<String> {
+        self.stack_of(&self.current)
+    }
+
+    /// Every local branch, ordered so that parents always come before their children
+    pub fn topological_order(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        for root in self.roots() {
+            seen.insert(root.clone());
+            out.push(root.clone());
+            self.collect_descendants(&root, &mut seen, &mut out);
+        }
+        // Branches caught in a parent cycle have no root - tack them on at the end
+        for b in &self.branches {
+            if seen.insert(b.clone()) { out.push(b.clone()); }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_graph() -> StackGraph {
+        // main <- a <- b <- c
+        //           \_ d
+        // dev (tracks origin/dev)
+        let tips = ["main", "a", "b", "c", "d", "dev"].iter()
+            .map(|b| (b.to_string(), format!("{}-sha", b)))
+            .collect();
+        let parents = [("a", "main"), ("b", "a"), ("c", "b"), ("d", "a")].iter()
+            .map(|(b, p)| (b.to_string(), p.to_string()))
+            .collect();
+        let upstreams = [("main", "origin/main"), ("b", "origin/b"), ("d", "main"), ("dev", "origin/dev")].iter()
+            .map(|(b, u)| (b.to_string(), u.to_string()))
+            .collect();
+        let bases = [("a", "main-sha"), ("b", "old-a-sha")].iter()
+            .map(|(b, s)| (b.to_string(), s.to_string()))
+            .collect();
+        StackGraph::new("b", tips, parents, upstreams, bases)
+    }
+
+    #[test]
+    fn test_parents_and_children() {
+        let g = test_graph();
+        assert_eq!(g.parent_of("b", BranchType::Local), Some("a".to_string()));
+        assert_eq!(g.parent_of("main", BranchType::Local), None);
+        assert_eq!(g.parent_of("main", BranchType::All), Some("origin/main".to_string()));
+        assert_eq!(g.parent_of("dev", BranchType::Local), None);
+        assert_eq!(g.parent_of("dev", BranchType::Remote), Some("origin/dev".to_string()));
+        assert_eq!(g.parent_of("b", BranchType::All), Some("a".to_string()));
+        assert_eq!(g.parent_of("b", BranchType::Remote), Some("origin/b".to_string()));
+        assert_eq!(g.upstream_of("d"), None);  // tracking a local branch doesn't make it a parent
+        assert_eq!(g.children_of("a"), vec!["b", "d"]);
+        assert_eq!(g.tip_of("c"), Some("c-sha"));
+        assert_eq!(g.base_of("b"), Some("old-a-sha"));
+        assert_eq!(g.base_of("c"), None);
+    }
+
+    #[test]
+    fn test_roots_and_tips() {
+        let g = test_graph();
+        assert_eq!(g.roots(), vec!["dev", "main"]);
+        assert_eq!(g.tips(), vec!["c", "d", "dev"]);
+    }
+
+    #[test]
+    fn test_traversals() {
+        let g = test_graph();
+        assert_eq!(g.ancestors("c"), vec!["b", "a", "main"]);
+        assert_eq!(g.descendants("a"), vec!["b", "c", "d"]);
+        assert_eq!(g.current_stack(), vec!["main", "a", "b", "c"]);
+        assert_eq!(g.topological_order(), vec!["dev", "main", "a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_cycles_terminate() {
+        let tips = ["x", "y"].iter().map(|b| (b.to_string(), String::new())).collect();
+        let parents = [("x", "y"), ("y", "x")].iter().map(|(b, p)| (b.to_string(), p.to_string())).collect();
+        let g = StackGraph::new("x", tips, parents, HashMap::new(), HashMap::new());
+
+        assert_eq!(g.ancestors("x"), vec!["y"]);
+        assert_eq!(g.descendants("x"), vec!["y"]);
+        assert_eq!(g.topological_order(), vec!["x", "y"]);
+    }
+}
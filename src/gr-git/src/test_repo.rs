@@ -0,0 +1,72 @@
+/// Scratch git repos for testing `Git` against the real thing.
+/// Both backends work on the current directory, so tests using one run one at a time.
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+static IN_USE: Mutex<()> = Mutex::new(());
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty repo with `main` checked out, made the current directory until dropped
+pub(crate) struct TestRepo {
+    pub dir: PathBuf,
+    /// Holds the repo and its remotes
+    root: PathBuf,
+    previous_dir: PathBuf,
+    _in_use: MutexGuard<'static, ()>,
+}
+
+impl TestRepo {
+    pub fn new() -> TestRepo {
+        // A test which panicked still leaves a usable lock behind
+        let in_use = IN_USE.lock().unwrap_or_else(|e| e.into_inner());
+
+        let root = std::env::temp_dir().join(format!("gr-git-test-{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::SeqCst)));
+        let dir = root.join("repo");
+        std::fs::create_dir_all(&dir).unwrap();
+        let previous_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+
+        // Independent of whoever runs the tests
+        for (key, value) in [("GIT_CONFIG_GLOBAL", "/dev/null"), ("GIT_CONFIG_NOSYSTEM", "1"),
+                             ("GIT_AUTHOR_NAME", "gr"), ("GIT_AUTHOR_EMAIL", "gr@example.com"),
+                             ("GIT_COMMITTER_NAME", "gr"), ("GIT_COMMITTER_EMAIL", "gr@example.com")] {
+            std::env::set_var(key, value);
+        }
+
+        let repo = TestRepo { dir, root, previous_dir, _in_use: in_use };
+        repo.git(&["init", "--quiet", "--initial-branch", "main"]);
+        repo
+    }
+
+    /// Adds an empty bare repo as the remote `name`, with `main` pushed to it
+    pub fn add_remote(&self, name: &str) {
+        let remote = self.root.join(format!("{}.git", name));
+        self.git(&["init", "--quiet", "--bare", remote.to_str().unwrap()]);
+        self.git(&["remote", "add", name, remote.to_str().unwrap()]);
+        self.git(&["push", "--quiet", name, "main"]);
+    }
+
+    /// Runs git, panicking if it fails. Returns its trimmed output.
+    pub fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git").args(args).current_dir(&self.dir).output().unwrap();
+        assert!(output.status.success(), "git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// Commits a new file named `file` on the current branch - returns the commit
+    pub fn commit(&self, file: &str) -> String {
+        std::fs::write(self.dir.join(file), file).unwrap();
+        self.git(&["add", file]);
+        self.git(&["commit", "--quiet", "-m", &format!("Add {}", file)]);
+        self.git(&["rev-parse", "HEAD"])
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.previous_dir);
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
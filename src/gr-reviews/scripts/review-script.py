@@ -0,0 +1,191 @@
+#!/usr/bin/env python3
+"""Reference review script for stk - protocol version 1.
+
+Select "Script" in `stk init`, or configure it in the stk config with
+
+    [code_review_tool.Script]
+    command = "/path/to/review-script.py"
+
+This implementation keeps its reviews as JSON files in a local directory ($STK_REVIEW_STORE,
+or .git/stk-reviews) instead of talking to a forge - replace the Store class with calls to
+your forge's API to plug it into stk. Besides the protocol's subcommands it understands
+
+    approve <id> [<user>]   - approve a review, so it can be merged
+
+The protocol itself is documented in gr-reviews/src/script/mod.rs.
+"""
+import json
+import os
+import subprocess
+import sys
+
+VERSION = 1
+OPEN_STATES = ("pending", "approved", "rejected", "conflicted")
+
+
+class ScriptError(Exception):
+    pass
+
+
+class Store:
+    """Reviews kept as <id>.json files"""
+
+    def __init__(self):
+        path = os.environ.get("STK_REVIEW_STORE")
+        if not path:
+            git_dir = subprocess.run(["git", "rev-parse", "--git-common-dir"],
+                                     capture_output=True, text=True, check=True).stdout.strip()
+            path = os.path.join(git_dir, "stk-reviews")
+        os.makedirs(path, exist_ok=True)
+        self.path = path
+
+    def _file(self, review_id):
+        return os.path.join(self.path, "%s.json" % review_id)
+
+    def all(self):
+        reviews = []
+        for name in os.listdir(self.path):
+            if name.endswith(".json"):
+                with open(os.path.join(self.path, name)) as f:
+                    reviews.append(json.load(f))
+        return sorted(reviews, key=lambda r: int(r["id"]))
+
+    def get(self, review_id):
+        try:
+            with open(self._file(review_id)) as f:
+                return json.load(f)
+        except FileNotFoundError:
+            return None
+
+    def must_get(self, review_id):
+        review = self.get(review_id)
+        if review is None:
+            raise ScriptError("no review %s" % review_id)
+        return review
+
+    def save(self, review):
+        with open(self._file(review["id"]), "w") as f:
+            json.dump(review, f, indent=2)
+        return review
+
+    def next_id(self):
+        return str(max([int(r["id"]) for r in self.all()], default=0) + 1)
+
+
+def read_input():
+    data = sys.stdin.read()
+    return json.loads(data) if data.strip() else {}
+
+
+def cmd_review(store, review_id):
+    return {"review": store.get(review_id)}
+
+
+def cmd_reviews(store):
+    return {"reviews": [r for r in store.all() if r["state"] in OPEN_STATES]}
+
+
+def cmd_reviews_for(store, branch, *flags):
+    wanted = lambda r: "--all" in flags or r["state"] in OPEN_STATES
+    return {"reviews": [r for r in store.all() if r["branch"] == branch and wanted(r)]}
+
+
+def cmd_create(store, branch, parent):
+    request = read_input()
+    review = {
+        "id": store.next_id(),
+        "branch": branch,
+        "base": parent,
+        "title": request.get("title", branch),
+        "body": request.get("body", ""),
+        "url": None,
+        "state": "pending",
+        "reviewers": [],
+        "approvals": [],
+        "tests": [{"name": "1 approval", "state": "pending"}],
+    }
+    return {"review": store.save(review)}
+
+
+def cmd_merge(store, review_id):
+    # A forge would land the branch using the request's "method" - squash, rebase or merge -
+    # refusing when the branch's head is no longer its "expected_head"
+    read_input()
+    review = store.must_get(review_id)
+    if review["state"] != "approved":
+        return {"merge": {"state": "failed", "reason": "review is %s" % review["state"]}, "review": review}
+
+    review["state"] = "merged"
+    return {"merge": {"state": "merged"}, "review": store.save(review)}
+
+
+def cmd_publish(store, remote, branch, parent):
+    # Nothing special about pushing here - let stk push the branch
+    return {"unsupported": True}
+
+
+def cmd_retarget(store, review_id, base):
+    review = store.must_get(review_id)
+    review["base"] = base
+    return {"review": store.save(review)}
+
+
+def cmd_close(store, review_id):
+    request = read_input()
+    review = store.must_get(review_id)
+    review["state"] = "closed"
+    review.setdefault("comments", []).append(request.get("comment", ""))
+    store.save(review)
+    return {}
+
+
+def cmd_auto_merge(store, review_id):
+    return {"unsupported": True}
+
+
+def cmd_approve(store, review_id, user=None):
+    user = user or os.environ.get("USER", "reviewer")
+    review = store.must_get(review_id)
+    if user not in review["reviewers"]:
+        review["reviewers"].append(user)
+    if user not in review["approvals"]:
+        review["approvals"].append(user)
+    review["state"] = "approved"
+    review["tests"] = [{"name": "1 approval", "state": "passed"}]
+    return {"review": store.save(review)}
+
+
+COMMANDS = {
+    "review": cmd_review,
+    "reviews": cmd_reviews,
+    "reviews-for": cmd_reviews_for,
+    "create": cmd_create,
+    "merge": cmd_merge,
+    "publish": cmd_publish,
+    "retarget": cmd_retarget,
+    "close": cmd_close,
+    "auto-merge": cmd_auto_merge,
+    "approve": cmd_approve,
+}
+
+
+def main(args):
+    if not args or args[0] not in COMMANDS:
+        print("usage: %s <%s> [args...]" % (sys.argv[0], " | ".join(COMMANDS)), file=sys.stderr)
+        return 2
+
+    try:
+        response = COMMANDS[args[0]](Store(), *args[1:])
+    except TypeError:
+        print("wrong arguments for %s" % args[0], file=sys.stderr)
+        return 2
+    except ScriptError as e:
+        response = {"error": str(e)}
+
+    response["version"] = VERSION
+    print(json.dumps(response))
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main(sys.argv[1:]))
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+/// Seconds to wait between polls of a review service - quick at first, then backing off
+pub const BACKOFF_TIME_SECONDS : [u64; 10] = [1, 5, 5, 5, 5, 10, 10, 10, 30, 60];
+
+/// Steps through `BACKOFF_TIME_SECONDS`, staying at the longest wait once the schedule runs out
+#[derive(Default)]
+pub struct Backoff {
+    idx: usize,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { idx: 0 }
+    }
+
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_wait()).await;
+    }
+
+    fn next_wait(&mut self) -> Duration {
+        let backoff_time = BACKOFF_TIME_SECONDS[self.idx.min(BACKOFF_TIME_SECONDS.len() - 1)];
+        self.idx += 1;
+        Duration::from_secs(backoff_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_stays_at_the_longest_wait() {
+        let mut backoff = Backoff::new();
+        let waits = (0..BACKOFF_TIME_SECONDS.len() + 3).map(|_| backoff.next_wait().as_secs()).collect::<Vec<u64>>();
+
+        assert_eq!(waits[..BACKOFF_TIME_SECONDS.len()], BACKOFF_TIME_SECONDS);
+        assert_eq!(waits[BACKOFF_TIME_SECONDS.len()..], [60, 60, 60]);
+    }
+}
@@ -0,0 +1,484 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use colored::Colorize;
+use octocrab::Octocrab;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use crate::rest::{send, Method};
+use crate::{CodeReviewService, MergeMethod, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewTest, ReviewTestState};
+use crate::merge_requests::{stale_head, Refusal};
+use crate::verdicts::{state_of_review, ReviewStatus, Verdicts};
+
+/// Pull requests fetched per page
+const PAGE_SIZE: usize = 100;
+
+/// Reviews pull requests on Bitbucket Server / Data Center, through its REST API 1.0.
+pub struct BitbucketReviewer {
+    client: Octocrab,
+    /// The server as configured, e.g. https://bitbucket.example.com
+    host: String,
+    /// Project key, e.g. PROJ - or ~USER for personal repos
+    project: String,
+    /// Repo slug
+    repo: String,
+}
+
+#[derive(Deserialize)]
+struct Page<T> {
+    values: Vec<T>,
+    #[serde(rename = "isLastPage", default = "last_page")]
+    is_last_page: bool,
+    #[serde(rename = "nextPageStart")]
+    next_page_start: Option<usize>,
+}
+
+fn last_page() -> bool { true }
+
+#[derive(Clone, Deserialize)]
+struct BitbucketUser {
+    /// The login
+    name: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct BitbucketParticipant {
+    user: BitbucketUser,
+    /// APPROVED, NEEDS_WORK or UNAPPROVED
+    status: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct BitbucketRef {
+    /// e.g. feature - `id` holds refs/heads/feature
+    #[serde(rename = "displayId")]
+    display_id: String,
+    #[serde(rename = "latestCommit")]
+    latest_commit: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+struct BitbucketLink {
+    href: Url,
+}
+
+#[derive(Clone, Deserialize)]
+struct BitbucketLinks {
+    #[serde(rename = "self", default)]
+    self_links: Vec<BitbucketLink>,
+}
+
+#[derive(Clone, Deserialize)]
+struct BitbucketPull {
+    id: u64,
+    /// Optimistic lock - changing a pull request needs its current version
+    version: u64,
+    title: String,
+    description: Option<String>,
+    /// OPEN, MERGED or DECLINED
+    state: String,
+    /// Bitbucket 8.1+
+    #[serde(default)]
+    draft: bool,
+    #[serde(rename = "fromRef")]
+    from_ref: BitbucketRef,
+    #[serde(rename = "toRef")]
+    to_ref: BitbucketRef,
+    #[serde(default)]
+    reviewers: Vec<BitbucketParticipant>,
+    links: Option<BitbucketLinks>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketVeto {
+    #[serde(rename = "summaryMessage")]
+    summary_message: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketMergeStatus {
+    #[serde(rename = "canMerge", default)]
+    can_merge: bool,
+    #[serde(default)]
+    conflicted: bool,
+    /// Merge checks which currently block the merge - approvals, builds, tasks...
+    #[serde(default)]
+    vetoes: Vec<BitbucketVeto>,
+}
+
+struct PullWithChecks {
+    pull: BitbucketPull,
+    /// None when Bitbucket couldn't tell, e.g. while it's still computing the merge
+    merge_status: Option<BitbucketMergeStatus>,
+}
+
+impl BitbucketReviewer {
+    /// `host` is the server's web address - the API lives under /rest/api/1.0
+    pub fn new(host: &str, project: &str, repo: &str, token: Option<String>) -> Result<BitbucketReviewer> {
+        let host = host.trim_end_matches('/').to_string();
+        let mut builder = Octocrab::builder().base_uri(format!("{}/rest/api/1.0", host))?;
+        if let Some(token) = token { builder = builder.personal_token(token); }
+
+        Ok(BitbucketReviewer {
+            client: builder.build()?,
+            host,
+            project: project.to_string(),
+            repo: repo.to_string(),
+        })
+    }
+
+    fn route(&self, path: &str) -> String {
+        format!("/projects/{}/repos/{}{}", self.project, self.repo, path)
+    }
+
+    /// A ref in this repo, as Bitbucket's request bodies want it
+    fn ref_json(&self, branch: &str) -> serde_json::Value {
+        json!({
+            "id": format!("refs/heads/{}", branch),
+            "repository": { "slug": self.repo, "project": { "key": self.project } },
+        })
+    }
+
+    async fn pull(&self, id: &str) -> Result<BitbucketPull> {
+        Ok(self.client.get(self.route(&format!("/pull-requests/{}", id)), None::<&()>).await?)
+    }
+
+    /// Pull requests in `state` (OPEN, MERGED, DECLINED or ALL), optionally only those from `branch`
+    async fn pulls(&self, state: &str, branch: Option<&str>) -> Result<Vec<BitbucketPull>> {
+        let mut pulls = Vec::new();
+        let mut start = 0;
+        loop {
+            let mut params = vec![("state", state.to_string()), ("start", start.to_string()), ("limit", PAGE_SIZE.to_string())];
+            if let Some(branch) = branch {
+                params.push(("at", format!("refs/heads/{}", branch)));
+                params.push(("direction", "OUTGOING".to_string()));
+            }
+
+            let page: Page<BitbucketPull> = self.client.get(self.route("/pull-requests"), Some(&params)).await?;
+            pulls.extend(page.values);
+            match page.next_page_start {
+                Some(next) if !page.is_last_page => start = next,
+                _ => break,
+            }
+        }
+        Ok(pulls)
+    }
+
+    async fn convert_to_review(&self, pull: BitbucketPull) -> Result<Review> {
+        // Only open pull requests have merge checks to run
+        let merge_status = match pull.state.as_str() {
+            "OPEN" => self.client.get(self.route(&format!("/pull-requests/{}/merge", pull.id)), None::<&()>).await.ok(),
+            _ => None,
+        };
+        let prc = PullWithChecks { pull, merge_status };
+        let verdicts = verdicts_of(&prc.pull);
+        let tests = review_tests(&prc);
+        let state = state_of_review(&status_of(&prc, &verdicts), &tests);
+
+        Ok(Review {
+            id: prc.pull.id.to_string(),
+            branch: prc.pull.from_ref.display_id.clone(),
+            base: prc.pull.to_ref.display_id.clone(),
+            head: prc.pull.from_ref.latest_commit.clone(),
+            title: prc.pull.title.clone(),
+            body: prc.pull.description.clone().unwrap_or_default(),
+            service: CodeReviewService::Bitbucket { host: self.host.clone() },
+            url: prc.pull.links.as_ref().and_then(|l| l.self_links.first()).map(|l| l.href.clone()),
+            reviewers: verdicts.reviewers(prc.pull.reviewers.iter().map(|r| r.user.name.clone())),
+            approvals: verdicts.approvals(),
+            state,
+            tests,
+        })
+    }
+}
+
+/// Bitbucket reports errors as {"errors": [{"message": "..."}, ...]}
+fn error_message(body: &str) -> String {
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(body) else { return body.to_string() };
+    let messages = json["errors"].as_array().into_iter()
+        .flatten()
+        .filter_map(|e| e["message"].as_str())
+        .collect::<Vec<&str>>();
+    if messages.is_empty() { body.to_string() } else { messages.join("; ") }
+}
+
+fn verdicts_of(pull: &BitbucketPull) -> Verdicts {
+    let mut verdicts = Verdicts::default();
+    for r in &pull.reviewers {
+        match r.status.as_str() {
+            "APPROVED" => verdicts.approve(r.user.name.clone()),
+            // A reviewer marked it as needing work
+            "NEEDS_WORK" => verdicts.request_changes(r.user.name.clone()),
+            _ => {},
+        }
+    }
+    verdicts
+}
+
+/// One failing test per merge check veto - or a single passing one when nothing blocks the merge
+fn review_tests(prc: &PullWithChecks) -> Vec<ReviewTest> {
+    let Some(status) = &prc.merge_status else { return Vec::new() };
+
+    if status.vetoes.is_empty() {
+        let state = if status.can_merge { ReviewTestState::Passed } else { ReviewTestState::Pending };
+        return vec![ReviewTest { name: "merge checks".to_string(), state }];
+    }
+    status.vetoes.iter()
+        .map(|v| ReviewTest { name: v.summary_message.clone(), state: ReviewTestState::Failed })
+        .collect()
+}
+
+fn status_of(prc: &PullWithChecks, verdicts: &Verdicts) -> ReviewStatus {
+    let pull = &prc.pull;
+    ReviewStatus {
+        merged: pull.state == "MERGED",
+        closed: pull.state == "DECLINED",
+        draft: pull.draft,
+        conflicted: prc.merge_status.as_ref().is_some_and(|s| s.conflicted),
+        rejected: verdicts.changes_requested(),
+        // Approved once no merge check vetoes it - Bitbucket's checks cover the approvals it requires
+        ready: Some(prc.merge_status.as_ref().is_some_and(|s| s.can_merge && s.vetoes.is_empty())),
+    }
+}
+
+fn bitbucket_strategy(method: MergeMethod) -> &'static str {
+    match method {
+        MergeMethod::Squash => "squash",
+        MergeMethod::Rebase => "rebase-ff-only",
+        MergeMethod::Merge => "no-ff",
+    }
+}
+
+/// Reads why Bitbucket refused to merge a pull request
+fn refusal(status: u16, message: &str) -> Refusal {
+    match status {
+        400 if message.to_lowercase().contains("strategy") => Refusal::MethodDisabled,
+        // Vetoed by merge checks, conflicted, or the pull request changed under us
+        409 => Refusal::NotMergeable,
+        401 | 403 => Refusal::NotPermitted,
+        _ => Refusal::Other(status),
+    }
+}
+
+#[async_trait]
+impl ReviewService for BitbucketReviewer {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        let pull = self.pull(&review.id).await?;
+        let (version, head) = (pull.version, pull.from_ref.latest_commit.clone());
+        let review = self.convert_to_review(pull).await?;
+
+        // Merging can't be made conditional on the head commit - check it ourselves
+        if let (Some(expected), Some(head)) = (&options.expected_head, &head) {
+            if expected != head {
+                let reason = stale_head("head", expected);
+                return Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)));
+            }
+        }
+
+        let route = format!("{}?version={}", self.route(&format!("/pull-requests/{}/merge", review.id)), version);
+        let body = json!({
+            "strategyId": bitbucket_strategy(options.method),
+            "message": format!("{} (#{})\n\n{}", review.title, review.id, review.body),
+        });
+        match send(&self.client, Method::Post, &route, Some(&body)).await? {
+            (200..=299, _) => Ok(MergeRequest::new(review).in_state(MergeState::Merged)),
+            (status, body) => {
+                let message = error_message(&body);
+                let reason = refusal(status, &message).reason(&message, options);
+                Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)))
+            }
+        }
+    }
+
+    async fn review(&self, id: &str) -> Result<Option<Review>> {
+        let pull = self.pull(id).await?;
+        Ok(Some(self.convert_to_review(pull).await?))
+    }
+
+    async fn reviews(&self) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls("OPEN", None).await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls("OPEN", Some(branch)).await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls("ALL", Some(branch)).await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
+        println!("Creating review for {} [on {}] at {}/{}", branch.cyan(), parent.black(), self.project.green(), self.repo.blue());
+        println!("PR title: {}", title.bold());
+        let pull: BitbucketPull = self.client.post(self.route("/pull-requests"), Some(&json!({
+            "title": title,
+            "description": body,
+            "fromRef": self.ref_json(branch),
+            "toRef": self.ref_json(parent),
+        }))).await?;
+
+        self.convert_to_review(pull).await
+    }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        let pull = self.pull(&review.id).await?;
+        let body = json!({ "version": pull.version, "toRef": self.ref_json(base) });
+
+        match send(&self.client, Method::Put, &self.route(&format!("/pull-requests/{}", review.id)), Some(&body)).await? {
+            (200..=299, body) => self.convert_to_review(serde_json::from_str(&body)?).await,
+            (status, body) => Err(anyhow!("Bitbucket could not retarget pull request {} to {} ({}): {}", review.id, base, status, error_message(&body))),
+        }
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        let _: serde_json::Value = self.client
+            .post(self.route(&format!("/pull-requests/{}/comments", review.id)), Some(&json!({ "text": comment })))
+            .await?;
+
+        let version = self.pull(&review.id).await?.version;
+        let route = format!("{}?version={}", self.route(&format!("/pull-requests/{}/decline", review.id)), version);
+        match send(&self.client, Method::Post, &route, Some(&json!({}))).await? {
+            (200..=299, _) => Ok(()),
+            (status, body) => Err(anyhow!("Bitbucket could not decline pull request {} ({}): {}", review.id, status, error_message(&body))),
+        }
+    }
+
+    async fn enable_auto_merge(&self, review: &Review, _options: &MergeOptions) -> Result<bool> {
+        // Auto-merge needs Bitbucket 8.15+ and uses the repo's default strategy.
+        // Older servers, or repos which don't allow it, refuse.
+        let (status, _) = send(&self.client, Method::Post, &self.route(&format!("/pull-requests/{}/auto-merge", review.id)), Some(&json!({}))).await?;
+        Ok((200..=299).contains(&status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{param, refused_merge, reviews_for};
+    use crate::mock_server::MockServer;
+    use crate::ReviewState;
+
+    const API: &str = "/rest/api/1.0/projects/PROJ/repos/repo";
+
+    fn pull_json(id: u64, branch: &str, base: &str, reviewers: serde_json::Value) -> serde_json::Value {
+        json!({
+            "id": id,
+            "version": 3,
+            "title": format!("Change {}", id),
+            "description": "Body",
+            "state": "OPEN",
+            "fromRef": { "id": format!("refs/heads/{}", branch), "displayId": branch, "latestCommit": format!("{}-sha", branch) },
+            "toRef": { "id": format!("refs/heads/{}", base), "displayId": base, "latestCommit": format!("{}-sha", base) },
+            "reviewers": reviewers,
+            "links": { "self": [{ "href": format!("https://bitbucket.example.com/projects/PROJ/repos/repo/pull-requests/{}", id) }] },
+        })
+    }
+
+    fn reviewer(server: &MockServer) -> BitbucketReviewer {
+        BitbucketReviewer::new(server.url(), "PROJ", "repo", Some("secret".to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_vetoes_become_failing_tests() {
+        let server = MockServer::start().await;
+        let reviewers = json!([{ "user": { "name": "alice" }, "status": "APPROVED" }, { "user": { "name": "bob" }, "status": "UNAPPROVED" }]);
+        server.on("GET", &format!("{}/pull-requests/7", API), 200, &pull_json(7, "feature", "main", reviewers).to_string());
+        server.on("GET", &format!("{}/pull-requests/7/merge", API), 200,
+                  r#"{"canMerge":false,"conflicted":false,"outcome":"CLEAN","vetoes":[{"summaryMessage":"Requires 2 approvals","detailedMessage":"..."}]}"#);
+
+        let review = reviewer(&server).review("7").await.unwrap().unwrap();
+
+        assert_eq!(review.branch, "feature");
+        assert_eq!(review.base, "main");
+        assert_eq!(review.approvals, vec!["alice"]);
+        assert_eq!(review.reviewers, vec!["alice", "bob"]);
+        assert_eq!(review.tests.len(), 1);
+        assert_eq!(review.tests[0].name, "Requires 2 approvals");
+        assert!(matches!(review.tests[0].state, ReviewTestState::Failed));
+        assert!(matches!(review.state, ReviewState::Pending));
+    }
+
+    #[tokio::test]
+    async fn test_mergeable_and_needs_work() {
+        let server = MockServer::start().await;
+        let reviewers = json!([{ "user": { "name": "alice" }, "status": "NEEDS_WORK" }]);
+        server.on("GET", &format!("{}/pull-requests/8", API), 200, &pull_json(8, "feature", "main", reviewers).to_string());
+        server.on("GET", &format!("{}/pull-requests/8/merge", API), 200, r#"{"canMerge":true,"conflicted":false,"vetoes":[]}"#);
+        server.on("GET", &format!("{}/pull-requests/9", API), 200, &pull_json(9, "other", "main", json!([])).to_string());
+        server.on("GET", &format!("{}/pull-requests/9/merge", API), 200, r#"{"canMerge":true,"conflicted":false,"vetoes":[]}"#);
+
+        let bitbucket = reviewer(&server);
+        assert!(matches!(bitbucket.review("8").await.unwrap().unwrap().state, ReviewState::Rejected));
+        assert!(matches!(bitbucket.review("9").await.unwrap().unwrap().state, ReviewState::Approved));
+    }
+
+    #[tokio::test]
+    async fn test_reviews_for_follows_pages() {
+        let server = MockServer::start().await;
+        // A single page - the query must select the branch
+        let page = json!({ "values": [pull_json(2, "b", "a", json!([]))], "isLastPage": true, "start": 0 });
+        server.on("GET", &format!("{}/pull-requests", API), 200, &page.to_string());
+
+        let (reviews, query) = reviews_for(&reviewer(&server), &server, "b", &format!("{}/pull-requests", API)).await;
+
+        assert_eq!(reviews.len(), 1);
+        assert_eq!(param(&query, "at"), Some("refs/heads/b"));
+        assert_eq!(param(&query, "direction"), Some("OUTGOING"));
+        assert_eq!(param(&query, "state"), Some("OPEN"));
+    }
+
+    #[tokio::test]
+    async fn test_create_review() {
+        let server = MockServer::start().await;
+        server.on("POST", &format!("{}/pull-requests", API), 201, &pull_json(3, "feature", "main", json!([])).to_string());
+
+        let review = reviewer(&server).create_review("feature", "main", "Change 3", "Body").await.unwrap();
+
+        assert_eq!(review.id, "3");
+        let sent = server.sent_json("POST", &format!("{}/pull-requests", API));
+        assert_eq!(sent["fromRef"]["id"], "refs/heads/feature");
+        assert_eq!(sent["toRef"]["id"], "refs/heads/main");
+        assert_eq!(sent["toRef"]["repository"]["project"]["key"], "PROJ");
+    }
+
+    #[tokio::test]
+    async fn test_merge_with_strategy() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pull-requests/4", API), 200, &pull_json(4, "feature", "main", json!([])).to_string());
+        server.on("POST", &format!("{}/pull-requests/4/merge", API), 200, r#"{"state":"MERGED"}"#);
+
+        let bitbucket = reviewer(&server);
+        let review = bitbucket.review("4").await.unwrap().unwrap();
+        let options = MergeOptions { method: MergeMethod::Rebase, expected_head: Some("feature-sha".to_string()) };
+        let merged = bitbucket.merge(&review, &options).await.unwrap();
+
+        assert!(matches!(merged.state, MergeState::Merged));
+        assert_eq!(param(&server.query("POST", &format!("{}/pull-requests/4/merge", API)), "version"), Some("3"));
+        let sent = server.sent_json("POST", &format!("{}/pull-requests/4/merge", API));
+        assert_eq!(sent["strategyId"], "rebase-ff-only");
+    }
+
+    #[tokio::test]
+    async fn test_merge_refused() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pull-requests/5", API), 200, &pull_json(5, "feature", "main", json!([])).to_string());
+        server.on("POST", &format!("{}/pull-requests/5/merge", API), 409,
+                  r#"{"errors":[{"message":"Merging the pull request has been vetoed.","vetoes":[]}]}"#);
+
+        let reason = refused_merge(&reviewer(&server), "5", &MergeOptions::default()).await;
+
+        assert_eq!(reason, "not mergeable: Merging the pull request has been vetoed.");
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::Result;
 use std::fmt::{Display, Formatter};
 use async_trait::async_trait;
+use gr_git::Git;
 use url::Url;
-use crate::{CodeReviewService, MergeRequest, review_service_for};
+use crate::{CodeReviewService, MergeOptions, MergeRequest};
 
 /// Represents the state of a code review
 #[derive(Clone, Default)]
@@ -51,32 +52,54 @@ pub struct Review {
     pub id: String,
     pub branch: String,
     pub base: String,
+    /// The commit the review's branch points at - None where the service doesn't say
+    pub head: Option<String>,
     pub title: String,
     pub body: String,
     pub service: CodeReviewService,
     pub reviewers: Vec<String>,
+    /// Reviewers whose latest verdict is an approval
+    pub approvals: Vec<String>,
     pub state: ReviewState,
     pub tests: Vec<ReviewTest>,
     pub url: Option<Url>,
 }
 
-impl Review {
-    pub async fn refresh(&mut self) -> Result<()> {
-        let service = review_service_for(&self.service)?;
-        *self = service.review(&self.id).await?.unwrap();
-        Ok(())
-    }
-
-    pub async fn merge(&self) -> Result<MergeRequest> {
-        review_service_for(&self.service)?.merge(&self).await
-    }
-}
-
 #[async_trait]
-pub trait ReviewService {
-    async fn merge(&self, review: &Review) -> Result<MergeRequest>;
+pub trait ReviewService: Sync {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest>;
     async fn review(&self, id: &str) -> Result<Option<Review>>;
     async fn reviews(&self) -> Result<Vec<Review>>;
     async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>>;
+
+    /// Every review ever opened for `branch` - including merged and closed ones
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>>;
     async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review>;
-}
\ No newline at end of file
+
+    /// Pushes `branch` (stacked on `parent`) to `remote`, so its review - new or existing - sees its latest commits
+    async fn publish(&self, remote: &str, branch: &str, _parent: &str) -> Result<()> {
+        push_branch(remote, branch)
+    }
+
+    /// Points `review` at a new base branch, e.g. after its branch was moved onto another parent
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review>;
+
+    /// Closes `review` without merging it, leaving `comment` to explain why
+    async fn close(&self, review: &Review, comment: &str) -> Result<()>;
+
+    /// Asks the review service to merge `review` by itself once it is ready.
+    /// Returns false where that isn't supported - callers then have to merge it themselves.
+    async fn enable_auto_merge(&self, review: &Review, options: &MergeOptions) -> Result<bool>;
+}
+
+/// Pushes `branch` to its namesake on `remote` - forcing it when a plain push is refused
+pub(crate) fn push_branch(remote: &str, branch: &str) -> Result<()> {
+    let git = Git::new();
+
+    // if we fail to push normal-like, let's try a force push before we give up
+    match git.push(vec![remote, branch]) {
+        Ok(_) => (),
+        Err(_e) => { git.push(vec![remote, branch, "-f"])?; }
+    }
+    Ok(())
+}
@@ -0,0 +1,432 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Result};
+use octocrab::models::{AppId, InstallationId};
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// The account tokens are stored under - they don't belong to any account we know of
+const TOKEN_USER: &str = "stk-token";
+
+/// Secrets are stored for a host of their own, next to the review service's - e.g. github.com.stk.
+/// Stored for the service's host itself, git would hand them out when pushing there, too.
+const STORE_SUFFIX: &str = ".stk";
+
+/// How to authenticate with a review service
+#[derive(Clone)]
+pub enum Credentials {
+    /// A personal access token, sent as a bearer token
+    Token(String),
+    Basic { user: String, pass: String },
+    /// A GitHub App, acting through one of its installations.
+    /// Installation tokens are minted - and renewed - from the app's private key as needed.
+    GithubApp { app_id: u64, installation_id: u64, private_key: String },
+}
+
+impl Credentials {
+    /// The secret to send as a token - services taking nothing else use a password as one
+    pub(crate) fn token(self) -> Option<String> {
+        match self {
+            Credentials::Token(token) => Some(token),
+            Credentials::Basic { pass, .. } => Some(pass),
+            Credentials::GithubApp { .. } => None,
+        }
+    }
+}
+
+/// Where the stk config's review credentials are kept. The secret itself lives with git's
+/// credential helper - in the system keychain, or wherever the helper keeps it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialRef {
+    /// The review service, e.g. https://github.com
+    pub host: String,
+    /// The account a password belongs to - unset for tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl CredentialRef {
+    fn username(&self) -> &str {
+        self.user.as_deref().unwrap_or(TOKEN_USER)
+    }
+
+    /// Where git's credential helpers keep the secret
+    fn store_url(&self) -> Result<Url> {
+        let mut url = Url::parse(&self.host)?;
+        let host = format!("{}{}", url.host_str().unwrap_or_default(), STORE_SUFFIX);
+        url.set_host(Some(&host))?;
+        Ok(url)
+    }
+
+    /// Saves `secret` - a token, or `user`'s password - with git's credential helper
+    pub fn store(&self, secret: &str) -> Result<()> {
+        let url = self.store_url()?;
+        let helper = git().args(["config", "--get-urlmatch", "credential.helper", url.as_str()]).output()?;
+        if String::from_utf8_lossy(&helper.stdout).trim().is_empty() {
+            return Err(anyhow!("No git credential helper is configured for {} - set one up, \
+                e.g. `git config --global credential.helper store` or your system's keychain helper", self.host));
+        }
+
+        let (ok, _) = git_credential("approve", &url, Some(self.username()), Some(secret))?;
+        if !ok { return Err(anyhow!("git's credential helper refused to store the credentials for {}", self.host)); }
+        Ok(())
+    }
+
+    /// Erases the stored secret
+    pub fn erase(&self) -> Result<()> {
+        git_credential("reject", &self.store_url()?, Some(self.username()), None)?;
+        Ok(())
+    }
+
+    /// The stored credentials - None once they were erased
+    pub fn load(&self) -> Option<Credentials> {
+        let credentials = match self.fill(&self.store_url().ok()?) {
+            Some(credentials) => credentials,
+            None => self.load_from_service_host()?,
+        };
+        match credentials {
+            Credentials::Basic { user, pass } if user == TOKEN_USER => Some(Credentials::Token(pass)),
+            credentials => Some(credentials),
+        }
+    }
+
+    fn fill(&self, url: &Url) -> Option<Credentials> {
+        let (ok, output) = git_credential("fill", url, Some(self.username()), None).ok()?;
+        if !ok { return None; }
+        parse_credential(&output)
+    }
+
+    /// Older versions stored secrets for the review service's own host - moves them to `store_url`.
+    /// Tokens are erased there, as nothing but stk uses them. Passwords may be git's own, so they stay.
+    fn load_from_service_host(&self) -> Option<Credentials> {
+        let url = Url::parse(&self.host).ok()?;
+        let credentials = self.fill(&url)?;
+        let (Credentials::Basic { pass, .. } | Credentials::Token(pass)) = &credentials else { return Some(credentials) };
+
+        if self.store(pass).is_ok() && self.user.is_none() {
+            let _ = git_credential("reject", &url, Some(TOKEN_USER), None);
+        }
+        Some(credentials)
+    }
+}
+
+/// Credentials, and where they came from
+pub(crate) struct Found {
+    pub credentials: Credentials,
+    pub source: String,
+}
+
+impl Found {
+    fn new(credentials: Credentials, source: &str) -> Found {
+        Found { credentials, source: source.to_string() }
+    }
+}
+
+/// Credentials from the stk config - they take precedence over any found elsewhere
+#[derive(Clone)]
+pub enum ConfiguredCredentials {
+    Stored(CredentialRef),
+    /// Configs written by older versions keep secrets in plaintext
+    Plain(Credentials),
+}
+
+impl ConfiguredCredentials {
+    /// Credentials kept in plaintext by configs which predate `CredentialRef` - a token, or a user and password
+    pub fn plain(token: Option<&str>, user: Option<&str>, pass: Option<&str>) -> Option<ConfiguredCredentials> {
+        let credentials = match (token, user, pass) {
+            (Some(token), _, _) if !token.is_empty() => Credentials::Token(token.to_string()),
+            (_, Some(user), Some(pass)) => Credentials::Basic { user: user.to_string(), pass: pass.to_string() },
+            _ => return None,
+        };
+        Some(ConfiguredCredentials::Plain(credentials))
+    }
+}
+
+/// Credentials for the review service at `host` (e.g. https://gitea.example.com), from the
+/// first source having some: the stk config's `config`, a token in one of `env_vars`, then git's credential helpers
+pub(crate) fn resolve(config: Option<&ConfiguredCredentials>, host: &str, env_vars: &[&str]) -> Option<Found> {
+    configured(config, host)
+        .or_else(|| env_token(env_vars))
+        .or_else(|| credential_helper(host))
+}
+
+/// Like `resolve`, for services which take a user and password - from `user_var` and `pass_var` in the environment
+pub(crate) fn resolve_basic(config: Option<&ConfiguredCredentials>, host: &str, user_var: &str, pass_var: &str) -> Option<Found> {
+    let env = match (std::env::var(user_var), std::env::var(pass_var)) {
+        (Ok(user), Ok(pass)) => Some(Found::new(Credentials::Basic { user, pass }, &format!("${} and ${}", user_var, pass_var))),
+        _ => None,
+    };
+    configured(config, host)
+        .or(env)
+        .or_else(|| credential_helper(host))
+        .filter(|found| matches!(found.credentials, Credentials::Basic { .. }))
+}
+
+/// Credentials for GitHub at `host` - https://github.com or an Enterprise server. Looks at the
+/// stk config, a GitHub App or token in the environment, git's credential helpers and the gh CLI's hosts file.
+pub(crate) fn resolve_github(config: Option<&ConfiguredCredentials>, host: &str) -> Result<Option<Found>> {
+    let domain = Url::parse(host)?.host_str().unwrap_or_default().to_string();
+
+    if let Some(found) = configured(config, host) { return Ok(Some(found)); }
+    if let Some(app) = github_app()? { return Ok(Some(Found::new(app, "GitHub App ($GITHUB_APP_ID)"))); }
+    if let Some(found) = env_token(github_token_vars(&domain)) { return Ok(Some(found)); }
+    if let (Ok(user), Ok(pass)) = (std::env::var("GITHUB_USER"), std::env::var("GITHUB_PASS")) {
+        return Ok(Some(Found::new(Credentials::Basic { user, pass }, "$GITHUB_USER and $GITHUB_PASS")));
+    }
+
+    // GitHub takes tokens rather than passwords - helpers hand them out as the password
+    let helper = credential_helper(host).and_then(|found| {
+        let token = found.credentials.token()?;
+        Some(Found::new(Credentials::Token(token), &found.source))
+    });
+    Ok(helper.or_else(|| gh_token(&domain)))
+}
+
+/// Environment variables GitHub tokens are looked up in, by preference
+pub(crate) fn github_token_vars(domain: &str) -> &'static [&'static str] {
+    match domain {
+        "github.com" => &["GITHUB_TOKEN", "GH_TOKEN"],
+        _ => &["GH_ENTERPRISE_TOKEN", "GITHUB_ENTERPRISE_TOKEN", "GITHUB_TOKEN", "GH_TOKEN"],
+    }
+}
+
+/// A client for the API at `base_uri` (api.github.com when None), authenticated with `credentials`
+pub(crate) fn client(base_uri: Option<&str>, credentials: Option<Credentials>) -> Result<Octocrab> {
+    let mut builder = Octocrab::builder();
+    if let Some(base_uri) = base_uri { builder = builder.base_uri(base_uri)?; }
+
+    Ok(match credentials {
+        None => builder.build()?,
+        Some(Credentials::Token(token)) => builder.personal_token(token).build()?,
+        Some(Credentials::Basic { user, pass }) => builder.basic_auth(user, pass).build()?,
+        Some(Credentials::GithubApp { app_id, installation_id, private_key }) => {
+            let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())
+                .map_err(|e| anyhow!("Invalid GitHub App private key: {}", e))?;
+            builder.app(AppId(app_id), key).build()?.installation(InstallationId(installation_id))
+        }
+    })
+}
+
+/// The stk config's credentials - provided they are meant for `host`
+fn configured(config: Option<&ConfiguredCredentials>, host: &str) -> Option<Found> {
+    match config? {
+        ConfiguredCredentials::Stored(credential) if same_host(&credential.host, host) => {
+            let account = match &credential.user {
+                Some(user) => format!("{} on {}", user, credential.host),
+                None => format!("token for {}", credential.host),
+            };
+            let source = format!("git credential helper ({})", account);
+            Some(Found::new(credential.load()?, &source))
+        }
+        ConfiguredCredentials::Stored(_) => None,
+        ConfiguredCredentials::Plain(credentials) => Some(Found::new(credentials.clone(), "stk config (plaintext)")),
+    }
+}
+
+fn same_host(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/').eq_ignore_ascii_case(b.trim_end_matches('/'))
+}
+
+fn env_token(env_vars: &[&str]) -> Option<Found> {
+    env_vars.iter()
+        .filter_map(|var| Some((var, std::env::var(var).ok()?)))
+        .find(|(_, token)| !token.is_empty())
+        .map(|(var, token)| Found::new(Credentials::Token(token), &format!("${}", var)))
+}
+
+/// A GitHub App from GITHUB_APP_ID, GITHUB_APP_INSTALLATION_ID and GITHUB_APP_PRIVATE_KEY
+/// (the PEM itself) or GITHUB_APP_PRIVATE_KEY_PATH
+fn github_app() -> Result<Option<Credentials>> {
+    let Ok(app_id) = std::env::var("GITHUB_APP_ID") else { return Ok(None) };
+    let app_id = app_id.parse().map_err(|_| anyhow!("GITHUB_APP_ID must be a number, got {}", app_id))?;
+
+    let installation_id = std::env::var("GITHUB_APP_INSTALLATION_ID")
+        .map_err(|_| anyhow!("GITHUB_APP_ID is set, but GITHUB_APP_INSTALLATION_ID is missing"))?;
+    let installation_id = installation_id.parse()
+        .map_err(|_| anyhow!("GITHUB_APP_INSTALLATION_ID must be a number, got {}", installation_id))?;
+
+    let private_key = match (std::env::var("GITHUB_APP_PRIVATE_KEY"), std::env::var("GITHUB_APP_PRIVATE_KEY_PATH")) {
+        (Ok(key), _) => key,
+        (_, Ok(path)) => std::fs::read_to_string(&path).map_err(|e| anyhow!("Could not read {}: {}", path, e))?,
+        _ => return Err(anyhow!("GITHUB_APP_ID is set, but neither GITHUB_APP_PRIVATE_KEY nor GITHUB_APP_PRIVATE_KEY_PATH is")),
+    };
+
+    Ok(Some(Credentials::GithubApp { app_id, installation_id, private_key }))
+}
+
+/// Asks git's credential helpers for whatever credentials they have for `host`
+fn credential_helper(host: &str) -> Option<Found> {
+    let url = Url::parse(host).ok()?;
+    let (ok, output) = git_credential("fill", &url, None, None).ok()?;
+    if !ok { return None; }
+    Some(Found::new(parse_credential(&output)?, "git credential helper"))
+}
+
+/// Runs `git credential <action>` for `url` - never prompting for anything. Returns whether
+/// it succeeded, and its output.
+/// git, never prompting the user - stk asks for what it needs itself
+fn git() -> Command {
+    let mut git = Command::new("git");
+    git.env("GIT_TERMINAL_PROMPT", "0").env("GIT_ASKPASS", "").env("SSH_ASKPASS", "");
+    #[cfg(test)]
+    tests::GIT_CONFIG.with_borrow(|config| if let Some(config) = config { git.env("GIT_CONFIG_GLOBAL", config).env("GIT_CONFIG_NOSYSTEM", "1"); });
+    git
+}
+
+fn git_credential(action: &str, url: &Url, user: Option<&str>, pass: Option<&str>) -> Result<(bool, String)> {
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let mut input = format!("protocol={}\nhost={}\n", url.scheme(), host);
+    if let Some(user) = user { input += &format!("username={}\n", user); }
+    if let Some(pass) = pass { input += &format!("password={}\n", pass); }
+    input += "\n";
+
+    let mut child = git()
+        .args(["credential", action])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes())?;
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    Ok((output.status.success(), String::from_utf8_lossy(&output.stdout).to_string()))
+}
+
+/// Credentials from `git credential fill`'s key=value lines
+fn parse_credential(output: &str) -> Option<Credentials> {
+    let value = |key: &str| output.lines()
+        .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+        .filter(|v| !v.is_empty())
+        .map(str::to_string);
+
+    let pass = value("password")?;
+    match value("username") {
+        Some(user) => Some(Credentials::Basic { user, pass }),
+        None => Some(Credentials::Token(pass)),
+    }
+}
+
+/// The gh CLI's token for `domain` - from its hosts file, or from gh itself when it keeps tokens in the system keyring
+fn gh_token(domain: &str) -> Option<Found> {
+    let hosts = gh_config_dir().and_then(|dir| std::fs::read_to_string(dir.join("hosts.yml")).ok());
+    if let Some(token) = hosts.and_then(|hosts| hosts_file_token(&hosts, domain)) {
+        return Some(Found::new(Credentials::Token(token), "gh CLI (hosts.yml)"));
+    }
+
+    let output = Command::new("gh").args(["auth", "token", "--hostname", domain]).stderr(Stdio::null()).output().ok()?;
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || token.is_empty() { return None; }
+    Some(Found::new(Credentials::Token(token), "gh CLI (gh auth token)"))
+}
+
+fn gh_config_dir() -> Option<PathBuf> {
+    if let Ok(dir) = std::env::var("GH_CONFIG_DIR") { return Some(PathBuf::from(dir)); }
+    if let Ok(dir) = std::env::var("XDG_CONFIG_HOME") { return Some(PathBuf::from(dir).join("gh")); }
+    if let Ok(home) = std::env::var("HOME") { return Some(PathBuf::from(home).join(".config").join("gh")); }
+    std::env::var("APPDATA").ok().map(|dir| PathBuf::from(dir).join("GitHub CLI"))
+}
+
+/// The `oauth_token` of `domain` in gh's hosts.yml - the active account's, which sits right
+/// under the host, before those of other accounts listed under `users:`
+fn hosts_file_token(hosts: &str, domain: &str) -> Option<String> {
+    let mut lines = hosts.lines().skip_while(|l| l.trim_end().trim_end_matches(':').trim_matches('"') != domain);
+    lines.next()?;
+
+    lines.take_while(|l| l.is_empty() || l.starts_with(char::is_whitespace))
+        .filter_map(|l| {
+            let indent = l.len() - l.trim_start().len();
+            let token = l.trim().strip_prefix("oauth_token:")?.trim().trim_matches('"');
+            Some((indent, token.to_string()))
+        })
+        .filter(|(_, token)| !token.is_empty())
+        .min_by_key(|(indent, _)| *indent)
+        .map(|(_, token)| token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// The global git config this thread's git commands use - the user's when None
+        pub(super) static GIT_CONFIG: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    }
+
+    /// A global git config keeping credentials in a file of its own, used by this thread until dropped
+    struct ScratchCredentials {
+        dir: PathBuf,
+    }
+
+    impl ScratchCredentials {
+        fn new() -> ScratchCredentials {
+            let dir = std::env::temp_dir().join(format!("stk-credentials-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let config = dir.join("gitconfig");
+            std::fs::write(&config, format!("[credential]\n\thelper = store --file={}\n", dir.join("store").display())).unwrap();
+            GIT_CONFIG.set(Some(config));
+            ScratchCredentials { dir }
+        }
+    }
+
+    impl Drop for ScratchCredentials {
+        fn drop(&mut self) {
+            GIT_CONFIG.set(None);
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn test_parse_credential() {
+        let Some(Credentials::Basic { user, pass }) = parse_credential("protocol=https\nhost=github.com\nusername=me\npassword=secret\n") else { panic!() };
+        assert_eq!((user.as_str(), pass.as_str()), ("me", "secret"));
+        assert!(matches!(parse_credential("password=tok\n"), Some(Credentials::Token(t)) if t == "tok"));
+        assert!(parse_credential("protocol=https\nhost=github.com\n").is_none());
+    }
+
+    #[test]
+    fn test_secrets_are_stored_apart_from_git_credentials() {
+        let _scratch = ScratchCredentials::new();
+
+        let credential = CredentialRef { host: "https://git.example.com".to_string(), user: None };
+        assert_eq!(credential.store_url().unwrap().as_str(), "https://git.example.com.stk/");
+        credential.store("secret").unwrap();
+
+        assert!(matches!(credential.load(), Some(Credentials::Token(t)) if t == "secret"));
+        // Pushing to the service's host doesn't see stk's secret
+        let (_, output) = git_credential("fill", &Url::parse("https://git.example.com").unwrap(), None, None).unwrap();
+        assert!(!output.contains("secret"));
+
+        credential.erase().unwrap();
+        assert!(credential.load().is_none());
+    }
+
+    #[test]
+    fn test_hosts_file_token() {
+        let hosts = "github.com:
+    users:
+        other:
+            oauth_token: gho_other
+        me:
+            oauth_token: gho_me
+    git_protocol: https
+    oauth_token: gho_me
+    user: me
+github.example.com:
+    oauth_token: ghe_token
+    user: me
+";
+        assert_eq!(hosts_file_token(hosts, "github.com"), Some("gho_me".to_string()));
+        assert_eq!(hosts_file_token(hosts, "github.example.com"), Some("ghe_token".to_string()));
+        assert_eq!(hosts_file_token(hosts, "gitlab.com"), None);
+        // Tokens kept in the keyring aren't in the file
+        assert_eq!(hosts_file_token("github.com:\n    git_protocol: ssh\n    user: me\n", "github.com"), None);
+    }
+}
@@ -0,0 +1,24 @@
+/// Scenarios every review service is tested against - each backend's tests supply the
+/// service and its canned responses.
+use crate::mock_server::MockServer;
+use crate::{MergeOptions, MergeState, Review, ReviewService};
+
+/// Merges review `id`, which the server refuses - returns why
+pub(crate) async fn refused_merge(service: &dyn ReviewService, id: &str, options: &MergeOptions) -> String {
+    let review = service.review(id).await.unwrap().unwrap();
+    let merged = service.merge(&review, options).await.unwrap();
+
+    let MergeState::Failed(reason) = merged.state else { panic!("merge of {} should have failed", id) };
+    reason
+}
+
+/// The open reviews for `branch`, and the query that selected them from `path`
+pub(crate) async fn reviews_for(service: &dyn ReviewService, server: &MockServer, branch: &str, path: &str) -> (Vec<Review>, Vec<(String, String)>) {
+    let reviews = service.reviews_for(branch).await.unwrap();
+    (reviews, server.query("GET", path))
+}
+
+/// The value of `key` in a `query`
+pub(crate) fn param<'a>(query: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    query.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
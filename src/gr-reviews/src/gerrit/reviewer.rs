@@ -0,0 +1,536 @@
+use std::collections::{BTreeMap, HashMap};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use colored::Colorize;
+use gr_git::Git;
+use octocrab::Octocrab;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use crate::rest::{send, Method};
+use crate::{CodeReviewService, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewTest, ReviewTestState};
+use crate::merge_requests::stale_head;
+use crate::verdicts::{state_of_review, ReviewStatus};
+
+/// Run after each commit of a rebase - gives commits without a Change-Id one.
+/// A commit's own sha is as unique as the id Gerrit's commit-msg hook would have made up.
+const ADD_CHANGE_ID: &str = "git log -1 --format=%B | grep -q '^Change-Id: I' || \
+    git commit --quiet --amend --no-edit --no-verify --trailer \"Change-Id: I$(git rev-parse HEAD)\"";
+
+/// Query options for change details: votes, the current patch set and whether it can be submitted
+const CHANGE_OPTIONS: [&str; 5] = ["LABELS", "DETAILED_ACCOUNTS", "CURRENT_REVISION", "CURRENT_COMMIT", "SUBMITTABLE"];
+
+/// Reviews changes on a Gerrit server.
+///
+/// Gerrit reviews commits rather than branches: each branch is published by pushing it to
+/// `refs/for/<parent>` with the branch name as the change's topic, which is how changes are
+/// mapped back to branches. A branch with several commits makes a chain of changes under its
+/// topic - the change of the branch's tip commit stands for the branch, and submitting it
+/// submits the chain below it too.
+pub struct GerritReviewer {
+    client: Octocrab,
+    /// The server as configured, e.g. https://review.example.com
+    host: String,
+    project: String,
+    /// Only signed in users can search for their own changes
+    signed_in: bool,
+}
+
+#[derive(Clone, Deserialize)]
+struct GerritAccount {
+    username: Option<String>,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GerritVote {
+    #[serde(flatten)]
+    account: GerritAccount,
+    /// Reviewers who haven't voted have no value
+    #[serde(default)]
+    value: i32,
+}
+
+#[derive(Deserialize)]
+struct GerritLabel {
+    #[serde(default)]
+    all: Vec<GerritVote>,
+    /// Set when someone gave the label's highest vote - e.g. Code-Review +2 or Verified +1
+    approved: Option<GerritAccount>,
+    /// Set when someone gave the label's lowest, blocking vote
+    rejected: Option<GerritAccount>,
+    /// Optional labels don't block submission
+    #[serde(default)]
+    optional: bool,
+}
+
+#[derive(Deserialize)]
+struct GerritCommit {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct GerritRevision {
+    commit: Option<GerritCommit>,
+}
+
+#[derive(Deserialize)]
+struct GerritChange {
+    #[serde(rename = "_number")]
+    number: u64,
+    /// The Change-Id trailer shared by all of the change's patch sets
+    change_id: String,
+    /// The server-side branch the change is destined for
+    branch: String,
+    /// The local branch the change was published from
+    topic: Option<String>,
+    subject: String,
+    /// NEW, MERGED or ABANDONED
+    status: String,
+    mergeable: Option<bool>,
+    submittable: Option<bool>,
+    #[serde(default)]
+    work_in_progress: bool,
+    current_revision: Option<String>,
+    #[serde(default)]
+    revisions: HashMap<String, GerritRevision>,
+    #[serde(default)]
+    labels: BTreeMap<String, GerritLabel>,
+    /// Reviewer state (REVIEWER, CC...) -> accounts
+    #[serde(default)]
+    reviewers: HashMap<String, Vec<GerritAccount>>,
+}
+
+impl GerritAccount {
+    fn display_name(&self) -> String {
+        self.username.clone().or(self.name.clone()).or(self.email.clone()).unwrap_or("?".to_string())
+    }
+}
+
+impl GerritReviewer {
+    /// `host` is the server's web address. With `credentials` (user, HTTP password)
+    /// requests go through the authenticated /a/ endpoints.
+    pub fn new(host: &str, project: &str, credentials: Option<(String, String)>) -> Result<GerritReviewer> {
+        let host = host.trim_end_matches('/').to_string();
+        let signed_in = credentials.is_some();
+        let builder = match credentials {
+            Some((user, pass)) => Octocrab::builder().base_uri(format!("{}/a", host))?.basic_auth(user, pass),
+            None => Octocrab::builder().base_uri(host.clone())?,
+        };
+
+        Ok(GerritReviewer {
+            client: builder.build()?,
+            host,
+            project: project.to_string(),
+            signed_in,
+        })
+    }
+
+    /// `path` with `params` url-encoded into its query
+    fn route(path: &str, params: &[(&str, &str)]) -> String {
+        if params.is_empty() { return path.to_string(); }
+        let query = url::form_urlencoded::Serializer::new(String::new()).extend_pairs(params).finish();
+        format!("{}?{}", path, query)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, route: &str) -> Result<T> {
+        match send(&self.client, Method::Get, route, None).await? {
+            (200..=299, body) => parse(&body),
+            (status, body) => Err(anyhow!("Gerrit refused {} ({}): {}", route, status, body.trim())),
+        }
+    }
+
+    async fn change(&self, id: &str) -> Result<GerritChange> {
+        let options = CHANGE_OPTIONS.iter().map(|o| ("o", *o)).collect::<Vec<(&str, &str)>>();
+        self.get(&Self::route(&format!("/changes/{}", id), &options)).await
+    }
+
+    /// Changes matching the Gerrit search `query`, within this project
+    async fn changes(&self, query: &str) -> Result<Vec<GerritChange>> {
+        let query = format!("project:\"{}\" {}", self.project, query);
+        let mut params = vec![("q", query.as_str())];
+        params.extend(CHANGE_OPTIONS.iter().map(|o| ("o", *o)));
+        self.get(&Self::route("/changes/", &params)).await
+    }
+
+    /// The changes published from `branch` which match `query` - just the change of the branch's tip
+    /// commit, where the branch is ours to look at
+    async fn branch_changes(&self, branch: &str, query: &str) -> Result<Vec<Review>> {
+        // Anyone can use the same topic - only our own changes come from our branches
+        let owner = if self.signed_in { "owner:self " } else { "" };
+        let changes = self.changes(&format!("{}{}topic:\"{}\"", owner, query, branch)).await?;
+
+        Ok(of_tip(changes, tip_change_id(branch).as_deref())
+            .into_iter()
+            .map(|c| self.convert_to_review(c))
+            .collect())
+    }
+
+    fn convert_to_review(&self, change: GerritChange) -> Review {
+        let tests = review_tests(&change);
+        let state = state_of_review(&status_of(&change), &tests);
+        let message = change.current_revision.as_ref()
+            .and_then(|sha| change.revisions.get(sha))
+            .and_then(|r| r.commit.as_ref())
+            .map(|c| c.message.clone())
+            .unwrap_or_default();
+
+        Review {
+            id: change.number.to_string(),
+            branch: change.topic.clone().unwrap_or_default(),
+            base: change.branch.clone(),
+            head: change.current_revision.clone(),
+            title: change.subject.clone(),
+            // Everything after the subject line
+            body: message.split_once('\n').map(|(_, body)| body.trim().to_string()).unwrap_or_default(),
+            service: CodeReviewService::Gerrit { host: self.host.clone() },
+            url: Url::parse(&format!("{}/c/{}/+/{}", self.host, self.project, change.number)).ok(),
+            reviewers: reviewers_of(&change),
+            approvals: approvals_of(&change),
+            state,
+            tests,
+        }
+    }
+}
+
+/// Gerrit prefixes its JSON with )]}' to stop it being run as a script
+fn parse<T: DeserializeOwned>(body: &str) -> Result<T> {
+    Ok(serde_json::from_str(body.trim_start_matches(")]}'"))?)
+}
+
+/// The Change-Id of `branch`'s tip commit, if it is a local branch with one
+fn tip_change_id(branch: &str) -> Option<String> {
+    Git::new().log(vec!["-1", "--format=%(trailers:key=Change-Id,valueonly)", &format!("refs/heads/{}", branch)]).ok()?
+        .into_iter()
+        .find(|id| !id.is_empty())
+}
+
+/// The change for the commit with Change-Id `tip` - or every change, without a tip to go by
+fn of_tip(changes: Vec<GerritChange>, tip: Option<&str>) -> Vec<GerritChange> {
+    match tip {
+        Some(tip) => changes.into_iter().filter(|c| c.change_id == tip).collect(),
+        None => changes,
+    }
+}
+
+/// Shas of the commits in `log` - lines of "<sha> <change id>" - without a Change-Id
+fn commits_without_change_id(log: &[String]) -> Vec<String> {
+    log.iter()
+        .filter(|l| l.split_whitespace().count() == 1)
+        .map(|l| l.trim().to_string())
+        .collect()
+}
+
+/// Where changes for a branch stacked on `parent` are pushed: the parent - or, when the server has no
+/// such branch, the nearest ancestor it does have. Gerrit chains stacked changes through their commits.
+fn destination(git: &Git, remote: &str, parent: &str) -> Result<String> {
+    let graph = git.stack_graph()?;
+    let on_remote = |b: &String| git.rev_parse(vec!["--verify", "--quiet", &format!("refs/remotes/{}/{}", remote, b)]).is_ok();
+
+    Ok(std::iter::once(parent.to_string())
+        .chain(graph.ancestors(parent))
+        .find(on_remote)
+        .unwrap_or(parent.to_string()))
+}
+
+/// Everyone asked to review - plus anyone who voted anyway
+fn reviewers_of(change: &GerritChange) -> Vec<String> {
+    let mut reviewers = change.reviewers.get("REVIEWER").into_iter()
+        .flatten()
+        .map(|a| a.display_name())
+        .chain(change.labels.values().flat_map(|l| l.all.iter().filter(|v| v.value != 0).map(|v| v.account.display_name())))
+        .collect::<Vec<String>>();
+    reviewers.sort();
+    reviewers.dedup();
+    reviewers
+}
+
+/// Reviewers with a positive Code-Review vote
+fn approvals_of(change: &GerritChange) -> Vec<String> {
+    let mut approvals = change.labels.get("Code-Review")
+        .map(|l| l.all.iter().filter(|v| v.value > 0).map(|v| v.account.display_name()).collect::<Vec<String>>())
+        .unwrap_or_default();
+    approvals.sort();
+    approvals
+}
+
+/// One test per label - Code-Review, Verified and whatever else the project requires
+fn review_tests(change: &GerritChange) -> Vec<ReviewTest> {
+    change.labels.iter()
+        .map(|(name, label)| ReviewTest { name: name.clone(), state: label_state(label) })
+        .collect()
+}
+
+fn label_state(label: &GerritLabel) -> ReviewTestState {
+    if label.rejected.is_some() { return ReviewTestState::Failed; }
+    if label.approved.is_some() || label.optional { return ReviewTestState::Passed; }
+    ReviewTestState::Pending
+}
+
+fn status_of(change: &GerritChange) -> ReviewStatus {
+    ReviewStatus {
+        merged: change.status == "MERGED",
+        closed: change.status == "ABANDONED",
+        draft: change.work_in_progress,
+        conflicted: change.mergeable == Some(false),
+        // A blocking vote, e.g. Code-Review -2 or Verified -1
+        rejected: change.labels.values().any(|l| l.rejected.is_some()),
+        // Gerrit knows best whether its submit requirements are met
+        ready: change.submittable,
+    }
+}
+
+/// Explains why Gerrit refused to submit a change
+fn submit_failure_reason(status: u16, message: &str) -> String {
+    let message = message.trim();
+    match status {
+        // Missing votes, failing submit requirements, conflicts...
+        409 => format!("not submittable: {}", message),
+        403 => format!("not permitted to submit: {}", message),
+        _ => format!("submit failed ({}): {}", status, message),
+    }
+}
+
+#[async_trait]
+impl ReviewService for GerritReviewer {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        // How changes land - merge, rebase, cherry-pick... - is the project's submit type.
+        // Gerrit has no squash: each change already is one commit.
+        let change = self.change(&review.id).await?;
+        let head = change.current_revision.clone();
+        let review = self.convert_to_review(change);
+
+        // Submitting can't be made conditional on the patch set - check it ourselves
+        if let (Some(expected), Some(head)) = (&options.expected_head, &head) {
+            if expected != head {
+                let reason = stale_head("latest patch set", expected);
+                return Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)));
+            }
+        }
+
+        match send(&self.client, Method::Post, &format!("/changes/{}/submit", review.id), Some(&json!({}))).await? {
+            (200..=299, _) => Ok(MergeRequest::new(review).in_state(MergeState::Merged)),
+            (status, message) => Ok(MergeRequest::new(review).in_state(MergeState::Failed(submit_failure_reason(status, &message)))),
+        }
+    }
+
+    async fn review(&self, id: &str) -> Result<Option<Review>> {
+        Ok(Some(self.convert_to_review(self.change(id).await?)))
+    }
+
+    async fn reviews(&self) -> Result<Vec<Review>> {
+        Ok(self.changes("status:open").await?
+            .into_iter()
+            .map(|c| self.convert_to_review(c))
+            .collect())
+    }
+
+    async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        self.branch_changes(branch, "status:open ").await
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        self.branch_changes(branch, "").await
+    }
+
+    async fn create_review(&self, branch: &str, parent: &str, _title: &str, _body: &str) -> Result<Review> {
+        // Publishing the branch created the change - its title and body are the commit message's
+        println!("Looking up the change for {} [on {}] at {}", branch.cyan(), parent.black(), self.project.green());
+        self.reviews_for(branch).await?
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Gerrit has no open change for {} - was it pushed to refs/for/{}?", branch, parent))
+    }
+
+    async fn publish(&self, remote: &str, branch: &str, parent: &str) -> Result<()> {
+        if parent.is_empty() {
+            return Err(anyhow!("{} has no stack parent to push it for review against", branch));
+        }
+        let git = Git::new();
+
+        // Gerrit matches new patch sets to their change by Change-Id - every commit needs one
+        let log = git.log(vec![&format!("{}..{}", parent, branch), "--format=%H %(trailers:key=Change-Id,valueonly,separator=%x20)"])?;
+        let missing = commits_without_change_id(&log);
+        if !missing.is_empty() {
+            println!("Adding Change-Ids to {} commit(s) on {}", missing.len(), branch.cyan());
+            let current = git.current_branch()?;
+            // Rewriting the branch's commits restacks everything above it too
+            git.recursive_rebase(branch, vec!["--exec", ADD_CHANGE_ID])?;
+            git.switch(&current)?;
+        }
+
+        let target = destination(&git, remote, parent)?;
+        match git.push(vec![remote, &format!("{}:refs/for/{}%topic={}", branch, target, branch)]) {
+            Ok(_) => Ok(()),
+            // Nothing changed since the last push
+            Err(e) if e.to_string().contains("no new changes") => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        // Stacked changes share their destination - only move to branches the server has
+        let branch = format!("/projects/{}/branches/{}", self.project.replace('/', "%2F"), base.replace('/', "%2F"));
+        if review.base == base || send(&self.client, Method::Get, &branch, None).await?.0 == 404 {
+            return Ok(review.clone());
+        }
+
+        match send(&self.client, Method::Post, &format!("/changes/{}/move", review.id), Some(&json!({ "destination_branch": base }))).await? {
+            (200..=299, body) => Ok(self.convert_to_review(parse(&body)?)),
+            (status, message) => Err(anyhow!("Gerrit could not move change {} to {} ({}): {}", review.id, base, status, message.trim())),
+        }
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        match send(&self.client, Method::Post, &format!("/changes/{}/abandon", review.id), Some(&json!({ "message": comment }))).await? {
+            (200..=299, _) => Ok(()),
+            (status, message) => Err(anyhow!("Gerrit could not abandon change {} ({}): {}", review.id, status, message.trim())),
+        }
+    }
+
+    async fn enable_auto_merge(&self, _review: &Review, _options: &MergeOptions) -> Result<bool> {
+        // Gerrit has no auto-submit out of the box
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{param, refused_merge, reviews_for};
+    use crate::mock_server::MockServer;
+    use crate::ReviewState;
+
+    fn change_json(number: u64, topic: &str, code_review: serde_json::Value, verified: serde_json::Value, submittable: bool) -> serde_json::Value {
+        json!({
+            "_number": number,
+            "project": "platform/tools",
+            "branch": "main",
+            "topic": topic,
+            "change_id": format!("I{:040}", number),
+            "subject": format!("Change {}", number),
+            "status": "NEW",
+            "mergeable": true,
+            "submittable": submittable,
+            "current_revision": "abc123",
+            "revisions": { "abc123": { "commit": { "message": format!("Change {}\n\nBody\n\nChange-Id: I{:040}\n", number, number) } } },
+            "labels": { "Code-Review": code_review, "Verified": verified },
+            "reviewers": { "REVIEWER": [{ "username": "carol" }] },
+        })
+    }
+
+    /// Gerrit's XSSI guard, as the real server sends it
+    fn gerrit_body(json: serde_json::Value) -> String {
+        format!(")]}}'\n{}", json)
+    }
+
+    fn reviewer(server: &MockServer) -> GerritReviewer {
+        GerritReviewer::new(server.url(), "platform/tools", Some(("me".to_string(), "http-pass".to_string()))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_votes_and_verified_labels() {
+        let server = MockServer::start().await;
+        let change = change_json(7, "feature",
+                                 json!({ "all": [{ "username": "alice", "value": 2 }, { "username": "bob", "value": -1 }], "approved": { "username": "alice" } }),
+                                 json!({ "all": [] }),
+                                 false);
+        server.on("GET", "/a/changes/7", 200, &gerrit_body(change));
+
+        let review = reviewer(&server).review("7").await.unwrap().unwrap();
+
+        assert_eq!(review.branch, "feature");
+        assert_eq!(review.base, "main");
+        assert_eq!(review.body, "Body\n\nChange-Id: I0000000000000000000000000000000000000007");
+        assert_eq!(review.approvals, vec!["alice"]);
+        assert_eq!(review.reviewers, vec!["alice", "bob", "carol"]);
+        let tests = review.tests.iter().map(|t| (t.name.as_str(), matches!(t.state, ReviewTestState::Passed))).collect::<Vec<(&str, bool)>>();
+        assert_eq!(tests, vec![("Code-Review", true), ("Verified", false)]);
+        assert!(matches!(review.state, ReviewState::Pending));
+        assert!(server.requests()[0].path.contains("o=SUBMITTABLE"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_verification_rejects() {
+        let server = MockServer::start().await;
+        let change = change_json(8, "feature", json!({ "approved": { "username": "alice" } }), json!({ "rejected": { "username": "ci" } }), false);
+        server.on("GET", "/a/changes/8", 200, &gerrit_body(change));
+
+        let review = reviewer(&server).review("8").await.unwrap().unwrap();
+
+        assert!(matches!(review.state, ReviewState::Rejected));
+    }
+
+    #[tokio::test]
+    async fn test_reviews_for_queries_the_topic() {
+        let server = MockServer::start().await;
+        let change = change_json(9, "stack/b", json!({}), json!({}), true);
+        server.on("GET", "/a/changes/", 200, &gerrit_body(json!([change])));
+
+        let (reviews, query) = reviews_for(&reviewer(&server), &server, "stack/b", "/a/changes/").await;
+
+        assert_eq!(reviews.len(), 1);
+        assert!(matches!(reviews[0].state, ReviewState::Approved));
+        assert_eq!(param(&query, "q"), Some("project:\"platform/tools\" owner:self status:open topic:\"stack/b\""));
+    }
+
+    #[tokio::test]
+    async fn test_submit() {
+        let server = MockServer::start().await;
+        let change = change_json(4, "feature", json!({ "approved": { "username": "alice" } }), json!({ "approved": { "username": "ci" } }), true);
+        server.on("GET", "/a/changes/4", 200, &gerrit_body(change));
+        server.on("POST", "/a/changes/4/submit", 200, &gerrit_body(json!({ "status": "MERGED" })));
+
+        let gerrit = reviewer(&server);
+        let review = gerrit.review("4").await.unwrap().unwrap();
+        let merged = gerrit.merge(&review, &MergeOptions { expected_head: Some("abc123".to_string()), ..Default::default() }).await.unwrap();
+
+        assert!(matches!(merged.state, MergeState::Merged));
+        assert_eq!(server.requests_to("POST", "/a/changes/4/submit").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_submit_refuses_stale_patch_set() {
+        let server = MockServer::start().await;
+        let change = change_json(5, "feature", json!({}), json!({}), true);
+        server.on("GET", "/a/changes/5", 200, &gerrit_body(change));
+
+        let options = MergeOptions { expected_head: Some("def4567890".to_string()), ..Default::default() };
+        let reason = refused_merge(&reviewer(&server), "5", &options).await;
+
+        assert!(reason.contains("no longer def4567"));
+        assert!(server.requests_to("POST", "/a/changes/5/submit").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retarget_keeps_destination_the_server_lacks() {
+        let server = MockServer::start().await;
+        let change = change_json(6, "feature", json!({}), json!({}), false);
+        server.on("GET", "/a/changes/6", 200, &gerrit_body(change));
+
+        let gerrit = reviewer(&server);
+        let review = gerrit.review("6").await.unwrap().unwrap();
+        let moved = gerrit.retarget(&review, "local-only").await.unwrap();
+
+        assert_eq!(moved.base, "main");
+        assert!(server.requests_to("POST", "/a/changes/6/move").is_empty());
+    }
+
+    #[test]
+    fn test_branch_is_its_tip_change() {
+        let chain = vec![change_json(1, "stack/b", json!({}), json!({}), false), change_json(2, "stack/b", json!({}), json!({}), false)];
+        let changes = || serde_json::from_value::<Vec<GerritChange>>(json!(chain)).unwrap();
+
+        let tip = of_tip(changes(), Some("I0000000000000000000000000000000000000002"));
+        assert_eq!(tip.iter().map(|c| c.number).collect::<Vec<u64>>(), vec![2]);
+        assert_eq!(of_tip(changes(), None).len(), 2);
+    }
+
+    #[test]
+    fn test_commits_without_change_id() {
+        let log = vec!["aaa Iabc".to_string(), "bbb".to_string(), "ccc Idef".to_string()];
+        assert_eq!(commits_without_change_id(&log), vec!["bbb"]);
+    }
+}
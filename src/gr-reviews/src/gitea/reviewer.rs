@@ -0,0 +1,504 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use colored::Colorize;
+use octocrab::Octocrab;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use crate::{CodeReviewService, MergeMethod, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewTest, ReviewTestState};
+use crate::merge_requests::Refusal;
+use crate::verdicts::{state_of_review, ReviewStatus, Verdicts};
+
+/// Pull requests fetched per page - Gitea caps pages at 50 by default
+const PAGE_SIZE: usize = 50;
+
+/// Reviews pull requests on a Gitea or Forgejo server.
+/// Their REST API is modelled on GitHub's - errors included - so octocrab serves as a plain REST client.
+pub struct GiteaReviewer {
+    client: Octocrab,
+    /// The server as configured, e.g. https://gitea.example.com
+    host: String,
+    owner: String,
+    repo: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GiteaUser {
+    login: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GiteaBranch {
+    #[serde(rename = "ref")]
+    ref_field: String,
+    sha: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GiteaPull {
+    number: u64,
+    title: String,
+    body: Option<String>,
+    /// open or closed - merged pull requests are closed too
+    state: String,
+    #[serde(default)]
+    merged: bool,
+    /// None until the server has worked out whether it merges cleanly
+    mergeable: Option<bool>,
+    /// Only reported by newer servers - older ones mark drafts with a WIP title prefix
+    #[serde(default)]
+    draft: bool,
+    html_url: Option<Url>,
+    head: GiteaBranch,
+    base: GiteaBranch,
+    requested_reviewers: Option<Vec<GiteaUser>>,
+}
+
+#[derive(Deserialize)]
+struct GiteaReview {
+    user: Option<GiteaUser>,
+    /// APPROVED, REQUEST_CHANGES, COMMENT, PENDING or REQUEST_REVIEW
+    state: String,
+    #[serde(default)]
+    dismissed: bool,
+}
+
+#[derive(Deserialize)]
+struct GiteaStatus {
+    context: String,
+    /// pending, success, error, failure or warning
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct GiteaCombinedStatus {
+    statuses: Option<Vec<GiteaStatus>>,
+}
+
+struct PullWithChecks {
+    pull: GiteaPull,
+    /// Latest commit status per context
+    statuses: Vec<GiteaStatus>,
+    verdicts: Verdicts,
+    /// Approvals the base branch's protection rules require
+    required_approvals: usize,
+}
+
+impl GiteaReviewer {
+    /// `host` is the server's web address - the API lives under /api/v1
+    pub fn new(host: &str, owner: &str, repo: &str, token: Option<String>) -> Result<GiteaReviewer> {
+        let host = host.trim_end_matches('/').to_string();
+        let mut builder = Octocrab::builder().base_uri(format!("{}/api/v1", host))?;
+        if let Some(token) = token { builder = builder.personal_token(token); }
+
+        Ok(GiteaReviewer {
+            client: builder.build()?,
+            host,
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+        })
+    }
+
+    fn route(&self, path: &str) -> String {
+        format!("/repos/{}/{}{}", self.owner, self.repo, path)
+    }
+
+    async fn pull(&self, number: u64) -> Result<GiteaPull> {
+        Ok(self.client.get(self.route(&format!("/pulls/{}", number)), None::<&()>).await?)
+    }
+
+    /// Page `page` of the pull requests in `state` (open, closed or all), most recently updated first
+    async fn pulls_page(&self, state: &str, page: usize) -> Result<Vec<GiteaPull>> {
+        let params = [("state", state.to_string()), ("sort", "recentupdate".to_string()),
+                      ("page", page.to_string()), ("limit", PAGE_SIZE.to_string())];
+        Ok(self.client.get(self.route("/pulls"), Some(&params)).await?)
+    }
+
+    /// Every pull request in `state`, following pagination
+    async fn pulls(&self, state: &str) -> Result<Vec<GiteaPull>> {
+        let mut pulls = Vec::new();
+        for page in 1.. {
+            let batch = self.pulls_page(state, page).await?;
+            let done = batch.len() < PAGE_SIZE;
+            pulls.extend(batch);
+            if done { break; }
+        }
+        Ok(pulls)
+    }
+
+    /// The latest pull requests from `branch` in `state`. Gitea only looks pull requests up by
+    /// base and head together, so this pages through the repo's - stopping at the first page
+    /// which has any from `branch`, as only a branch's latest reviews matter to stk.
+    async fn pulls_from(&self, state: &str, branch: &str) -> Result<Vec<GiteaPull>> {
+        let mut pulls = Vec::new();
+        for page in 1.. {
+            let batch = self.pulls_page(state, page).await?;
+            let done = batch.len() < PAGE_SIZE;
+            pulls.extend(batch.into_iter().filter(|p| p.head.ref_field == branch));
+            if done || !pulls.is_empty() { break; }
+        }
+        Ok(pulls)
+    }
+
+    async fn with_checks(&self, pull: GiteaPull) -> Result<PullWithChecks> {
+        let statuses = match self.client
+            .get::<GiteaCombinedStatus, _, ()>(self.route(&format!("/commits/{}/status", pull.head.sha)), None).await {
+            Ok(combined) => combined.statuses.unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+
+        Ok(PullWithChecks {
+            verdicts: self.verdicts(pull.number).await,
+            required_approvals: self.required_approvals(&pull.base.ref_field).await,
+            statuses,
+            pull,
+        })
+    }
+
+    async fn verdicts(&self, number: u64) -> Verdicts {
+        let mut verdicts = Verdicts::default();
+        let reviews = match self.client
+            .get::<Vec<GiteaReview>, _, ()>(self.route(&format!("/pulls/{}/reviews", number)), None).await {
+            Ok(reviews) => reviews,
+            Err(_) => return verdicts,
+        };
+
+        // Reviews come oldest first - only each reviewer's latest verdict counts
+        for review in reviews {
+            let Some(user) = review.user else { continue };
+            if review.dismissed {
+                verdicts.dismiss(&user.login);
+                continue;
+            }
+            match review.state.as_str() {
+                "APPROVED" => verdicts.approve(user.login),
+                "REQUEST_CHANGES" => verdicts.request_changes(user.login),
+                _ => {},  // comments don't change a verdict
+            }
+        }
+        verdicts
+    }
+
+    async fn required_approvals(&self, base: &str) -> usize {
+        // Gitea finds protection rules by name, so this sees a rule named after the branch - without
+        // one (404) no approvals are needed. Only repo admins may read rules; others assume one approval.
+        match self.client.get::<serde_json::Value, _, ()>(self.route(&format!("/branch_protections/{}", base)), None).await {
+            Ok(rules) => rules["required_approvals"].as_u64().unwrap_or(0) as usize,
+            Err(octocrab::Error::GitHub { source, .. }) if source.status_code.as_u16() == 404 => 0,
+            Err(_) => 1,
+        }
+    }
+
+    async fn convert_to_review(&self, pull: GiteaPull) -> Result<Review> {
+        let prc = self.with_checks(pull).await?;
+        let tests = review_tests(&prc);
+        let state = state_of_review(&status_of(&prc.pull, &prc.verdicts), &tests);
+
+        Ok(Review {
+            id: prc.pull.number.to_string(),
+            branch: prc.pull.head.ref_field.clone(),
+            base: prc.pull.base.ref_field.clone(),
+            head: Some(prc.pull.head.sha.clone()),
+            title: prc.pull.title.clone(),
+            body: prc.pull.body.clone().unwrap_or_default(),
+            service: CodeReviewService::Gitea { host: self.host.clone() },
+            url: prc.pull.html_url.clone(),
+            reviewers: prc.verdicts.reviewers(prc.pull.requested_reviewers.iter().flatten().map(|u| u.login.clone())),
+            approvals: prc.verdicts.approvals(),
+            state,
+            tests,
+        })
+    }
+
+    /// Asks Gitea to merge pull request `number` - now, or once its checks succeed.
+    /// Err holds the status and message of a refusal.
+    async fn request_merge(&self, review: &Review, options: &MergeOptions, when_checks_succeed: bool) -> Result<std::result::Result<(), (u16, String)>> {
+        let body = json!({
+            "Do": gitea_merge_style(options.method),
+            "MergeTitleField": format!("{} (#{})", review.title, review.id),
+            "MergeMessageField": review.body,
+            "head_commit_id": options.expected_head.clone().unwrap_or_default(),
+            "merge_when_checks_succeed": when_checks_succeed,
+        });
+        let response = self.client._post(self.route(&format!("/pulls/{}/merge", review.id)), Some(&body)).await?;
+
+        match octocrab::map_github_error(response).await {
+            Ok(_) => Ok(Ok(())),
+            Err(octocrab::Error::GitHub { source, .. }) => Ok(Err((source.status_code.as_u16(), source.message))),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// One test per commit status, plus one for the required approvals
+fn review_tests(prc: &PullWithChecks) -> Vec<ReviewTest> {
+    let statuses = prc.statuses.iter()
+        .map(|s| ReviewTest { name: s.context.clone(), state: status_state(&s.status) });
+
+    statuses.chain(std::iter::once(prc.verdicts.approval_test(prc.required_approvals))).collect()
+}
+
+fn status_state(status: &str) -> ReviewTestState {
+    match status {
+        "success" => ReviewTestState::Passed,
+        "pending" => ReviewTestState::Pending,
+        // error, failure, warning
+        _ => ReviewTestState::Failed,
+    }
+}
+
+fn is_draft(pull: &GiteaPull) -> bool {
+    let title = pull.title.to_uppercase();
+    pull.draft || title.starts_with("WIP:") || title.starts_with("[WIP]")
+}
+
+fn status_of(pull: &GiteaPull, verdicts: &Verdicts) -> ReviewStatus {
+    ReviewStatus {
+        merged: pull.merged,
+        closed: pull.state == "closed",
+        draft: is_draft(pull),
+        // Not worked out yet isn't a conflict
+        conflicted: pull.mergeable == Some(false),
+        rejected: verdicts.changes_requested(),
+        ready: None,
+    }
+}
+
+fn gitea_merge_style(method: MergeMethod) -> &'static str {
+    match method {
+        MergeMethod::Squash => "squash",
+        MergeMethod::Rebase => "rebase",
+        MergeMethod::Merge => "merge",
+    }
+}
+
+/// Reads why Gitea's merge endpoint refused to merge a pull request
+fn refusal(status: u16, message: &str) -> Refusal {
+    match status {
+        405 if message.to_lowercase().contains("not allowed") => Refusal::MethodDisabled,
+        // Not mergeable yet: failing status checks, missing approvals, still being checked...
+        405 => Refusal::NotMergeable,
+        // Either the head moved since we last looked, or the merge conflicts
+        409 if message.to_lowercase().contains("head") => Refusal::HeadMoved,
+        409 => Refusal::Conflicted,
+        403 => Refusal::NotPermitted,
+        _ => Refusal::Other(status),
+    }
+}
+
+#[async_trait]
+impl ReviewService for GiteaReviewer {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        let pull = self.pull(review.id.parse::<u64>()?).await?;
+        let review = self.convert_to_review(pull).await?;
+
+        match self.request_merge(&review, options, false).await? {
+            Ok(()) => Ok(MergeRequest::new(review).in_state(MergeState::Merged)),
+            Err((status, message)) => {
+                let reason = refusal(status, &message).reason(&message, options);
+                Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)))
+            }
+        }
+    }
+
+    async fn review(&self, id: &str) -> Result<Option<Review>> {
+        let pull = self.pull(id.parse::<u64>()?).await?;
+        Ok(Some(self.convert_to_review(pull).await?))
+    }
+
+    async fn reviews(&self) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls("open").await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls_from("open", branch).await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for pull in self.pulls_from("all", branch).await? {
+            reviews.push(self.convert_to_review(pull).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
+        println!("Creating review for {} [on {}] at {}/{}", branch.cyan(), parent.black(), self.owner.green(), self.repo.blue());
+        println!("PR title: {}", title.bold());
+        let pull: GiteaPull = self.client.post(self.route("/pulls"), Some(&json!({
+            "head": branch,
+            "base": parent,
+            "title": title,
+            "body": body,
+        }))).await?;
+
+        self.convert_to_review(pull).await
+    }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        let pull: GiteaPull = self.client
+            .patch(self.route(&format!("/pulls/{}", review.id)), Some(&json!({ "base": base })))
+            .await?;
+
+        self.convert_to_review(pull).await
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        let _: serde_json::Value = self.client
+            .post(self.route(&format!("/issues/{}/comments", review.id)), Some(&json!({ "body": comment })))
+            .await?;
+        let _: serde_json::Value = self.client
+            .patch(self.route(&format!("/pulls/{}", review.id)), Some(&json!({ "state": "closed" })))
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_auto_merge(&self, review: &Review, options: &MergeOptions) -> Result<bool> {
+        // Scheduled merges need Gitea 1.17+ - older servers, or repos which don't allow them, refuse
+        Ok(self.request_merge(review, options, true).await?.is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{param, refused_merge, reviews_for};
+    use crate::mock_server::MockServer;
+    use crate::ReviewState;
+
+    const API: &str = "/api/v1/repos/owner/repo";
+
+    fn pull_json(number: u64, branch: &str, base: &str, state: &str, merged: bool) -> String {
+        json!({
+            "number": number,
+            "title": format!("Change {}", number),
+            "body": "Body",
+            "state": state,
+            "merged": merged,
+            "mergeable": true,
+            "html_url": format!("https://gitea.example.com/owner/repo/pulls/{}", number),
+            "head": { "ref": branch, "sha": format!("{}-sha", branch) },
+            "base": { "ref": base, "sha": format!("{}-sha", base) },
+            "requested_reviewers": [{ "login": "carol" }],
+        }).to_string()
+    }
+
+    fn reviewer(server: &MockServer) -> GiteaReviewer {
+        GiteaReviewer::new(server.url(), "owner", "repo", Some("secret".to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_review_state_from_statuses_and_reviews() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pulls/7", API), 200, &pull_json(7, "feature", "main", "open", false));
+        server.on("GET", &format!("{}/commits/feature-sha/status", API), 200,
+                  r#"{"state":"success","statuses":[{"context":"ci/build","status":"success"}]}"#);
+        server.on("GET", &format!("{}/pulls/7/reviews", API), 200,
+                  r#"[{"user":{"login":"alice"},"state":"REQUEST_CHANGES"},{"user":{"login":"alice"},"state":"APPROVED"},
+                      {"user":{"login":"bob"},"state":"COMMENT"}]"#);
+        server.on("GET", &format!("{}/branch_protections/main", API), 200, r#"{"required_approvals":1}"#);
+
+        let review = reviewer(&server).review("7").await.unwrap().unwrap();
+
+        assert_eq!(review.branch, "feature");
+        assert_eq!(review.base, "main");
+        assert_eq!(review.approvals, vec!["alice"]);
+        assert_eq!(review.reviewers, vec!["alice", "carol"]);
+        assert_eq!(review.tests.iter().map(|t| t.name.as_str()).collect::<Vec<&str>>(), vec!["ci/build", "1/1 approvals"]);
+        assert!(matches!(review.state, ReviewState::Approved));
+        assert!(matches!(review.service, CodeReviewService::Gitea { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_reviews_for_filters_by_branch() {
+        let server = MockServer::start().await;
+        let pulls = format!("[{},{}]", pull_json(1, "a", "main", "open", false), pull_json(2, "b", "a", "open", false));
+        server.on("GET", &format!("{}/pulls", API), 200, &pulls);
+
+        let (reviews, query) = reviews_for(&reviewer(&server), &server, "b", &format!("{}/pulls", API)).await;
+
+        assert_eq!(reviews.iter().map(|r| r.id.as_str()).collect::<Vec<&str>>(), vec!["2"]);
+        // No branch protection on a - no approvals needed, and no statuses reported
+        assert!(matches!(reviews[0].state, ReviewState::Approved));
+        assert_eq!(param(&query, "state"), Some("open"));
+    }
+
+    #[tokio::test]
+    async fn test_reviews_for_stops_paging_at_the_branch() {
+        let server = MockServer::start().await;
+        let mut pulls = (1..PAGE_SIZE as u64).map(|n| pull_json(n, &format!("other-{}", n), "main", "open", false)).collect::<Vec<String>>();
+        pulls.push(pull_json(99, "b", "main", "open", false));
+        // A full page - so there may be more
+        server.on("GET", &format!("{}/pulls", API), 200, &format!("[{}]", pulls.join(",")));
+
+        let (reviews, query) = reviews_for(&reviewer(&server), &server, "b", &format!("{}/pulls", API)).await;
+
+        assert_eq!(reviews.iter().map(|r| r.id.as_str()).collect::<Vec<&str>>(), vec!["99"]);
+        assert_eq!(server.requests_to("GET", &format!("{}/pulls", API)).len(), 1);
+        assert_eq!(param(&query, "sort"), Some("recentupdate"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_mergeability_is_not_a_conflict() {
+        let server = MockServer::start().await;
+        let mut pull: serde_json::Value = serde_json::from_str(&pull_json(8, "feature", "main", "open", false)).unwrap();
+        pull.as_object_mut().unwrap().remove("mergeable");
+        server.on("GET", &format!("{}/pulls/8", API), 200, &pull.to_string());
+
+        let review = reviewer(&server).review("8").await.unwrap().unwrap();
+
+        assert!(matches!(review.state, ReviewState::Approved));
+    }
+
+    #[tokio::test]
+    async fn test_create_review() {
+        let server = MockServer::start().await;
+        server.on("POST", &format!("{}/pulls", API), 201, &pull_json(3, "feature", "main", "open", false));
+
+        let review = reviewer(&server).create_review("feature", "main", "Change 3", "Body").await.unwrap();
+
+        assert_eq!(review.id, "3");
+        let sent = server.sent_json("POST", &format!("{}/pulls", API));
+        assert_eq!(sent["head"], "feature");
+        assert_eq!(sent["base"], "main");
+        assert_eq!(sent["title"], "Change 3");
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pulls/4", API), 200, &pull_json(4, "feature", "main", "open", false));
+        server.on("POST", &format!("{}/pulls/4/merge", API), 200, "");
+
+        let gitea = reviewer(&server);
+        let review = gitea.review("4").await.unwrap().unwrap();
+        let options = MergeOptions { method: MergeMethod::Rebase, expected_head: Some("feature-sha".to_string()) };
+        let merged = gitea.merge(&review, &options).await.unwrap();
+
+        assert!(matches!(merged.state, MergeState::Merged));
+        let sent = server.sent_json("POST", &format!("{}/pulls/4/merge", API));
+        assert_eq!(sent["Do"], "rebase");
+        assert_eq!(sent["head_commit_id"], "feature-sha");
+        assert_eq!(sent["MergeTitleField"], "Change 4 (#4)");
+    }
+
+    #[tokio::test]
+    async fn test_merge_refused() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pulls/5", API), 200, &pull_json(5, "feature", "main", "open", false));
+        server.on("POST", &format!("{}/pulls/5/merge", API), 409, r#"{"message":"head out of date"}"#);
+
+        let options = MergeOptions { method: MergeMethod::Squash, expected_head: Some("0123456789".to_string()) };
+        let reason = refused_merge(&reviewer(&server), "5", &options).await;
+
+        assert!(reason.contains("head is no longer 0123456"));
+    }
+}
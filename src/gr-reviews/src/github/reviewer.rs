@@ -1,42 +1,71 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use colored::Colorize;
-use crate::{CodeReviewService, MergeRequest, Review, ReviewService, ReviewState};
+use crate::credentials::{client, Credentials};
+use crate::{Backoff, CodeReviewService, MergeMethod, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewTest, ReviewTestState};
+use crate::merge_requests::Refusal;
+use crate::verdicts::{state_of_review, ReviewStatus, Verdicts};
 use octocrab;
-use octocrab::models::{IssueState};
+use octocrab::models::{IssueState, Status, StatusState};
 use octocrab::models::checks::CheckRun;
-use octocrab::models::pulls::PullRequest;
+use octocrab::models::pulls::{PullRequest, ReviewState as PullReviewState};
 use octocrab::Octocrab;
-use octocrab::params::repos::Commitish;
+use octocrab::params::repos::{Commitish, Reference};
+use octocrab::params::State;
 
 pub struct GithubReviewer {
     client: Octocrab,
+    /// GraphQL lives outside the REST API's base path on GitHub Enterprise
+    graphql: Octocrab,
+    /// github.com or the Enterprise server these reviews live on
+    service: CodeReviewService,
     owner: String,
     repo: String
 }
 
 struct PullRequestWithChecks {
     pull: PullRequest,
-    checks: Vec<CheckRun>
+    checks: Vec<CheckRun>,
+    /// Latest commit status per context - the older, pre-Actions way to report CI results
+    statuses: Vec<Status>,
+    verdicts: Verdicts,
+    /// Approvals the base branch's protection rules require
+    required_approvals: usize,
 }
 
-const BACKOFF_TIME_SECONDS : [u64; 10] = [1, 5, 5, 5, 5, 10, 10, 10, 30, 60];
-
 impl GithubReviewer {
-    pub fn new(gh_owner: &str, gh_repo: &str) -> GithubReviewer {
-        let token = std::env::var("GITHUB_TOKEN").expect("GITHUB_TOKEN env variable is required");
-        let client = Octocrab::builder().personal_token(token).build().unwrap();
+    pub fn new(gh_owner: &str, gh_repo: &str, credentials: Credentials) -> Result<GithubReviewer> {
+        let client = client(None, Some(credentials))?;
 
-        GithubReviewer { client,
+        Ok(GithubReviewer {
+            graphql: client.clone(),
+            client,
+            service: CodeReviewService::Github,
             owner: gh_owner.to_string(),
             repo: gh_repo.to_string()
-        }
+        })
+    }
+
+    /// A repo on a GitHub Enterprise Server. `api_url` is the REST API's base, <host>/api/v3 by default.
+    pub fn enterprise(host: &str, api_url: Option<&str>, gh_owner: &str, gh_repo: &str, credentials: Credentials) -> Result<GithubReviewer> {
+        let host = host.trim_end_matches('/');
+        let api_url = api_url.map(|u| u.trim_end_matches('/').to_string()).unwrap_or(format!("{}/api/v3", host));
+        // GraphQL is served from <host>/api/graphql, next to the versioned REST API
+        let graphql_url = api_url.strip_suffix("/v3").unwrap_or(&api_url).to_string();
+
+        Ok(GithubReviewer {
+            client: client(Some(&api_url), Some(credentials.clone()))?,
+            graphql: client(Some(&graphql_url), Some(credentials))?,
+            service: CodeReviewService::GithubEnterprise { host: host.to_string(), api_url: Some(api_url) },
+            owner: gh_owner.to_string(),
+            repo: gh_repo.to_string()
+        })
     }
 
     async fn await_mergability(&self, pull: &PullRequest) -> Result<PullRequest> {
         // Check whether the review can be merged
         let mut pull = pull.clone();
-        let mut backoff_time_idx: usize = 0;
+        let mut backoff = Backoff::new();
 
         // It can take minutes for the mergeability to be determined
         for _ in 0..15 {
@@ -48,13 +77,11 @@ impl GithubReviewer {
 
             if pull.mergeable.is_some() { break; }
 
-            let backoff_time = BACKOFF_TIME_SECONDS[backoff_time_idx];
-            backoff_time_idx = (backoff_time_idx + 1) % BACKOFF_TIME_SECONDS.len();
-            tokio::time::sleep(std::time::Duration::from_secs(backoff_time)).await;
+            backoff.wait().await;
 
             pull = self.client.pulls(&self.owner, &self.repo)
                 .get(pull.number)
-                .await.unwrap();
+                .await?;
         }
 
         if pull.mergeable.is_some() { return Ok(pull); }
@@ -71,27 +98,89 @@ impl GithubReviewer {
             Err(_) => Vec::new()
         };
 
+        let statuses = match self.client.repos(&self.owner, &self.repo)
+            .combined_status_for_ref(&Reference::Commit(pull.head.sha.clone())).await {
+            Ok(combined) => combined.statuses,
+            Err(_) => Vec::new()
+        };
 
-        Ok(PullRequestWithChecks { pull: pull.clone(), checks: check_runs })
+        Ok(PullRequestWithChecks {
+            pull: pull.clone(),
+            checks: check_runs,
+            statuses,
+            verdicts: self.verdicts(pull).await,
+            required_approvals: self.required_approvals(&pull.base.ref_field).await,
+        })
+    }
+
+    async fn verdicts(&self, pull: &PullRequest) -> Verdicts {
+        let mut verdicts = Verdicts::default();
+        let reviews = match self.client.pulls(&self.owner, &self.repo).list_reviews(pull.number).per_page(100).send().await {
+            Ok(page) => self.client.all_pages(page).await.unwrap_or_default(),
+            Err(_) => return verdicts,
+        };
+
+        // Reviews come oldest first - only each reviewer's latest verdict counts
+        for review in reviews {
+            let (Some(user), Some(state)) = (review.user, review.state) else { continue };
+            match state {
+                PullReviewState::Approved => verdicts.approve(user.login),
+                PullReviewState::ChangesRequested => verdicts.request_changes(user.login),
+                PullReviewState::Dismissed => verdicts.dismiss(&user.login),
+                _ => {},  // comments don't change a verdict
+            }
+        }
+        verdicts
+    }
+
+    async fn required_approvals(&self, base: &str) -> usize {
+        // Reading protection needs admin rights - without them, GitHub answers 403 or a plain 404,
+        // so assume one approval. Only a "Branch not protected" 404 means there's nothing to wait for.
+        let route = format!("/repos/{}/{}/branches/{}/protection/required_pull_request_reviews", self.owner, self.repo, base);
+        match self.client.get::<serde_json::Value, _, ()>(route, None).await {
+            Ok(rules) => rules["required_approving_review_count"].as_u64().unwrap_or(1) as usize,
+            Err(octocrab::Error::GitHub { source, .. })
+                if source.status_code.as_u16() == 404 && source.message.eq_ignore_ascii_case("Branch not protected") => 0,
+            Err(_) => 1,
+        }
+    }
+
+    /// Pull requests from `branch` in `state` - GitHub filters them, so only those get converted
+    async fn reviews_in(&self, state: State, branch: &str) -> Result<Vec<Review>> {
+        let page = self.client
+            .pulls(&self.owner, &self.repo)
+            .list()
+            .state(state)
+            .head(format!("{}:{}", self.owner, branch))
+            .per_page(100)
+            .send().await?;
+
+        let mut reviews = Vec::new();
+        for pr in self.client.all_pages(page).await? {
+            reviews.push(self.convert_to_review(pr).await?);
+        }
+        Ok(reviews)
     }
 
     async fn convert_to_review(&self, pull: PullRequest) -> Result<Review> {
         let pull = self.await_mergability(&pull).await?;
         let prc = self.with_check_runs(&pull).await?;
 
-        Ok(Review::from(prc))
+        Ok(Review { service: self.service.clone(), ..Review::from(prc) })
     }
 }
 
 impl From<PullRequestWithChecks> for Review {
     fn from(prc: PullRequestWithChecks) -> Self {
-        let review_state = state_of_review(&prc);
+        let tests = review_tests(&prc);
+        let review_state = state_of_review(&status_of(&prc), &tests);
         Review {
             // Number monotonically increases per repo - we want this as our ID
             id: prc.pull.number.to_string(),
             // Branch names
-            branch: prc.pull.head.label.unwrap().split(":").last().unwrap().to_owned(),
-            base: prc.pull.base.label.unwrap().split(":").last().unwrap().to_owned(),
+            branch: prc.pull.head.label.clone().unwrap().split(":").last().unwrap().to_owned(),
+            base: prc.pull.base.label.clone().unwrap().split(":").last().unwrap().to_owned(),
+            head: Some(prc.pull.head.sha.clone()),
 
             // Title and body
             title: prc.pull.title.clone().unwrap_or(String::new()).to_owned(),
@@ -101,55 +190,105 @@ impl From<PullRequestWithChecks> for Review {
             url: prc.pull.html_url.clone(),
 
             // What's its state?
-            reviewers: Vec::new(),
+            reviewers: prc.verdicts.reviewers(prc.pull.requested_reviewers.iter().flatten().map(|a| a.login.clone())),
+            approvals: prc.verdicts.approvals(),
             state: review_state,
-            tests: Vec::new()
+            tests
         }
     }
 
 }
 
-fn state_of_review(prc: &PullRequestWithChecks) -> ReviewState {
-    let review = prc.pull.clone();
+/// One test per check run and commit status, plus one for the required approvals
+fn review_tests(prc: &PullRequestWithChecks) -> Vec<ReviewTest> {
+    let checks = prc.checks.iter()
+        .map(|c| ReviewTest { name: c.name.clone(), state: check_run_state(c.conclusion.as_deref()) });
+    let statuses = prc.statuses.iter()
+        .map(|s| ReviewTest { name: s.context.clone().unwrap_or("status".to_string()), state: status_state(&s.state) });
 
-    if review.draft.unwrap() { return ReviewState::Pending; } // not ready yet!
-    if review.merged_at.is_some() { return ReviewState::Merged; }  // This is already merged!
-    if review.state == Some(IssueState::Closed) { return ReviewState::Closed; } // this was closed!
+    checks.chain(statuses).chain(std::iter::once(prc.verdicts.approval_test(prc.required_approvals))).collect()
+}
 
+/// Check runs without a conclusion are still running
+fn check_run_state(conclusion: Option<&str>) -> ReviewTestState {
+    match conclusion {
+        None => ReviewTestState::Pending,
+        Some("success") | Some("neutral") | Some("skipped") => ReviewTestState::Passed,
+        // failure, cancelled, timed_out, action_required, stale...
+        Some(_) => ReviewTestState::Failed,
+    }
+}
+
+fn status_state(state: &StatusState) -> ReviewTestState {
+    match state {
+        StatusState::Success => ReviewTestState::Passed,
+        StatusState::Pending => ReviewTestState::Pending,
+        _ => ReviewTestState::Failed,
+    }
+}
 
-    // Conflicts need to be resolved
-    if review.mergeable.unwrap() == false { return ReviewState::Conflicted; }
+fn status_of(prc: &PullRequestWithChecks) -> ReviewStatus {
+    let pull = &prc.pull;
+    ReviewStatus {
+        merged: pull.merged_at.is_some(),
+        closed: pull.state == Some(IssueState::Closed),
+        draft: pull.draft.unwrap_or(false),
+        conflicted: pull.mergeable == Some(false),
+        rejected: prc.verdicts.changes_requested(),
+        ready: None,
+    }
+}
 
-    // See if the PR's checks have passed
-    // let check_states = *prc.checks
-    //     .iter()
-    //     .map(|check| { check.conclusion.unwrap_or(String::new()).to_owned().to_lowercase() })
-    //     .filter(|c| *c != "")
-    //     .collect::<Vec<String>>()
-    //     .iter()
-    //     .unique()
-    //     .collect::<Vec<&String>>();
+fn github_merge_method(method: MergeMethod) -> octocrab::params::pulls::MergeMethod {
+    match method {
+        MergeMethod::Squash => octocrab::params::pulls::MergeMethod::Squash,
+        MergeMethod::Rebase => octocrab::params::pulls::MergeMethod::Rebase,
+        MergeMethod::Merge => octocrab::params::pulls::MergeMethod::Merge,
+    }
+}
 
-    ReviewState::Pending
+/// Reads why GitHub's merge endpoint refused to merge a pull request
+fn refusal(status: u16, message: &str) -> Refusal {
+    match status {
+        // Not mergeable: disallowed merge method, failing required checks, branch protection...
+        405 if message.to_lowercase().contains("not allowed") => Refusal::MethodDisabled,
+        405 => Refusal::NotMergeable,
+        409 => Refusal::HeadMoved,
+        403 => Refusal::NotPermitted,
+        _ => Refusal::Other(status),
+    }
 }
 
 #[async_trait]
 impl ReviewService for GithubReviewer {
-    async fn merge(&self, review: &Review) -> Result<MergeRequest> {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        let number = review.id.parse::<u64>()?;
         let pull = self.client
             .pulls(&self.owner, &self.repo)
-            .get(review.id.parse::<u64>()?)
+            .get(number)
             .await?;
 
         let review = self.convert_to_review(pull).await?;
-        let msg = format!("{}\n\n{}", review.title, review.body);
-
-        self.client
-            .repos(&self.owner, &self.repo)
-            .merge(&review.branch, &review.base)
-            .commit_message(&msg)
-            .send().await?;
-        Ok(MergeRequest::new(review.clone()))
+        let pulls = self.client.pulls(&self.owner, &self.repo);
+        let mut request = pulls
+            .merge(number)
+            .method(github_merge_method(options.method))
+            .title(format!("{} (#{})", review.title, review.id))
+            .message(review.body.clone());
+        if let Some(sha) = &options.expected_head { request = request.sha(sha); }
+
+        match request.send().await {
+            Ok(res) if res.merged => Ok(MergeRequest::new(review).in_state(MergeState::Merged)),
+            Ok(res) => {
+                let reason = res.message.unwrap_or("GitHub did not merge the pull request".to_string());
+                Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)))
+            },
+            Err(octocrab::Error::GitHub { source, .. }) => {
+                let reason = refusal(source.status_code.as_u16(), &source.message).reason(&source.message, options);
+                Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)))
+            },
+            Err(e) => Err(e.into()),
+        }
     }
 
     async fn review(&self, id: &str) -> Result<Option<Review>> {
@@ -178,11 +317,11 @@ impl ReviewService for GithubReviewer {
     }
 
     async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
-        Ok(self.reviews().await?
-            .into_iter()
-            .filter(|review| review.branch == branch)
-            .map(|review| review.clone())
-            .collect())
+        self.reviews_in(State::Open, branch).await
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        self.reviews_in(State::All, branch).await
     }
 
     async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
@@ -199,4 +338,140 @@ impl ReviewService for GithubReviewer {
 
         Ok(review)
     }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        let pull = self.client
+            .pulls(&self.owner, &self.repo)
+            .update(review.id.parse::<u64>()?)
+            .base(base)
+            .send()
+            .await?;
+
+        self.convert_to_review(pull).await
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        let number = review.id.parse::<u64>()?;
+        self.client
+            .issues(&self.owner, &self.repo)
+            .create_comment(number, comment)
+            .await?;
+        self.client
+            .pulls(&self.owner, &self.repo)
+            .update(number)
+            .state(octocrab::params::pulls::State::Closed)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_auto_merge(&self, review: &Review, options: &MergeOptions) -> Result<bool> {
+        let pull = self.client
+            .pulls(&self.owner, &self.repo)
+            .get(review.id.parse::<u64>()?)
+            .await?;
+        let Some(node_id) = pull.node_id else { return Ok(false) };
+
+        // Auto-merge is only exposed through GraphQL
+        let query = "mutation($id: ID!, $method: PullRequestMergeMethod!, $sha: GitObjectID) {
+            enablePullRequestAutoMerge(input: {pullRequestId: $id, mergeMethod: $method, expectedHeadOid: $sha}) {
+                clientMutationId
+            }
+        }";
+        let method = match options.method {
+            MergeMethod::Squash => "SQUASH",
+            MergeMethod::Rebase => "REBASE",
+            MergeMethod::Merge => "MERGE",
+        };
+        let response: serde_json::Value = self.graphql.graphql(&serde_json::json!({
+            "query": query,
+            "variables": { "id": node_id, "method": method, "sha": options.expected_head },
+        })).await?;
+
+        // e.g. auto-merge is disabled for the repo, or the PR is already mergeable
+        Ok(response.get("errors").is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::refused_merge;
+    use crate::mock_server::MockServer;
+
+    const API: &str = "/repos/owner/repo";
+
+    fn reviewer(server: &MockServer) -> GithubReviewer {
+        GithubReviewer::enterprise(server.url(), Some(server.url()), "owner", "repo", Credentials::Token("secret".to_string())).unwrap()
+    }
+
+    fn pull_json(number: u64, branch: &str, base: &str) -> String {
+        serde_json::json!({
+            "url": format!("https://github.example.com/api/v3/repos/owner/repo/pulls/{}", number),
+            "id": number,
+            "number": number,
+            "state": "open",
+            "title": format!("Change {}", number),
+            "body": "Does things",
+            "mergeable": true,
+            "head": { "ref": branch, "label": format!("owner:{}", branch), "sha": format!("{}-sha", branch) },
+            "base": { "ref": base, "label": format!("owner:{}", base), "sha": format!("{}-sha", base) },
+        }).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pulls/4", API), 200, &pull_json(4, "feature", "main"));
+        server.on("PUT", &format!("{}/pulls/4/merge", API), 200, r#"{"sha":"merged-sha","merged":true,"message":"Pull Request successfully merged"}"#);
+
+        let github = reviewer(&server);
+        let review = github.review("4").await.unwrap().unwrap();
+        let options = MergeOptions { method: MergeMethod::Rebase, expected_head: Some("feature-sha".to_string()) };
+        let merged = github.merge(&review, &options).await.unwrap();
+
+        assert!(matches!(merged.state, MergeState::Merged));
+        let sent = server.sent_json("PUT", &format!("{}/pulls/4/merge", API));
+        assert_eq!(sent["merge_method"], "rebase");
+        assert_eq!(sent["sha"], "feature-sha");
+        assert_eq!(sent["commit_title"], "Change 4 (#4)");
+    }
+
+    #[tokio::test]
+    async fn test_merge_refused() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/pulls/5", API), 200, &pull_json(5, "feature", "main"));
+        server.on("PUT", &format!("{}/pulls/5/merge", API), 405, r#"{"message":"Squash merges are not allowed on this repository."}"#);
+        server.on("GET", &format!("{}/pulls/6", API), 200, &pull_json(6, "other", "main"));
+        server.on("PUT", &format!("{}/pulls/6/merge", API), 409, r#"{"message":"Head branch was modified. Review and try the merge again."}"#);
+
+        let github = reviewer(&server);
+        let options = MergeOptions { method: MergeMethod::Squash, expected_head: Some("0123456789".to_string()) };
+        assert!(refused_merge(&github, "5", &options).await.contains("merges are disabled for this repo"));
+        assert!(refused_merge(&github, "6", &options).await.contains("head is no longer 0123456"));
+    }
+
+    #[tokio::test]
+    async fn test_required_approvals() {
+        let server = MockServer::start().await;
+        server.on("GET", "/repos/owner/repo/branches/main/protection/required_pull_request_reviews", 200, r#"{"required_approving_review_count":2}"#);
+        server.on("GET", "/repos/owner/repo/branches/locked/protection/required_pull_request_reviews", 403, r#"{"message":"Must have admin rights"}"#);
+        server.on("GET", "/repos/owner/repo/branches/unprotected/protection/required_pull_request_reviews", 404, r#"{"message":"Branch not protected"}"#);
+
+        let github = reviewer(&server);
+        assert_eq!(github.required_approvals("main").await, 2);
+        // Nothing to wait for
+        assert_eq!(github.required_approvals("unprotected").await, 0);
+        assert_eq!(github.required_approvals("locked").await, 1);
+        // What non-admins get for protected branches
+        assert_eq!(github.required_approvals("hidden").await, 1);
+    }
+
+    #[test]
+    fn test_check_run_state() {
+        assert!(matches!(check_run_state(None), ReviewTestState::Pending));
+        assert!(matches!(check_run_state(Some("success")), ReviewTestState::Passed));
+        assert!(matches!(check_run_state(Some("skipped")), ReviewTestState::Passed));
+        assert!(matches!(check_run_state(Some("timed_out")), ReviewTestState::Failed));
+    }
 }
@@ -0,0 +1,2 @@
+mod reviewer;
+pub use reviewer::GitlabReviewer;
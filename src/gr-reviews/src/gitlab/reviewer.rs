@@ -0,0 +1,468 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use colored::Colorize;
+use octocrab::Octocrab;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use crate::{CodeReviewService, MergeMethod, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewTest, ReviewTestState};
+use crate::merge_requests::Refusal;
+use crate::rest::{send, Method};
+use crate::verdicts::{state_of_review, ReviewStatus, Verdicts};
+
+/// Merge requests fetched per page - GitLab's maximum
+const PAGE_SIZE: usize = 100;
+
+/// Reviews merge requests on gitlab.com or a self-hosted GitLab instance.
+pub struct GitlabReviewer {
+    client: Octocrab,
+    /// The instance as configured, e.g. https://gitlab.com
+    host: String,
+    /// Full project path, e.g. group/subgroup/project
+    project: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GitlabUser {
+    username: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct GitlabMergeRequest {
+    /// Number of the merge request within its project
+    iid: u64,
+    title: String,
+    description: Option<String>,
+    /// opened, closed, locked or merged
+    state: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    has_conflicts: bool,
+    /// mergeable, conflict, checking, ci_must_pass, not_approved... - newer instances only
+    detailed_merge_status: Option<String>,
+    web_url: Option<Url>,
+    source_branch: String,
+    target_branch: String,
+    /// The source branch's head commit
+    sha: Option<String>,
+    #[serde(default)]
+    reviewers: Vec<GitlabUser>,
+    #[serde(default)]
+    merge_when_pipeline_succeeds: bool,
+}
+
+#[derive(Deserialize)]
+struct GitlabApprover {
+    user: GitlabUser,
+}
+
+#[derive(Deserialize)]
+struct GitlabApprovals {
+    /// Only reported where approval rules exist (Premium and up)
+    approvals_required: Option<usize>,
+    #[serde(default)]
+    approved_by: Vec<GitlabApprover>,
+}
+
+#[derive(Deserialize)]
+struct GitlabReviewerState {
+    user: GitlabUser,
+    /// unreviewed, reviewed, requested_changes or approved
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabPipeline {
+    id: u64,
+    /// created, pending, running, success, failed, canceled, skipped, manual...
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabJob {
+    name: String,
+    status: String,
+    /// Failures of these jobs don't fail the pipeline
+    #[serde(default)]
+    allow_failure: bool,
+}
+
+struct MergeRequestWithChecks {
+    mr: GitlabMergeRequest,
+    /// The latest pipeline run for the merge request, if any
+    pipeline: Option<GitlabPipeline>,
+    /// The jobs of that pipeline
+    jobs: Vec<GitlabJob>,
+    verdicts: Verdicts,
+    /// Approvals the project's rules require
+    required_approvals: usize,
+}
+
+impl GitlabReviewer {
+    /// `host` is the instance's web address - the API lives under /api/v4
+    pub fn new(host: &str, project: &str, token: Option<String>) -> Result<GitlabReviewer> {
+        let host = host.trim_end_matches('/').to_string();
+        let mut builder = Octocrab::builder().base_uri(format!("{}/api/v4", host))?;
+        if let Some(token) = token { builder = builder.personal_token(token); }
+
+        Ok(GitlabReviewer {
+            client: builder.build()?,
+            host,
+            project: project.to_string(),
+        })
+    }
+
+    fn route(&self, path: &str) -> String {
+        // Projects are addressed by their url-encoded path
+        format!("/projects/{}{}", self.project.replace('/', "%2F"), path)
+    }
+
+    async fn merge_request(&self, iid: &str) -> Result<GitlabMergeRequest> {
+        Ok(self.client.get(self.route(&format!("/merge_requests/{}", iid)), None::<&()>).await?)
+    }
+
+    /// Merge requests in `state` (opened, closed, merged or all), optionally only those from `branch`
+    async fn merge_requests(&self, state: &str, branch: Option<&str>) -> Result<Vec<GitlabMergeRequest>> {
+        let mut mrs = Vec::new();
+        for page in 1.. {
+            let mut params = vec![("state", state.to_string()), ("page", page.to_string()), ("per_page", PAGE_SIZE.to_string())];
+            if let Some(branch) = branch { params.push(("source_branch", branch.to_string())); }
+
+            let batch: Vec<GitlabMergeRequest> = self.client.get(self.route("/merge_requests"), Some(&params)).await?;
+            let done = batch.len() < PAGE_SIZE;
+            mrs.extend(batch);
+            if done { break; }
+        }
+        Ok(mrs)
+    }
+
+    async fn with_checks(&self, mr: GitlabMergeRequest) -> Result<MergeRequestWithChecks> {
+        let (verdicts, required_approvals) = self.verdicts(mr.iid).await;
+        let pipeline = self.client
+            .get::<Vec<GitlabPipeline>, _, ()>(self.route(&format!("/merge_requests/{}/pipelines", mr.iid)), None).await
+            .ok()
+            .and_then(|pipelines| pipelines.into_iter().next());  // newest first
+        let jobs = match &pipeline {
+            Some(p) => self.client
+                .get::<Vec<GitlabJob>, _, _>(self.route(&format!("/pipelines/{}/jobs", p.id)), Some(&[("per_page", PAGE_SIZE)])).await
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        Ok(MergeRequestWithChecks { mr, pipeline, jobs, verdicts, required_approvals })
+    }
+
+    /// Reviewer verdicts, and how many approvals are required
+    async fn verdicts(&self, iid: u64) -> (Verdicts, usize) {
+        let mut verdicts = Verdicts::default();
+
+        // Requesting changes needs GitLab 17 - older instances only know approvals
+        if let Ok(reviewers) = self.client
+            .get::<Vec<GitlabReviewerState>, _, ()>(self.route(&format!("/merge_requests/{}/reviewers", iid)), None).await {
+            for r in reviewers.into_iter().filter(|r| r.state == "requested_changes") {
+                verdicts.request_changes(r.user.username);
+            }
+        }
+
+        // Without approval rules to go by, assume one approval
+        let mut required = 1;
+        if let Ok(approvals) = self.client
+            .get::<GitlabApprovals, _, ()>(self.route(&format!("/merge_requests/{}/approvals", iid)), None).await {
+            required = approvals.approvals_required.unwrap_or(required);
+            for a in approvals.approved_by {
+                verdicts.approve(a.user.username);
+            }
+        }
+        (verdicts, required)
+    }
+
+    async fn convert_to_review(&self, mr: GitlabMergeRequest) -> Result<Review> {
+        let mrc = self.with_checks(mr).await?;
+        let tests = review_tests(&mrc);
+        let state = state_of_review(&status_of(&mrc), &tests);
+
+        Ok(Review {
+            id: mrc.mr.iid.to_string(),
+            branch: mrc.mr.source_branch.clone(),
+            base: mrc.mr.target_branch.clone(),
+            head: mrc.mr.sha.clone(),
+            title: mrc.mr.title.clone(),
+            body: mrc.mr.description.clone().unwrap_or_default(),
+            service: CodeReviewService::Gitlab { host: self.host.clone() },
+            url: mrc.mr.web_url.clone(),
+            reviewers: mrc.verdicts.reviewers(mrc.mr.reviewers.iter().map(|u| u.username.clone())),
+            approvals: mrc.verdicts.approvals(),
+            state,
+            tests,
+        })
+    }
+
+    /// Asks GitLab to merge `review` - now, or once its pipeline succeeds.
+    /// Err holds the status and message of a refusal.
+    async fn request_merge(&self, review: &Review, options: &MergeOptions, when_pipeline_succeeds: bool) -> Result<std::result::Result<GitlabMergeRequest, (u16, String)>> {
+        // Whether commits are rebased or merged is the project's merge method -
+        // per merge request, GitLab only lets us choose whether to squash them
+        let squash = options.method == MergeMethod::Squash;
+        let mut body = json!({
+            "squash": squash,
+            "merge_when_pipeline_succeeds": when_pipeline_succeeds,
+        });
+        if squash { body["squash_commit_message"] = json!(format!("{} (!{})\n\n{}", review.title, review.id, review.body)); }
+        if let Some(sha) = &options.expected_head { body["sha"] = json!(sha); }
+
+        match send(&self.client, Method::Put, &self.route(&format!("/merge_requests/{}/merge", review.id)), Some(&body)).await? {
+            (200..=299, text) => Ok(Ok(serde_json::from_str(&text)?)),
+            (status, text) => Ok(Err((status, error_message(&text)))),
+        }
+    }
+}
+
+/// GitLab reports errors as {"message": "..."}, {"message": {...}} or {"error": "..."}
+fn error_message(body: &str) -> String {
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(body) else { return body.to_string() };
+    match json.get("message").or(json.get("error")) {
+        Some(serde_json::Value::String(message)) => message.clone(),
+        Some(message) => message.to_string(),
+        None => body.to_string(),
+    }
+}
+
+/// One test per job of the latest pipeline - or the pipeline itself, when its jobs are unknown -
+/// plus one for the required approvals
+fn review_tests(mrc: &MergeRequestWithChecks) -> Vec<ReviewTest> {
+    let mut tests = mrc.jobs.iter()
+        .map(|j| ReviewTest { name: j.name.clone(), state: job_state(&j.status, j.allow_failure) })
+        .collect::<Vec<ReviewTest>>();
+    if let (Some(pipeline), true) = (&mrc.pipeline, tests.is_empty()) {
+        tests.push(ReviewTest { name: format!("pipeline #{}", pipeline.id), state: job_state(&pipeline.status, false) });
+    }
+
+    tests.push(mrc.verdicts.approval_test(mrc.required_approvals));
+    tests
+}
+
+fn job_state(status: &str, allow_failure: bool) -> ReviewTestState {
+    match status {
+        "success" | "skipped" => ReviewTestState::Passed,
+        "failed" | "canceled" if allow_failure => ReviewTestState::Passed,
+        "failed" | "canceled" => ReviewTestState::Failed,
+        // Manual jobs block the pipeline until someone runs them - unless they're optional
+        "manual" if allow_failure => ReviewTestState::Passed,
+        // created, pending, running, manual, scheduled...
+        _ => ReviewTestState::Pending,
+    }
+}
+
+fn status_of(mrc: &MergeRequestWithChecks) -> ReviewStatus {
+    let mr = &mrc.mr;
+    ReviewStatus {
+        merged: mr.state == "merged",
+        closed: mr.state == "closed" || mr.state == "locked",
+        draft: mr.draft,
+        conflicted: mr.has_conflicts || mr.detailed_merge_status.as_deref() == Some("conflict"),
+        rejected: mrc.verdicts.changes_requested(),
+        ready: None,
+    }
+}
+
+/// Reads why GitLab's merge endpoint refused to merge a merge request
+fn refusal(status: u16, message: &str) -> Refusal {
+    match status {
+        405 | 422 if message.to_lowercase().contains("squash") => Refusal::MethodDisabled,
+        // Draft, blocked by discussions or approvals, pipeline still running...
+        405 => Refusal::NotMergeable,
+        406 => Refusal::Conflicted,
+        409 => Refusal::HeadMoved,
+        401 | 403 => Refusal::NotPermitted,
+        _ => Refusal::Other(status),
+    }
+}
+
+#[async_trait]
+impl ReviewService for GitlabReviewer {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        let review = self.convert_to_review(self.merge_request(&review.id).await?).await?;
+
+        match self.request_merge(&review, options, false).await? {
+            Ok(mr) if mr.state == "merged" => Ok(MergeRequest::new(review).in_state(MergeState::Merged)),
+            Ok(mr) => Ok(MergeRequest::new(review).in_state(MergeState::Failed(format!("merge request is still {}", mr.state)))),
+            Err((status, message)) => {
+                let reason = refusal(status, &message).reason(&message, options);
+                Ok(MergeRequest::new(review).in_state(MergeState::Failed(reason)))
+            }
+        }
+    }
+
+    async fn review(&self, id: &str) -> Result<Option<Review>> {
+        let mr = self.merge_request(id).await?;
+        Ok(Some(self.convert_to_review(mr).await?))
+    }
+
+    async fn reviews(&self) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for mr in self.merge_requests("opened", None).await? {
+            reviews.push(self.convert_to_review(mr).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for mr in self.merge_requests("opened", Some(branch)).await? {
+            reviews.push(self.convert_to_review(mr).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let mut reviews = Vec::new();
+        for mr in self.merge_requests("all", Some(branch)).await? {
+            reviews.push(self.convert_to_review(mr).await?);
+        }
+        Ok(reviews)
+    }
+
+    async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
+        println!("Creating review for {} [on {}] at {}", branch.cyan(), parent.black(), self.project.green());
+        println!("MR title: {}", title.bold());
+        let mr: GitlabMergeRequest = self.client.post(self.route("/merge_requests"), Some(&json!({
+            "source_branch": branch,
+            "target_branch": parent,
+            "title": title,
+            "description": body,
+        }))).await?;
+
+        self.convert_to_review(mr).await
+    }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        let mr: GitlabMergeRequest = self.client
+            .put(self.route(&format!("/merge_requests/{}", review.id)), Some(&json!({ "target_branch": base })))
+            .await?;
+
+        self.convert_to_review(mr).await
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        let _: serde_json::Value = self.client
+            .post(self.route(&format!("/merge_requests/{}/notes", review.id)), Some(&json!({ "body": comment })))
+            .await?;
+        let _: serde_json::Value = self.client
+            .put(self.route(&format!("/merge_requests/{}", review.id)), Some(&json!({ "state_event": "close" })))
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_auto_merge(&self, review: &Review, options: &MergeOptions) -> Result<bool> {
+        // GitLab merges right away when the pipeline already passed
+        match self.request_merge(review, options, true).await? {
+            Ok(mr) => Ok(mr.merge_when_pipeline_succeeds || mr.state == "merged"),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{param, refused_merge, reviews_for};
+    use crate::mock_server::MockServer;
+    use crate::ReviewState;
+
+    const API: &str = "/api/v4/projects/group%2Fsub%2Frepo";
+
+    fn mr_json(iid: u64, branch: &str, base: &str, state: &str) -> serde_json::Value {
+        json!({
+            "iid": iid,
+            "title": format!("Change {}", iid),
+            "description": "Body",
+            "state": state,
+            "draft": false,
+            "has_conflicts": false,
+            "detailed_merge_status": "mergeable",
+            "web_url": format!("https://gitlab.example.com/group/sub/repo/-/merge_requests/{}", iid),
+            "source_branch": branch,
+            "target_branch": base,
+            "reviewers": [{ "username": "carol" }],
+        })
+    }
+
+    fn reviewer(server: &MockServer) -> GitlabReviewer {
+        GitlabReviewer::new(server.url(), "group/sub/repo", Some("secret".to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_review_state_from_pipeline_and_approvals() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/merge_requests/7", API), 200, &mr_json(7, "feature", "main", "opened").to_string());
+        server.on("GET", &format!("{}/merge_requests/7/pipelines", API), 200, r#"[{"id":12,"status":"running"},{"id":11,"status":"failed"}]"#);
+        server.on("GET", &format!("{}/pipelines/12/jobs", API), 200,
+                  r#"[{"name":"build","status":"success"},{"name":"lint","status":"failed","allow_failure":true},{"name":"test","status":"running"}]"#);
+        server.on("GET", &format!("{}/merge_requests/7/approvals", API), 200,
+                  r#"{"approvals_required":2,"approved_by":[{"user":{"username":"alice"}}]}"#);
+
+        let review = reviewer(&server).review("7").await.unwrap().unwrap();
+
+        assert_eq!(review.branch, "feature");
+        assert_eq!(review.base, "main");
+        assert_eq!(review.approvals, vec!["alice"]);
+        assert_eq!(review.reviewers, vec!["alice", "carol"]);
+        let tests = review.tests.iter().map(|t| (t.name.as_str(), matches!(t.state, ReviewTestState::Passed))).collect::<Vec<(&str, bool)>>();
+        assert_eq!(tests, vec![("build", true), ("lint", true), ("test", false), ("1/2 approvals", false)]);
+        assert!(matches!(review.state, ReviewState::Pending));
+    }
+
+    #[tokio::test]
+    async fn test_requested_changes_reject() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/merge_requests/8", API), 200, &mr_json(8, "feature", "main", "opened").to_string());
+        server.on("GET", &format!("{}/merge_requests/8/reviewers", API), 200,
+                  r#"[{"user":{"username":"bob"},"state":"requested_changes"},{"user":{"username":"carol"},"state":"unreviewed"}]"#);
+
+        let review = reviewer(&server).review("8").await.unwrap().unwrap();
+
+        assert!(matches!(review.state, ReviewState::Rejected));
+    }
+
+    #[tokio::test]
+    async fn test_reviews_for_filters_by_source_branch() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/merge_requests", API), 200, &json!([mr_json(2, "b", "a", "opened")]).to_string());
+
+        let (reviews, query) = reviews_for(&reviewer(&server), &server, "b", &format!("{}/merge_requests", API)).await;
+
+        assert_eq!(reviews.len(), 1);
+        assert_eq!(param(&query, "source_branch"), Some("b"));
+        assert_eq!(param(&query, "state"), Some("opened"));
+    }
+
+    #[tokio::test]
+    async fn test_squash_merge() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/merge_requests/4", API), 200, &mr_json(4, "feature", "main", "opened").to_string());
+        server.on("PUT", &format!("{}/merge_requests/4/merge", API), 200, &mr_json(4, "feature", "main", "merged").to_string());
+
+        let gitlab = reviewer(&server);
+        let review = gitlab.review("4").await.unwrap().unwrap();
+        let options = MergeOptions { method: MergeMethod::Squash, expected_head: Some("feature-sha".to_string()) };
+        let merged = gitlab.merge(&review, &options).await.unwrap();
+
+        assert!(matches!(merged.state, MergeState::Merged));
+        let sent = server.sent_json("PUT", &format!("{}/merge_requests/4/merge", API));
+        assert_eq!(sent["squash"], true);
+        assert_eq!(sent["sha"], "feature-sha");
+        assert!(sent["squash_commit_message"].as_str().unwrap().starts_with("Change 4 (!4)"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_refused() {
+        let server = MockServer::start().await;
+        server.on("GET", &format!("{}/merge_requests/5", API), 200, &mr_json(5, "feature", "main", "opened").to_string());
+        server.on("PUT", &format!("{}/merge_requests/5/merge", API), 406, r#"{"message":"Branch cannot be merged"}"#);
+
+        let reason = refused_merge(&reviewer(&server), "5", &MergeOptions::default()).await;
+
+        assert_eq!(reason, "conflicts with its base branch: Branch cannot be merged");
+    }
+}
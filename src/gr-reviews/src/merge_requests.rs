@@ -1,12 +1,66 @@
-use crate::{Review, ReviewState};
-use anyhow::Result;
+use std::fmt::{Display, Formatter};
+use crate::{Review, ReviewService, ReviewState};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
 /// Whether or not a given code review has been merged - but from the Merge Request's perspective
 #[derive(Clone)]
 pub enum MergeState {
     Pending,
     Merged,
-    Failed
+    /// Why the review couldn't be merged
+    Failed(String)
+}
+
+/// How a review's commits land on its base branch. Set via `merge_method` in the stk config.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMethod {
+    /// Combine the review's commits into a single commit
+    #[default]
+    Squash,
+    /// Replay the review's commits onto the base
+    Rebase,
+    /// Create a merge commit
+    Merge,
+}
+
+impl Display for MergeMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeMethod::Squash => write!(f, "squash"),
+            MergeMethod::Rebase => write!(f, "rebase"),
+            MergeMethod::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+/// In which order a stack of reviews lands on the root branch. Set via `merge_order` in the stk config.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeOrder {
+    /// Merge each review into the root in turn - one merge per review
+    #[default]
+    BottomUp,
+    /// Squash the stack down into its lowest review, then merge that once
+    TopDown,
+}
+
+impl Display for MergeOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeOrder::BottomUp => write!(f, "bottom-up"),
+            MergeOrder::TopDown => write!(f, "top-down"),
+        }
+    }
+}
+
+/// How to merge a review
+#[derive(Clone, Debug, Default)]
+pub struct MergeOptions {
+    pub method: MergeMethod,
+    /// Refuse to merge unless the review's head is still this commit
+    pub expected_head: Option<String>,
 }
 
 /// Tracks the state of a request to merge a Review
@@ -30,9 +84,10 @@ impl MergeRequest {
         self
     }
 
-    pub async fn refresh(&mut self) -> Result<()> {
-        // Pull the latest state of the review and use that to determine our own state
-        self.review.refresh().await?;
+    pub async fn refresh(&mut self, service: &dyn ReviewService) -> Result<()> {
+        // Pull the latest state of the review from `service` and use that to determine our own state
+        self.review = service.review(&self.review.id).await?
+            .ok_or(anyhow!("Review #{} has disappeared", self.review.id))?;
         self.update_state();
         Ok(())
     }
@@ -53,9 +108,45 @@ impl MergeRequest {
             ReviewState::Merged => MergeState::Merged,
 
             // Failed to merge the PR - or the PR is in a non-mergeable state.
-            ReviewState::Conflicted |
-            ReviewState::Closed |
-            ReviewState::Rejected => MergeState::Failed,
+            ReviewState::Conflicted => MergeState::Failed("conflicts with its base branch".to_string()),
+            ReviewState::Closed => MergeState::Failed("review was closed".to_string()),
+            ReviewState::Rejected => MergeState::Failed("changes were requested".to_string()),
         }
     }
 }
+
+/// Why a review service refused a merge. Each backend reads its service's response into one
+/// of these, so the reasons read the same whichever service refused.
+pub(crate) enum Refusal {
+    /// The configured merge method is turned off for the repo
+    MethodDisabled,
+    /// Failing checks, missing approvals, branch protection...
+    NotMergeable,
+    Conflicted,
+    /// The review's head moved since we last looked
+    HeadMoved,
+    NotPermitted,
+    Other(u16),
+}
+
+impl Refusal {
+    pub fn reason(&self, message: &str, options: &MergeOptions) -> String {
+        match self {
+            Refusal::MethodDisabled =>
+                format!("{} - '{}' merges are disabled for this repo, set merge_method in the stk config", message, options.method),
+            Refusal::NotMergeable => format!("not mergeable: {}", message),
+            Refusal::Conflicted => format!("conflicts with its base branch: {}", message),
+            Refusal::HeadMoved => match &options.expected_head {
+                Some(sha) => stale_head("head", sha),
+                None => format!("head branch was modified: {}", message),
+            },
+            Refusal::NotPermitted => format!("not permitted to merge: {}", message),
+            Refusal::Other(status) => format!("merge failed ({}): {}", status, message),
+        }
+    }
+}
+
+/// The review's `what` (head, latest patch set...) is no longer the `expected` commit
+pub(crate) fn stale_head(what: &str, expected: &str) -> String {
+    format!("{} is no longer {} - push your local branch (stk submit) and try again", what, &expected[..expected.len().min(7)])
+}
@@ -0,0 +1,115 @@
+/// A tiny HTTP server which answers with canned JSON, for testing review services
+/// without talking to the real thing.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request the server received
+#[derive(Clone, Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    /// Path and query, e.g. /api/v1/repos/o/r/pulls?state=open
+    pub path: String,
+    pub body: String,
+}
+
+/// (method, path without query) -> (status, body)
+type Routes = Arc<Mutex<HashMap<(String, String), (u16, String)>>>;
+
+pub(crate) struct MockServer {
+    url: String,
+    routes: Routes,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let routes: Routes = Arc::default();
+        let requests: Arc<Mutex<Vec<Request>>> = Arc::default();
+
+        let (r, q) = (routes.clone(), requests.clone());
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle(socket, r.clone(), q.clone()));
+            }
+        });
+
+        MockServer { url, routes, requests }
+    }
+
+    /// e.g. http://127.0.0.1:41234
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Answers `method path` with `status` and `body`. Unknown routes get a 404.
+    pub fn on(&self, method: &str, path: &str, status: u16, body: &str) {
+        self.routes.lock().unwrap().insert((method.to_string(), path.to_string()), (status, body.to_string()));
+    }
+
+    /// Everything received so far, oldest first
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Received requests for `method path` (ignoring any query)
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<Request> {
+        self.requests().into_iter()
+            .filter(|r| r.method == method && r.path.split('?').next() == Some(path))
+            .collect()
+    }
+
+    /// The JSON body of the first request to `method path`
+    pub fn sent_json(&self, method: &str, path: &str) -> serde_json::Value {
+        serde_json::from_str(&self.requests_to(method, path)[0].body).unwrap()
+    }
+
+    /// The decoded query of the first request to `method path`
+    pub fn query(&self, method: &str, path: &str) -> Vec<(String, String)> {
+        let request = &self.requests_to(method, path)[0];
+        let query = request.path.split_once('?').map(|(_, q)| q).unwrap_or_default();
+        url::form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+    }
+}
+
+async fn handle(mut socket: TcpStream, routes: Routes, requests: Arc<Mutex<Vec<Request>>>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    // Read the headers, then however much body they announce
+    let header_end = loop {
+        let Ok(n) = socket.read(&mut chunk).await else { return };
+        if n == 0 { return; }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") { break i + 4; }
+    };
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let content_length = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < header_end + content_length {
+        let Ok(n) = socket.read(&mut chunk).await else { return };
+        if n == 0 { break; }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+
+    let route = (method.clone(), path.split('?').next().unwrap_or_default().to_string());
+    let (status, response) = routes.lock().unwrap().get(&route).cloned()
+        .unwrap_or((404, r#"{"message":"not found"}"#.to_string()));
+    requests.lock().unwrap().push(Request { method, path, body });
+
+    let reply = format!("HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status, response.len(), response);
+    let _ = socket.write_all(reply.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
@@ -2,7 +2,7 @@ use std::hash::{DefaultHasher, Hash, Hasher};
 use anyhow::{Result};
 use async_trait::async_trait;
 use gr_git::Git;
-use crate::{CodeReviewService, MergeRequest, MergeState, Review, ReviewService, ReviewState};
+use crate::{CodeReviewService, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewState};
 
 pub struct NoneReviewer {}
 
@@ -15,27 +15,12 @@ impl NoneReviewer {
 
 #[async_trait]
 impl ReviewService for NoneReviewer {
-    async fn merge(&self, review: &Review) -> Result<MergeRequest> {
-        // Checkout the branch's parent, merge our branch onto it then move/rebase our children
+    async fn merge(&self, review: &Review, _options: &MergeOptions) -> Result<MergeRequest> {
+        // Merge our branch into its parent - 'stk merge' deletes it and moves its children afterwards
         let git = Git::new();
-        let parent = review.base.clone();
-        let children = git.children_of(&review.branch)?;
-
-        git.switch(&parent)?;
+        git.switch(&review.base)?;
         git.merge(vec![&review.branch])?;
 
-        // Reparent our children
-        for child in children {
-            git.switch(&child)?;
-            git.branch(vec!["--set-upstream-to", &parent])?;
-        }
-
-        // sync our parent and everyone downstream of it
-        git.sync(&parent)?;
-
-        // and finally, delete this branch - as it's been merged
-        git.branch(vec!["-d", &review.branch])?;
-
         Ok(MergeRequest {
             state: MergeState::Merged,
             review: review.clone(),
@@ -54,6 +39,10 @@ impl ReviewService for NoneReviewer {
         Ok(vec![])
     }
 
+    async fn all_reviews_for(&self, _branch: &str) -> Result<Vec<Review>> {
+        Ok(vec![])
+    }
+
     async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
         let mut hasher = DefaultHasher::new();
         (branch.to_string() + parent).hash(&mut hasher);
@@ -62,13 +51,27 @@ impl ReviewService for NoneReviewer {
             id,
             branch: branch.to_string(),
             base: parent.to_string(),
+            head: Git::new().rev_parse(vec![branch]).ok(),
             title: title.to_string(),
             body: body.to_string(),
             service: CodeReviewService::None,
             reviewers: vec![],
+            approvals: vec![],
             state: ReviewState::Approved,  // No one has to approve a "None" review
             tests: vec![],
             url: None,
         })
     }
-}
\ No newline at end of file
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        Ok(Review { base: base.to_string(), ..review.clone() })
+    }
+
+    async fn close(&self, _review: &Review, _comment: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn enable_auto_merge(&self, _review: &Review, _options: &MergeOptions) -> Result<bool> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,25 @@
+/// Raw requests for the services octocrab wasn't written for.
+///
+/// octocrab serves as a plain REST client for GitLab, Gerrit and Bitbucket too, but only GitHub
+/// and Gitea shape their errors the way it expects - anyone else's error comes back as an
+/// unhelpful parse failure. The calls whose failures matter go through `send`, and read the
+/// status and body themselves.
+use anyhow::Result;
+use octocrab::Octocrab;
+
+pub(crate) enum Method {
+    Get,
+    Post,
+    Put,
+}
+
+/// Status and body of a request to `route`
+pub(crate) async fn send(client: &Octocrab, method: Method, route: &str, body: Option<&serde_json::Value>) -> Result<(u16, String)> {
+    let response = match method {
+        Method::Get => client._get(route).await?,
+        Method::Post => client._post(route, body).await?,
+        Method::Put => client._put(route, body).await?,
+    };
+    let status = response.status().as_u16();
+    Ok((status, client.body_to_string(response).await?))
+}
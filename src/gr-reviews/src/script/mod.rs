@@ -0,0 +1,33 @@
+//! Review services implemented by an external executable, for forges stk doesn't support.
+//!
+//! stk runs `<command> <subcommand> [args...]` through `sh -c`, passes request bodies as JSON
+//! on stdin, and expects a JSON object on stdout carrying the protocol `"version": 1`.
+//! A non-zero exit status - or an `"error"` message - fails the request.
+//!
+//! | subcommand                         | stdin                         | stdout                              |
+//! |------------------------------------|-------------------------------|-------------------------------------|
+//! | `review <id>`                      |                               | `{"review": <review> \| null}`      |
+//! | `reviews`                          |                               | `{"reviews": [<review>...]}`        |
+//! | `reviews-for <branch> [--all]`     |                               | `{"reviews": [<review>...]}`        |
+//! | `create <branch> <parent>`         | `{"title", "body"}`           | `{"review": <review>}`              |
+//! | `merge <id>`                       | `{"method", "expected_head"}` | `{"merge": <merge>, "review": <review>}` |
+//! | `publish <remote> <branch> <parent>` |                             | `{}`                                |
+//! | `retarget <id> <base>`             |                               | `{"review": <review>}`              |
+//! | `close <id>`                       | `{"comment"}`                 | `{}`                                |
+//! | `auto-merge <id>`                  | `{"method", "expected_head"}` | `{"enabled": true \| false}`        |
+//!
+//! `--all` asks for merged and closed reviews too. `publish`, `retarget`, `close` and `auto-merge`
+//! may answer `{"unsupported": true}`: stk then pushes the branch itself, reports an error, or
+//! merges by itself respectively.
+//!
+//! A `<review>` looks like
+//! `{"id", "branch", "base", "head", "title", "body", "url", "state", "reviewers", "approvals", "tests"}`,
+//! where `state` is one of pending, approved, rejected, conflicted, merged or closed, and each
+//! test is `{"name", "state"}` with a state of pending, passed or failed. `head`, the sha the
+//! review's branch points at, is optional - without it stk can't tell whether a merged review
+//! holds the local branch's latest commits.
+//! A `<merge>` is `{"state": "merged" | "pending" | "failed", "reason"}`.
+//!
+//! `scripts/review-script.py` is a complete reference implementation.
+mod reviewer;
+pub use reviewer::ScriptReviewer;
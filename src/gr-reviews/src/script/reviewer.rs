@@ -0,0 +1,359 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use url::Url;
+use crate::{push_branch, CodeReviewService, MergeOptions, MergeRequest, MergeState, Review, ReviewService, ReviewState, ReviewTest, ReviewTestState};
+
+/// The version of the JSON protocol spoken with review scripts
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Delegates code reviews to an external executable - see the module docs for its protocol
+pub struct ScriptReviewer {
+    /// Run through `sh -c`, so it may carry arguments of its own
+    command: String,
+}
+
+/// What a script writes to stdout. Which fields are set depends on the subcommand.
+#[derive(Deserialize)]
+struct ScriptResponse {
+    version: u32,
+    error: Option<String>,
+    #[serde(default)]
+    unsupported: bool,
+    review: Option<ScriptReview>,
+    reviews: Option<Vec<ScriptReview>>,
+    merge: Option<ScriptMerge>,
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ScriptReviewState { Pending, Approved, Rejected, Conflicted, Merged, Closed }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ScriptTestState { Pending, Passed, Failed }
+
+#[derive(Deserialize)]
+struct ScriptTest {
+    name: String,
+    state: ScriptTestState,
+}
+
+#[derive(Deserialize)]
+struct ScriptReview {
+    /// Forges number their reviews - accept numbers as well as strings
+    #[serde(deserialize_with = "string_or_number")]
+    id: String,
+    branch: String,
+    base: String,
+    /// The commit the review's branch points at
+    head: Option<String>,
+    title: String,
+    #[serde(default)]
+    body: String,
+    url: Option<Url>,
+    state: ScriptReviewState,
+    #[serde(default)]
+    reviewers: Vec<String>,
+    #[serde(default)]
+    approvals: Vec<String>,
+    #[serde(default)]
+    tests: Vec<ScriptTest>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ScriptMergeState { Pending, Merged, Failed }
+
+#[derive(Deserialize)]
+struct ScriptMerge {
+    state: ScriptMergeState,
+    reason: Option<String>,
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<String, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!("expected a string or number, got {}", other))),
+    }
+}
+
+impl ScriptReviewer {
+    pub fn new(command: &str) -> ScriptReviewer {
+        ScriptReviewer { command: command.to_string() }
+    }
+
+    /// Runs `subcommand` with `args`, feeding it `input` on stdin, and checks its response
+    fn run(&self, subcommand: &str, args: &[&str], input: Option<serde_json::Value>) -> Result<ScriptResponse> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg("stk")
+            .arg(subcommand)
+            .args(args)
+            .env("STK_REVIEW_PROTOCOL", PROTOCOL_VERSION.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Could not run review script '{}': {}", self.command, e))?;
+
+        // Dropping stdin closes it, so scripts which don't read it still see its end
+        let mut stdin = child.stdin.take().unwrap();
+        if let Some(input) = input {
+            match stdin.write_all(input.to_string().as_bytes()) {
+                // The script exited without reading its input - its exit status tells the rest
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+                result => result?,
+            }
+        }
+        drop(stdin);
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("Review script '{} {}' failed ({}): {}", self.command, subcommand, output.status, stderr.trim()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let response: ScriptResponse = serde_json::from_str(&stdout)
+            .map_err(|e| anyhow!("Review script '{} {}' returned invalid JSON ({}): {}", self.command, subcommand, e, stdout.trim()))?;
+
+        if response.version != PROTOCOL_VERSION {
+            return Err(anyhow!("Review script '{}' speaks protocol version {}, stk speaks version {}", self.command, response.version, PROTOCOL_VERSION));
+        }
+        if let Some(error) = response.error {
+            return Err(anyhow!("Review script '{} {}' failed: {}", self.command, subcommand, error));
+        }
+        Ok(response)
+    }
+
+    fn missing(&self, subcommand: &str, field: &str) -> anyhow::Error {
+        anyhow!("Review script '{} {}' returned no \"{}\"", self.command, subcommand, field)
+    }
+
+    fn review_from(&self, subcommand: &str, response: ScriptResponse) -> Result<Review> {
+        let review = response.review.ok_or(self.missing(subcommand, "review"))?;
+        Ok(self.convert_to_review(review))
+    }
+
+    fn reviews_from(&self, subcommand: &str, response: ScriptResponse) -> Result<Vec<Review>> {
+        let reviews = response.reviews.ok_or(self.missing(subcommand, "reviews"))?;
+        Ok(reviews.into_iter().map(|r| self.convert_to_review(r)).collect())
+    }
+
+    fn convert_to_review(&self, review: ScriptReview) -> Review {
+        let state = match review.state {
+            ScriptReviewState::Pending => ReviewState::Pending,
+            ScriptReviewState::Approved => ReviewState::Approved,
+            ScriptReviewState::Rejected => ReviewState::Rejected,
+            ScriptReviewState::Conflicted => ReviewState::Conflicted,
+            ScriptReviewState::Merged => ReviewState::Merged,
+            ScriptReviewState::Closed => ReviewState::Closed,
+        };
+        let tests = review.tests.into_iter()
+            .map(|t| {
+                let state = match t.state {
+                    ScriptTestState::Pending => ReviewTestState::Pending,
+                    ScriptTestState::Passed => ReviewTestState::Passed,
+                    ScriptTestState::Failed => ReviewTestState::Failed,
+                };
+                ReviewTest { name: t.name, state }
+            })
+            .collect();
+
+        Review {
+            id: review.id,
+            branch: review.branch,
+            base: review.base,
+            head: review.head,
+            title: review.title,
+            body: review.body,
+            service: CodeReviewService::Script { command: self.command.clone() },
+            reviewers: review.reviewers,
+            approvals: review.approvals,
+            state,
+            tests,
+            url: review.url,
+        }
+    }
+}
+
+fn merge_options_json(options: &MergeOptions) -> serde_json::Value {
+    json!({ "method": options.method, "expected_head": options.expected_head })
+}
+
+#[async_trait]
+impl ReviewService for ScriptReviewer {
+    async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+        let response = self.run("merge", &[&review.id], Some(merge_options_json(options)))?;
+        let merge = response.merge.ok_or(self.missing("merge", "merge"))?;
+        // Scripts may skip the review when the merge failed
+        let review = match response.review {
+            Some(r) => self.convert_to_review(r),
+            None => review.clone(),
+        };
+
+        let state = match merge.state {
+            ScriptMergeState::Merged => MergeState::Merged,
+            ScriptMergeState::Pending => MergeState::Pending,
+            ScriptMergeState::Failed => MergeState::Failed(merge.reason.unwrap_or("the review script refused to merge".to_string())),
+        };
+        Ok(MergeRequest::new(review).in_state(state))
+    }
+
+    async fn review(&self, id: &str) -> Result<Option<Review>> {
+        let response = self.run("review", &[id], None)?;
+        Ok(response.review.map(|r| self.convert_to_review(r)))
+    }
+
+    async fn reviews(&self) -> Result<Vec<Review>> {
+        let response = self.run("reviews", &[], None)?;
+        self.reviews_from("reviews", response)
+    }
+
+    async fn reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let response = self.run("reviews-for", &[branch], None)?;
+        self.reviews_from("reviews-for", response)
+    }
+
+    async fn all_reviews_for(&self, branch: &str) -> Result<Vec<Review>> {
+        let response = self.run("reviews-for", &[branch, "--all"], None)?;
+        self.reviews_from("reviews-for", response)
+    }
+
+    async fn create_review(&self, branch: &str, parent: &str, title: &str, body: &str) -> Result<Review> {
+        let response = self.run("create", &[branch, parent], Some(json!({ "title": title, "body": body })))?;
+        self.review_from("create", response)
+    }
+
+    async fn publish(&self, remote: &str, branch: &str, parent: &str) -> Result<()> {
+        let response = self.run("publish", &[remote, branch, parent], None)?;
+        if response.unsupported { push_branch(remote, branch)?; }
+        Ok(())
+    }
+
+    async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+        let response = self.run("retarget", &[&review.id, base], None)?;
+        if response.unsupported {
+            return Err(anyhow!("Review script '{}' can't retarget review {} - point it at {} by hand", self.command, review.id, base));
+        }
+        self.review_from("retarget", response)
+    }
+
+    async fn close(&self, review: &Review, comment: &str) -> Result<()> {
+        let response = self.run("close", &[&review.id], Some(json!({ "comment": comment })))?;
+        if response.unsupported {
+            return Err(anyhow!("Review script '{}' can't close review {} - close it by hand", self.command, review.id));
+        }
+        Ok(())
+    }
+
+    async fn enable_auto_merge(&self, review: &Review, options: &MergeOptions) -> Result<bool> {
+        let response = self.run("auto-merge", &[&review.id], Some(merge_options_json(options)))?;
+        if response.unsupported { return Ok(false); }
+        Ok(response.enabled.unwrap_or(false))
+    }
+}
+
+/// Conformance tests - run against the reference script, plus scripts misbehaving on purpose
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+    use crate::MergeMethod;
+
+    static STORES: AtomicUsize = AtomicUsize::new(0);
+
+    /// The reference script, keeping its reviews in a fresh directory
+    fn reference() -> (ScriptReviewer, String) {
+        let store = std::env::temp_dir().join(format!("stk-review-store-{}-{}", std::process::id(), STORES.fetch_add(1, Ordering::SeqCst)));
+        let _ = std::fs::remove_dir_all(&store);
+        let script = format!("{}/scripts/review-script.py", env!("CARGO_MANIFEST_DIR"));
+        let command = format!("STK_REVIEW_STORE='{}' python3 '{}'", store.display(), script);
+        (ScriptReviewer::new(&command), command)
+    }
+
+    fn approve(command: &str, id: &str) {
+        let status = Command::new("sh").arg("-c").arg(format!("{} approve {} alice", command, id)).stdout(Stdio::null()).status().unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn test_create_and_list() {
+        let (script, _) = reference();
+
+        let review = script.create_review("feature", "main", "Add a feature", "Body").await.unwrap();
+        script.create_review("other", "feature", "Other", "").await.unwrap();
+
+        assert_eq!(review.id, "1");
+        assert_eq!((review.branch.as_str(), review.base.as_str(), review.title.as_str()), ("feature", "main", "Add a feature"));
+        assert!(matches!(review.state, ReviewState::Pending));
+        assert!(matches!(review.service, CodeReviewService::Script { .. }));
+        assert_eq!(script.reviews().await.unwrap().len(), 2);
+        assert_eq!(script.reviews_for("other").await.unwrap()[0].base, "feature");
+        assert!(script.review("3").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_merge_once_approved() {
+        let (script, command) = reference();
+        let review = script.create_review("feature", "main", "Add a feature", "Body").await.unwrap();
+        let options = MergeOptions { method: MergeMethod::Rebase, expected_head: None };
+
+        let refused = script.merge(&review, &options).await.unwrap();
+        let MergeState::Failed(reason) = refused.state else { panic!("merge should have failed") };
+        assert_eq!(reason, "review is pending");
+
+        approve(&command, &review.id);
+        let review = script.review(&review.id).await.unwrap().unwrap();
+        assert_eq!(review.approvals, vec!["alice"]);
+        assert!(matches!(review.tests[0].state, ReviewTestState::Passed));
+
+        let merged = script.merge(&review, &options).await.unwrap();
+        assert!(matches!(merged.state, MergeState::Merged));
+        assert!(matches!(merged.review.state, ReviewState::Merged));
+        assert!(script.reviews_for("feature").await.unwrap().is_empty());
+        assert_eq!(script.all_reviews_for("feature").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retarget_close_and_auto_merge() {
+        let (script, _) = reference();
+        let review = script.create_review("feature", "old", "Add a feature", "").await.unwrap();
+
+        assert_eq!(script.retarget(&review, "main").await.unwrap().base, "main");
+        assert!(!script.enable_auto_merge(&review, &MergeOptions::default()).await.unwrap());
+
+        script.close(&review, "Not needed").await.unwrap();
+        assert!(matches!(script.review(&review.id).await.unwrap().unwrap().state, ReviewState::Closed));
+        assert!(script.reviews().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_script_errors() {
+        let (script, _) = reference();
+        let err = script.retarget(&Review { id: "9".to_string(), ..script.create_review("a", "b", "t", "").await.unwrap() }, "main").await;
+        assert!(err.err().unwrap().to_string().ends_with("failed: no review 9"));
+
+        let failing = ScriptReviewer::new("echo broken >&2; exit 3; true");
+        let err = failing.reviews().await.err().unwrap().to_string();
+        assert!(err.contains("exit status: 3") && err.ends_with("broken"), "{}", err);
+
+        let garbage = ScriptReviewer::new("echo not json; true");
+        assert!(garbage.reviews().await.err().unwrap().to_string().contains("invalid JSON"));
+
+        let future = ScriptReviewer::new("echo '{\"version\": 2, \"reviews\": []}'; true");
+        assert!(future.reviews().await.err().unwrap().to_string().contains("protocol version 2"));
+
+        let numbered = ScriptReviewer::new("echo '{\"version\": 1, \"review\": {\"id\": 42, \"branch\": \"b\", \"base\": \"main\", \"title\": \"t\", \"state\": \"approved\"}}'; true");
+        let review = numbered.review("42").await.unwrap().unwrap();
+        assert_eq!(review.id, "42");
+        assert!(matches!(review.state, ReviewState::Approved));
+    }
+}
@@ -0,0 +1,133 @@
+/// How reviewers' verdicts, checks and a review's lifecycle add up to a `Review`'s state.
+/// Backends translate what their service reports into these terms, so every service judges
+/// reviews the same way.
+use std::collections::HashMap;
+use crate::{ReviewState, ReviewTest, ReviewTestState};
+
+/// Reviewer -> latest verdict: true for approved, false for changes requested
+#[derive(Default)]
+pub(crate) struct Verdicts(HashMap<String, bool>);
+
+impl Verdicts {
+    pub fn approve(&mut self, reviewer: String) {
+        self.0.insert(reviewer, true);
+    }
+
+    pub fn request_changes(&mut self, reviewer: String) {
+        self.0.insert(reviewer, false);
+    }
+
+    /// Withdrawn or dismissed - the reviewer has no verdict any more
+    pub fn dismiss(&mut self, reviewer: &str) {
+        self.0.remove(reviewer);
+    }
+
+    /// Whether any reviewer's verdict is a request for changes
+    pub fn changes_requested(&self) -> bool {
+        self.0.values().any(|approved| !approved)
+    }
+
+    pub fn approvals(&self) -> Vec<String> {
+        let mut approvals = self.0.iter()
+            .filter(|(_, approved)| **approved)
+            .map(|(reviewer, _)| reviewer.clone())
+            .collect::<Vec<String>>();
+        approvals.sort();
+        approvals
+    }
+
+    /// Everyone in `requested` - plus anyone who reviewed without being asked
+    pub fn reviewers(&self, requested: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut reviewers = requested.into_iter()
+            .chain(self.0.keys().cloned())
+            .collect::<Vec<String>>();
+        reviewers.sort();
+        reviewers.dedup();
+        reviewers
+    }
+
+    /// A test for having `required` approvals - failed by any outstanding request for changes,
+    /// however many approvals there are
+    pub fn approval_test(&self, required: usize) -> ReviewTest {
+        let approvals = self.approvals().len();
+        let state = if self.changes_requested() { ReviewTestState::Failed }
+                    else if approvals >= required { ReviewTestState::Passed }
+                    else { ReviewTestState::Pending };
+        ReviewTest { name: format!("{}/{} approvals", approvals, required), state }
+    }
+}
+
+/// Where a review is in its life, as its service reports it
+pub(crate) struct ReviewStatus {
+    pub merged: bool,
+    /// Closed, declined or abandoned without being merged
+    pub closed: bool,
+    /// Drafts aren't ready for review, whatever their checks say
+    pub draft: bool,
+    pub conflicted: bool,
+    /// Changes were requested - or a blocking vote was cast
+    pub rejected: bool,
+    /// The service's own judgement of whether the review may be merged, where it makes one.
+    /// Otherwise the review is ready once every test has passed.
+    pub ready: Option<bool>,
+}
+
+pub(crate) fn state_of_review(status: &ReviewStatus, tests: &[ReviewTest]) -> ReviewState {
+    if status.merged { return ReviewState::Merged; }
+    if status.closed { return ReviewState::Closed; }
+    if status.draft { return ReviewState::Pending; }
+
+    // Conflicts need to be resolved
+    if status.conflicted { return ReviewState::Conflicted; }
+
+    if status.rejected { return ReviewState::Rejected; }
+
+    // Approved once every check - approvals included - has passed
+    let ready = status.ready.unwrap_or_else(|| tests.iter().all(|t| matches!(t.state, ReviewTestState::Passed)));
+    if ready { return ReviewState::Approved; }
+
+    ReviewState::Pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> ReviewStatus {
+        ReviewStatus { merged: false, closed: false, draft: false, conflicted: false, rejected: false, ready: None }
+    }
+
+    #[test]
+    fn test_verdicts() {
+        let mut verdicts = Verdicts::default();
+        verdicts.request_changes("alice".to_string());
+        verdicts.approve("alice".to_string());
+        verdicts.approve("bob".to_string());
+        verdicts.request_changes("dave".to_string());
+        verdicts.dismiss("dave");
+
+        assert_eq!(verdicts.approvals(), vec!["alice", "bob"]);
+        assert_eq!(verdicts.reviewers(vec!["carol".to_string(), "bob".to_string()]), vec!["alice", "bob", "carol"]);
+        assert!(!verdicts.changes_requested());
+        assert!(matches!(verdicts.approval_test(2).state, ReviewTestState::Passed));
+        assert!(matches!(verdicts.approval_test(3).state, ReviewTestState::Pending));
+
+        verdicts.request_changes("carol".to_string());
+        assert!(matches!(verdicts.approval_test(1).state, ReviewTestState::Failed));
+        assert_eq!(verdicts.approval_test(1).name, "2/1 approvals");
+    }
+
+    #[test]
+    fn test_state_of_review() {
+        let passed = vec![ReviewTest { name: "ci".to_string(), state: ReviewTestState::Passed }];
+        let pending = vec![ReviewTest { name: "ci".to_string(), state: ReviewTestState::Pending }];
+
+        assert!(matches!(state_of_review(&open(), &passed), ReviewState::Approved));
+        assert!(matches!(state_of_review(&open(), &pending), ReviewState::Pending));
+        assert!(matches!(state_of_review(&ReviewStatus { ready: Some(false), ..open() }, &passed), ReviewState::Pending));
+        assert!(matches!(state_of_review(&ReviewStatus { ready: Some(true), ..open() }, &pending), ReviewState::Approved));
+        assert!(matches!(state_of_review(&ReviewStatus { draft: true, ..open() }, &passed), ReviewState::Pending));
+        assert!(matches!(state_of_review(&ReviewStatus { rejected: true, conflicted: true, ..open() }, &passed), ReviewState::Conflicted));
+        assert!(matches!(state_of_review(&ReviewStatus { merged: true, closed: true, ..open() }, &pending), ReviewState::Merged));
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use gr_reviews::{credential_host, credential_source};
+use crate::config::{read_config, store_credentials, write_config, GRConfig};
+use crate::gr::init::get_cr_auth;
+
+pub(crate) const AUTH_USAGE: &str = "stk auth <login | logout | status | rotate>
+
+Manages the credentials for the repo's review service and host.
+
+    login   Asks for a token (or password) and saves it with git's credential helper
+    logout  Erases the saved credentials
+    status  Shows where credentials for the review service come from
+    rotate  Replaces the saved credentials with new ones
+
+Secrets are kept by git's credential helper (see 'git help credential') under a host of
+their own - e.g. github.com.stk - so git never pushes with them. The stk config only records
+which host and account they belong to. Without saved credentials, stk falls
+back to the environment (e.g. GITHUB_TOKEN), git's credential helpers and the gh CLI.";
+
+pub fn auth(action: Option<&str>) -> Result<()> {
+    let mut config = read_config()?;
+    let host = credential_host(&config.code_review_tool)
+        .ok_or(anyhow!("{} reviews don't use stk's credentials", config.code_review_tool))?;
+
+    match action {
+        Some("login") => login(&mut config),
+        Some("logout") => logout(&mut config),
+        Some("status") | None => status(&config, &host),
+        Some("rotate") => {
+            if config.code_review_credential.is_none() {
+                return Err(anyhow!("No saved credentials to rotate - run 'stk auth login'"));
+            }
+            login(&mut config)
+        }
+        Some(other) => Err(anyhow!("Unknown auth action '{}' - expected login, logout, status or rotate", other)),
+    }
+}
+
+fn login(config: &mut GRConfig) -> Result<()> {
+    let previous = config.code_review_credential.clone();
+    let (user, secret) = get_cr_auth(&config.code_review_tool)?.secret()
+        .ok_or(anyhow!("No credentials given"))?;
+
+    store_credentials(config, user, &secret)?;
+    // A new account leaves the old one's secret behind - the same account's was overwritten
+    if let Some(previous) = previous.filter(|p| Some(p) != config.code_review_credential.as_ref()) {
+        previous.erase()?;
+    }
+    write_config(config)?;
+
+    println!("{} {}", "Saved credentials for".green(), config.code_review_tool.to_string().cyan());
+    Ok(())
+}
+
+fn logout(config: &mut GRConfig) -> Result<()> {
+    let Some(credential) = config.code_review_credential.take() else {
+        println!("{}", "No saved credentials".yellow());
+        return Ok(());
+    };
+
+    credential.erase()?;
+    write_config(config)?;
+    println!("{} {}", "Erased credentials for".green(), credential.host.cyan());
+    Ok(())
+}
+
+fn status(config: &GRConfig, host: &str) -> Result<()> {
+    println!("{} {} ({})", "Review service:".green(), config.code_review_tool, host.cyan());
+
+    match &config.code_review_credential {
+        Some(credential) => {
+            let account = credential.user.as_deref().unwrap_or("token");
+            let state = if credential.load().is_some() { "saved".green() } else { "missing - run 'stk auth login'".red() };
+            println!("{} {} ({})", "Saved credentials:".green(), account, state);
+        }
+        None => println!("{} {}", "Saved credentials:".green(), "none".yellow()),
+    }
+
+    match credential_source(&config.review_tool())? {
+        Some(source) => println!("{} {}", "Signing in with:".green(), source),
+        None => println!("{} {}", "Signing in with:".green(), "no credentials found".yellow()),
+    }
+    Ok(())
+}
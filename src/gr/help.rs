@@ -1,7 +1,11 @@
 use gr_reviews::{MERGE_USAGE, REVIEW_USAGE};
+use crate::gr::auth::AUTH_USAGE;
 use crate::gr::init;
 use crate::gr::log;
-use crate::gr::r#move::MOVE_USAGE;
+use crate::gr::r#move::{MOVE_USAGE, REPARENT_USAGE};
+use crate::gr::oplog::{OPLOG_USAGE, UNDO_USAGE};
+use crate::gr::restack::{ABORT_USAGE, CONTINUE_USAGE};
+use crate::gr::squash::{FOLD_USAGE, SQUASH_USAGE};
 
 const USAGE: &str = "Usage: stk <command> [<args>]
 
@@ -10,8 +14,11 @@ type 'stk help <command>' for detailed help with a specific command.
 
 General Commands:
     init            Configure (or reconfigure) stk
+    auth            Manage review service credentials
     help            Display this help message
     log             Display the commit log
+    oplog           List recorded operations
+    undo            Undo the last (or a given) operation
 
 Branch Commands:
     create, bc     Create a new branch
@@ -19,6 +26,8 @@ Branch Commands:
     commit, cc     Commit changes
     submit         Submit current branch (and parents) for code review
     sync           Sync from remote (recursive pull and rebase)
+    continue       Resume a sync after resolving conflicts
+    abort          Cancel a sync, restoring every branch
 
 Review Commands:
     reviews, rv    List open reviews
@@ -29,6 +38,9 @@ Stack Commands:
     bottom, bb     Move to the bottom of the stack
     up, bu         Move up in the stack
     down, bd       Move down in the stack
+    move --onto    Move the current branch onto another parent
+    fold           Fold the current branch into its parent
+    squash         Combine a run of stacked branches into one
 
 Git Commands:
     Any keywords not listed above will be passed directly to git.
@@ -65,7 +77,15 @@ This will force-update the remote if there are any conflicts.",
 Sync from remote (recursive pull and rebase).
 
 Sync recursively pulls the latest changes from remote and local branches in order
-to ensure that all branches in the current stack are up-to-date."
+to ensure that all branches in the current stack are up-to-date.
+
+Branches which already landed in their parent - including rebased or squash-merged
+ones - are reported as merged and offered for deletion. Their children are moved
+onto the merged branch's parent.
+
+If a branch conflicts, sync stops and leaves the rebase for you to resolve.
+Run 'stk continue' once the conflicts are fixed, or 'stk abort' to put every branch back
+where it was before the sync."
 ];
 
 pub fn show_usage() {
@@ -77,7 +97,10 @@ pub fn show_help(cmd: &str) {
         // General
         "help" => println!("You already got it, chief."),
         "init" => println!("{}", init::USAGE),
+        "auth" => println!("{}", AUTH_USAGE),
         "log" => println!("{}", log::USAGE),
+        "oplog" => println!("{}", OPLOG_USAGE),
+        "undo" => println!("{}", UNDO_USAGE),
 
         // Branch
         "create" | "bc" => println!("{}", BRANCH_USAGE[0]),
@@ -85,6 +108,8 @@ pub fn show_help(cmd: &str) {
         "commit" | "cc" => println!("{}", BRANCH_USAGE[2]),
         "submit" => println!("{}", BRANCH_USAGE[3]),
         "sync" => println!("{}", BRANCH_USAGE[4]),
+        "continue" => println!("{}", CONTINUE_USAGE),
+        "abort" => println!("{}", ABORT_USAGE),
 
         // Review
         "reviews" | "rv" => println!("{}", REVIEW_USAGE),
@@ -95,6 +120,9 @@ pub fn show_help(cmd: &str) {
         "down"   | "bd" => println!("{}", MOVE_USAGE[1]),
         "bottom" | "bb" => println!("{}", MOVE_USAGE[2]),
         "top"    | "bt" => println!("{}", MOVE_USAGE[3]),
+        "move" => println!("{}", REPARENT_USAGE),
+        "fold" => println!("{}", FOLD_USAGE),
+        "squash" => println!("{}", SQUASH_USAGE),
 
         // Git
         _ => println!("TODO"),
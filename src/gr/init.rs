@@ -1,10 +1,10 @@
 use anyhow::{anyhow, Result};
 use colored::Colorize;
 use gr_git::{BranchType, Git};
-use gr_reviews::CodeReviewService;
+use gr_reviews::{detect_review_service, CodeReviewService, MergeMethod, MergeOrder};
 use candy::candy::Candy;
 use candy::events::CandyEvent::{Cancel, Submit};
-use crate::config::{config_dir_path, config_file_exists, CRAuth, GrConfBranch, GRConfig };
+use crate::config::{local_config_path, migrate_config_location, store_credentials, write_config, CRAuth, GrConfBranch, GRConfig };
 
 pub(crate) const USAGE: &str = "stk init
 
@@ -13,18 +13,20 @@ Collects (or sets) the following information:
   - root branch
   - preferred remote
   - preferred code review tool
-      - auth token (if needed)
+      - server address (Github Enterprise, Gitea / Forgejo, GitLab, Gerrit, Bitbucket Server)
+      - review script command (Script)
+      - auth token (if needed) - kept by git's credential helper, see 'stk help auth'
 
-The config file for stk is in ~/.config/gr/<project>/<cwd>/config.toml";
+The config file for stk is in .git/gr/config.toml. Settings missing from it are taken from
+the repo's checked-in .gr.toml, then from ~/.config/gr/config.toml.";
 
 pub fn initialize_gr() -> Result<()> {
     let git = Git::new();
     let candy = Candy::new();
-    let gr_dir = config_dir_path()?;
-    let config_file_path = format!("{}/config.toml", gr_dir);
 
-    // Check if the config file exists
-    if config_file_exists(&config_file_path) {
+    // Check if the config file exists - wherever an older version left it
+    migrate_config_location()?;
+    if local_config_path()?.exists() {
         if candy.yn("stk is already initialized - reinitialize?") {}
         else {
             println!("{}", "Aborted initialization".red());
@@ -34,39 +36,59 @@ pub fn initialize_gr() -> Result<()> {
 
     println!("{}", "Initializing stk...".green());
 
-    // Prepare -
-    // Create the app's config directory if it doesn't exist
-    std::fs::create_dir_all(gr_dir)?;
-
     // Gather configuration info from the user
 
     let root_branch = select_root_branch(&git)?;
     let remote = select_remote(&git)?;
-    let cr_tool = select_review_tool()?;
+    let cr_tool = select_review_tool(&git, remote.as_deref())?;
     let cr_auth = get_cr_auth(&cr_tool)?;
 
     // Build config data
 
-    let config = GRConfig {
+    let mut config = GRConfig {
         origin: remote.unwrap_or("".to_string()),
         root_branch: root_branch,
         code_review_tool: cr_tool,
-        code_review_user: cr_auth.user,
-        code_review_pass: cr_auth.pass,
-        code_review_key: cr_auth.token,
+        code_review_credential: None,
+        code_review_user: None,
+        code_review_pass: None,
+        code_review_key: None,
+        merge_method: Some(MergeMethod::default()),
+        merge_order: Some(MergeOrder::default()),
         version: "1.0.0".to_string(),
         branches: build_branch_conf(&git)?,
     };
 
-    // Serialize to TOML
-    let toml = toml::to_string_pretty(&config)?;
+    // Secrets go to git's credential helper - the config only refers to them
+    // Without them the config would be useless, so don't write it unless they were saved
+    if let Some((user, secret)) = cr_auth.secret() {
+        while let Err(e) = store_credentials(&mut config, user.clone(), &secret) {
+            println!("  {} {}", "Credentials not saved:".yellow(), e);
+            if !candy.yn("Try again?") {
+                return Err(anyhow!("stk was not initialized - its credentials could not be saved. See 'stk help auth'"));
+            }
+        }
+        println!("  {}", "Credentials saved with git's credential helper".green());
+    }
 
     // (over)Write config file
-    std::fs::write(config_file_path, toml)?;
+    write_config(&config)?;
 
     Ok(())
 }
 
+/// Imports stack parents recorded via upstream tracking into gr's own parent metadata
+pub fn migrate_stack_parents(git: &Git) -> Result<()> {
+    let migrated = git.migrate_parents()?;
+    if migrated.is_empty() { return Ok(()); }
+
+    println!("{}", "Imported existing stacks:".green());
+    for (branch, parent) in migrated {
+        println!("  {} -> {}", branch.cyan(), parent.magenta());
+    }
+    Ok(())
+}
+
 fn build_branch_conf(git: &Git) -> Result<Vec<GrConfBranch>> {
     let branches = git.branches()?;
 
@@ -82,11 +104,12 @@ fn build_gr_conf_branch(git: &Git, branch: &str) -> Result<GrConfBranch> {
     Ok(GrConfBranch { name: branch.to_string(), parent: git.parent_of(branch, BranchType::Local)?, remote_branch: None, review_id: None })
 }
 
-fn get_cr_auth(cr_tool: &CodeReviewService) -> Result<CRAuth> {
+pub(crate) fn get_cr_auth(cr_tool: &CodeReviewService) -> Result<CRAuth> {
     let candy = Candy::new();
     match cr_tool {
-        CodeReviewService::None => Ok(CRAuth { user: None, pass: None, token: None }),
-        CodeReviewService::Github => {
+        // Scripts look after their own credentials
+        CodeReviewService::None | CodeReviewService::Script { .. } => Ok(CRAuth { user: None, pass: None, token: None }),
+        CodeReviewService::Github | CodeReviewService::GithubEnterprise { .. } => {
             // check for pre-configured env vars
             if let Ok(token) = std::env::var("GITHUB_TOKEN") {
                 if candy.yn("Found GITHUB_TOKEN in environment variables. Use it?") {
@@ -108,9 +131,41 @@ fn get_cr_auth(cr_tool: &CodeReviewService) -> Result<CRAuth> {
                 Ok(CRAuth { user: Some(user), pass: Some(pass), token: None })
             }
         }
+        CodeReviewService::Gitea { host } =>
+            get_token_auth("GITEA_TOKEN", &format!("Create an access token under Settings > Applications on {}", host.cyan())),
+        CodeReviewService::Gitlab { host } =>
+            get_token_auth("GITLAB_TOKEN", &format!("Create a personal access token with the 'api' scope under Preferences > Access tokens on {}", host.cyan())),
+        CodeReviewService::Gerrit { host } => {
+            if let (Ok(user), Ok(pass)) = (std::env::var("GERRIT_USER"), std::env::var("GERRIT_PASSWORD")) {
+                if candy.yn("Found GERRIT_USER and GERRIT_PASSWORD in environment variables. Use them?") {
+                    return Ok(CRAuth { user: Some(user), pass: Some(pass), token: None });
+                }
+            }
+
+            println!("  Generate an HTTP password under Settings > HTTP Credentials on {}", host.cyan());
+            let Submit(user) = candy.edit_line("Enter your Gerrit username: ", None) else { Err(anyhow!("Cancelled"))? };
+            let Submit(pass) = candy.edit_line("Paste your HTTP password: ", None) else { Err(anyhow!("Cancelled"))? };
+            Ok(CRAuth { user: Some(user), pass: Some(pass), token: None })
+        }
+        CodeReviewService::Bitbucket { host } =>
+            get_token_auth("BITBUCKET_TOKEN", &format!("Create an HTTP access token with repository write permission under Manage account > HTTP access tokens on {}", host.cyan())),
     }
 }
 
+/// Asks for an access token - offering the one in `env_var`, which the review service also reads
+fn get_token_auth(env_var: &str, hint: &str) -> Result<CRAuth> {
+    let candy = Candy::new();
+    if let Ok(token) = std::env::var(env_var) {
+        if candy.yn(&format!("Found {} in environment variables. Use it?", env_var)) {
+            return Ok(CRAuth { user: None, pass: None, token: Some(token) });
+        }
+    }
+
+    println!("  {}", hint);
+    let Submit(token) = candy.edit_line("Paste your token: ", None) else { Err(anyhow!("Cancelled"))? };
+    Ok(CRAuth { user: None, pass: None, token: Some(token) })
+}
+
 fn select_remote(git: &Git) -> Result<Option<String>> {
     let candy = Candy::new();
     let remotes = git.remotes()?;
@@ -135,12 +190,25 @@ fn select_remote(git: &Git) -> Result<Option<String>> {
     }
 }
 
-fn select_review_tool() -> Result<CodeReviewService> {
+fn select_review_tool(git: &Git, remote: Option<&str>) -> Result<CodeReviewService> {
     let candy = Candy::new();
-    match candy.select_one("Select your review tool:", vec![CodeReviewService::None.to_string(), CodeReviewService::Github.to_string()], None) {
+    let tools = vec!["None".to_string(), "Github".to_string(), "Github Enterprise".to_string(), "Gitea".to_string(), "Gitlab".to_string(), "Gerrit".to_string(), "Bitbucket".to_string(), "Script".to_string()];
+    // Start on the tool the remote's url points at, if it gives it away
+    let detected = remote
+        .and_then(|r| git.remote(vec!["get-url", r]).ok())
+        .and_then(|url| detect_review_service(url.trim()))
+        .and_then(|service| tools.iter().position(|t| *t == service.to_string()));
+    match candy.select_one("Select your review tool:", tools, detected) {
         Submit(tool) => {
             let tool = match tool.as_str() {
                 "Github" => CodeReviewService::Github,
+                // The API lives at <host>/api/v3 - set api_url in the config if yours doesn't
+                "Github Enterprise" => CodeReviewService::GithubEnterprise { host: select_host(git, remote)?, api_url: None },
+                "Gitea" => CodeReviewService::Gitea { host: select_host(git, remote)? },
+                "Gitlab" => CodeReviewService::Gitlab { host: select_host(git, remote)? },
+                "Gerrit" => CodeReviewService::Gerrit { host: select_host(git, remote)? },
+                "Bitbucket" => CodeReviewService::Bitbucket { host: select_host(git, remote)? },
+                "Script" => CodeReviewService::Script { command: select_script()? },
                 _ => CodeReviewService::None
             };
             let msg = format!("{} {}", "Review tool: ".green(), tool);
@@ -157,6 +225,36 @@ fn select_review_tool() -> Result<CodeReviewService> {
     }
 }
 
+/// Asks for the review server's address - suggesting the one the remote points at
+fn select_host(git: &Git, remote: Option<&str>) -> Result<String> {
+    let candy = Candy::new();
+    let suggested = remote
+        .and_then(|r| git.remote(vec!["get-url", r]).ok())
+        .and_then(|url| host_of(&url));
+
+    let Submit(host) = candy.edit_line("Server address (e.g. https://git.example.com): ", suggested.as_deref()) else { Err(anyhow!("Cancelled"))? };
+    let host = host.trim().trim_end_matches('/').to_string();
+    if host.is_empty() { return Err(anyhow!("No server address given")); }
+    Ok(host)
+}
+
+/// Asks for the review script to run - see scripts/review-script.py in gr-reviews for a reference
+fn select_script() -> Result<String> {
+    let candy = Candy::new();
+    let Submit(command) = candy.edit_line("Review script command: ", None) else { Err(anyhow!("Cancelled"))? };
+    let command = command.trim().to_string();
+    if command.is_empty() { return Err(anyhow!("No review script given")); }
+    Ok(command)
+}
+
+/// https://<host> for a remote url like https://host/o/r.git or git@host:o/r.git
+fn host_of(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let rest = rest.split_once('@').map(|(_, rest)| rest).unwrap_or(rest);
+    let host = rest.split(['/', ':']).next().filter(|h| !h.is_empty())?;
+    Some(format!("https://{}", host))
+}
+
 fn select_root_branch(git: &Git) -> Result<String> {
     let candy = Candy::new();
     let branches = git.branches()?;
@@ -1,7 +1,8 @@
 use std::fmt::Display;
+use std::rc::Rc;
 use colored::Colorize;
 use itertools::Itertools;
-use gr_git::{BranchType, Git};
+use gr_git::{BranchType, Git, StackGraph};
 use crate::gr::log::tree::{Node, WithChildren};
 use crate::indent::Indentable;
 
@@ -14,7 +15,8 @@ pub trait NamedData {
 pub struct LogBranch {
     sha: String,
     name: String,
-    commits: Vec<LogCommit>
+    commits: Vec<LogCommit>,
+    graph: Rc<StackGraph>
 }
 
 #[derive(Debug, Clone)]
@@ -26,26 +28,27 @@ pub struct LogCommit {
 
 pub struct GitBranch {
     name: String,
-    sha: String
+    sha: String,
+    graph: Rc<StackGraph>
 }
 
 impl GitBranch {
-    pub fn new(name: String, sha: String) -> Self {
-        Self { name, sha }
+    pub fn new(name: String, graph: Rc<StackGraph>) -> Self {
+        let sha = graph.tip_of(&name).unwrap_or_default().to_string();
+        Self { name, sha, graph }
     }
 
     pub fn root() -> Self {
-        let git = Git::new();
-        let name = git.root_branches().unwrap().first().unwrap().to_string();
-        let sha = git.rev_parse(vec![&name]).unwrap();
-        Self::new(name, sha)
+        let graph = Git::new().stack_graph().unwrap();
+        let name = graph.roots().first().unwrap().to_string();
+        Self::new(name, graph)
     }
 }
 
 impl From<GitBranch> for LogBranch {
     fn from(branch: GitBranch) -> Self {
         let git = Git::new();
-        let parent = git.parent_of(&branch.name, BranchType::All).unwrap_or(None);
+        let parent = branch.graph.parent_of(&branch.name, BranchType::All);
 
         let commits = match parent {
             None => { Vec::new() }
@@ -55,37 +58,24 @@ impl From<GitBranch> for LogBranch {
         LogBranch {
             name: branch.name,
             sha: branch.sha,
-            commits
+            commits,
+            graph: branch.graph
         }
     }
 }
 
 impl WithChildren for LogBranch {
     fn children(&self) -> Vec<Self> {
-        let git = Git::new();
-        git.children_of(&self.name).unwrap().iter()
-            .map(|c| GitBranch::new(c.to_string(), git.rev_parse(vec![&c]).unwrap()).into())
+        self.graph.children_of(&self.name).iter()
+            .map(|c| GitBranch::new(c.to_string(), self.graph.clone()).into())
             .collect_vec()
     }
 }
 
 impl LogBranch {
     pub fn from_branch(branch: &str) -> Self {
-        let git = Git::new();
-        let name = branch.to_string();
-        let sha = git.rev_parse(vec![branch]).unwrap();
-
-        let parent = git.parent_of(branch, BranchType::All).unwrap_or(None);
-        let commits = match parent {
-            None => { Vec::new() }
-            Some(p) => { LogCommit::from_diff(git.commit_diff(branch, &p).unwrap_or(String::new())) }
-        };
-
-        Self {
-            name,
-            sha,
-            commits
-        }
+        let graph = Git::new().stack_graph().unwrap();
+        GitBranch::new(branch.to_string(), graph).into()
     }
 }
 
@@ -1,42 +1,383 @@
-use std::thread::sleep;
-use std::time::Duration;
-use gr_reviews::{CodeReviewService, MergeRequest, MergeState, review_service_for};
+use std::time::{Duration, Instant};
+use gr_reviews::{Backoff, BACKOFF_TIME_SECONDS, MergeMethod, MergeOptions, MergeOrder, MergeRequest, MergeState, Review, ReviewState, ReviewTestState, ReviewTool, review_service_for};
 use gr_reviews::ReviewService;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use colored::Colorize;
-use gr_git::Git;
-use gr_git::BranchType;
+use gr_git::{BranchType, Git, StackGraph};
+use candy::candy::Candy;
+use candy::events::CandyEvent::Submit;
 use candy::symbols::{BACKSPACE, CHECK, CROSS};
 use crate::indent::Indentable;
+use crate::gr::r#move::pin_base;
+use crate::gr::restack::{assert_no_plan, restack_branches, RestackPlan};
+
+/// How long `--when-ready` waits for a review before giving up
+const WHEN_READY_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
 
 struct Pair<A, B> {
     a: A,
     b: B
 }
 
+/// One branch of the stack, as `stk merge` sees it before anything is merged
+struct PlanItem {
+    branch: String,
+    review: Option<Review>,
+    /// Reasons this review can't be merged
+    blockers: Vec<String>,
+    /// What this review still waits for - approvals, running checks. Blocks unless merging --when-ready.
+    waiting: Vec<String>,
+}
+
 /// Merges approved / mergeable code reviews for the current stack of branches
-pub async fn merge(cr_tool: &CodeReviewService, remote: &str) -> Result<()> {
+/// With `when_ready`, each review is merged as soon as it is approved and its checks pass.
+pub async fn merge(cr_tool: &ReviewTool, remote: &str, method: MergeMethod, order: MergeOrder, when_ready: bool) -> Result<()> {
     let git = Git::new();
+    assert_no_plan(&git)?;
+    let graph = git.stack_graph()?;
     let cr_service = review_service_for(cr_tool)?;
 
-    println!("{}", "Merging stack".green());
-    // recursively, from the lowest branch, merge our reviews
-    let mut merge_requests = merge_branch(&cr_service, remote, &git.current_branch()?).await?;
+    // Gather everything first - nothing is merged until the user has seen the plan
+    println!("{}", "Checking reviews...".green());
+    let plan = build_plan(cr_service.as_ref(), &graph).await?;
+    if plan.is_empty() {
+        println!("  {}", "No branches to merge".yellow());
+        return Ok(());
+    }
+    print_plan(&plan, when_ready);
+
+    let Some(cut_off) = choose_cut_off(&plan, when_ready) else {
+        println!("{}", "Aborted".red());
+        return Ok(());
+    };
+    let stack = plan.into_iter()
+        .take(cut_off)
+        .filter_map(|item| item.review.map(|r| Pair { a: item.branch, b: r }))
+        .collect::<Vec<Pair<String, Review>>>();
+
+    // Everything lands in the root the bottom review targets
+    let root = stack[0].b.base.clone();
+
+    println!("{}", format!("Merging stack ({}, {})", order, method).green());
+    let landed = match order {
+        MergeOrder::TopDown => merge_top_down(&git, cr_service.as_ref(), &graph, remote, method, when_ready, stack).await?,
+        MergeOrder::BottomUp => merge_bottom_up(&git, cr_service.as_ref(), remote, method, when_ready, stack).await?,
+    };
+    if landed.is_empty() { return Ok(()); }
+
+    clean_up(&git, cr_tool, remote, &root, &landed).await
+}
+
+/// Every stacked branch below (and including) the current one, bottom up, with its review
+async fn build_plan(cr_service: &dyn ReviewService, graph: &StackGraph) -> Result<Vec<PlanItem>> {
+    let mut branches = graph.ancestors(graph.current_branch());
+    branches.reverse();
+    branches.push(graph.current_branch().to_string());
 
-    while !merge_requests.is_empty() {
-        let mut pair = merge_requests.remove(0);
+    let mut plan = Vec::new();
+    // Roots are what we merge _into_
+    for branch in branches.into_iter().filter(|b| graph.parent_of(b, BranchType::Local).is_some()) {
+        let review = cr_service.reviews_for(&branch).await?.into_iter().next();
+        let (blockers, waiting) = match &review {
+            Some(r) => (blockers_for(r), waiting_on(r)),
+            None => (vec!["no open review".to_string()], Vec::new()),
+        };
+        plan.push(PlanItem { branch, review, blockers, waiting });
+    }
+    Ok(plan)
+}
+
+fn blockers_for(review: &Review) -> Vec<String> {
+    let mut blockers = Vec::new();
+    match review.state {
+        ReviewState::Conflicted => blockers.push("conflicts with its base".to_string()),
+        ReviewState::Rejected => blockers.push("changes requested".to_string()),
+        ReviewState::Closed => blockers.push("closed".to_string()),
+        _ => {}
+    }
+
+    let failing = failing_checks(review);
+    if !failing.is_empty() { blockers.push(format!("failing: {}", failing.join(", "))); }
+    blockers
+}
 
-        print!("  {}: ?", pair.a.green());
-        match pair.b {
-            None => { println!("  {}", "Up to date".yellow()); continue; },
-            Some(mut mr) => { track_mr_progress(&mut mr).await?; }
+/// What keeps a review which nothing blocks from being ready: approval, and checks still running
+fn waiting_on(review: &Review) -> Vec<String> {
+    let mut waiting = Vec::new();
+    if matches!(review.state, ReviewState::Pending) { waiting.push("not approved".to_string()); }
+
+    let pending = review.tests.iter()
+        .filter(|t| matches!(t.state, ReviewTestState::Pending))
+        .map(|t| t.name.clone())
+        .collect::<Vec<String>>();
+    if !pending.is_empty() { waiting.push(format!("pending: {}", pending.join(", "))); }
+    waiting
+}
+
+fn failing_checks(review: &Review) -> Vec<String> {
+    review.tests.iter()
+        .filter(|t| matches!(t.state, ReviewTestState::Failed))
+        .map(|t| t.name.clone())
+        .collect()
+}
+
+fn print_plan(plan: &[PlanItem], when_ready: bool) {
+    let width = plan.iter().map(|i| i.branch.len()).max().unwrap_or(0);
+
+    for item in plan {
+        let branch = format!("{:<width$}", item.branch, width = width);
+        let Some(review) = &item.review else {
+            println!("  {} {} {}", CROSS.red(), branch.green(), "no open review".red());
+            continue;
+        };
+
+        let status = match (item.blockers.is_empty(), item.waiting.is_empty()) {
+            (true, true) => CHECK.green(),
+            (true, false) if when_ready => "?".yellow(),
+            _ => CROSS.red(),
+        };
+        let checks = match failing_checks(review).len() {
+            _ if review.tests.is_empty() => "no checks".normal(),
+            0 => format!("{} checks passing", review.tests.len()).green(),
+            n => format!("{}/{} checks failing", n, review.tests.len()).red(),
+        };
+        let mergeable = match review.state {
+            ReviewState::Conflicted => "conflicted".red(),
+            _ => "mergeable".green(),
+        };
+        println!("  {} {} {} {} {} approvals, {}, {}",
+                 status,
+                 branch.green(),
+                 format!("#{}", review.id).cyan(),
+                 format!("{}", review.state).yellow(),
+                 review.approvals.len(),
+                 checks,
+                 mergeable);
+        if !item.blockers.is_empty() {
+            println!("      {}", format!("blocked: {}", item.blockers.join("; ")).red());
+        }
+        if !item.waiting.is_empty() {
+            let waiting = format!("waiting: {}", item.waiting.join("; "));
+            println!("      {}", if when_ready { waiting.yellow() } else { waiting.red() });
         }
     }
+    println!();
+}
+
+/// How many branches (from the bottom) to merge - None when the user backs out.
+/// Merging stops at the first blocked branch, as nothing above it can land. With `when_ready`,
+/// branches still waiting for approvals or checks can be picked - we wait for them.
+fn choose_cut_off(plan: &[PlanItem], when_ready: bool) -> Option<usize> {
+    let candy = Candy::new();
+    let ready = plan.iter().take_while(|i| i.blockers.is_empty() && (when_ready || i.waiting.is_empty())).count();
+
+    match ready {
+        0 if plan[0].blockers.is_empty() => {
+            println!("{}", "Nothing can be merged until the bottom branch is approved and its checks pass - use --when-ready to wait for them".yellow());
+            None
+        },
+        0 => {
+            println!("{}", "Nothing can be merged until the bottom branch's blockers are resolved".yellow());
+            None
+        },
+        1 => candy.yn(&format!("Merge {}?", plan[0].branch.green())).then_some(1),
+        _ => {
+            // Top of the mergeable run first - picking it merges everything we can
+            let options = plan[..ready].iter().rev().map(|i| i.branch.clone()).collect();
+            match candy.select_one("Merge up to (and including) which branch?", options, None) {
+                Submit(branch) => plan.iter().position(|i| i.branch == branch).map(|p| p + 1),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Merges each review into the root in turn, moving the next one onto the updated root as we go
+/// Returns the branches which landed.
+async fn merge_bottom_up(git: &Git, cr_service: &dyn ReviewService, remote: &str, method: MergeMethod, when_ready: bool, stack: Vec<Pair<String, Review>>) -> Result<Vec<String>> {
+    let mut merged = Vec::new();
+    // Where the last review landed - (base branch, the commit we merged)
+    let mut landed: Option<(String, String)> = None;
+    for Pair { a: branch, b: mut review } in stack {
+        print!("  {}: ?", branch.green());
+
+        // Our parent just landed - move onto the root it landed in, so this review merges cleanly
+        let tip = match &landed {
+            Some((root, merged_tip)) => {
+                restack_onto_root(git, remote, &branch, root, merged_tip)?;
+                cr_service.retarget(&review, root).await?;
+                let tip = git.rev_parse(vec![&branch])?;
+                review = await_head(cr_service, review, &tip).await?;
+                tip
+            }
+            None => git.rev_parse(vec![&branch])?,
+        };
+        let options = MergeOptions { method, expected_head: Some(tip.clone()) };
+        let mut mr = match when_ready {
+            true => match merge_when_ready(cr_service, review, &options).await? {
+                Some(mr) => mr,
+                None => break,
+            },
+            false => cr_service.merge(&review, &options).await?,
+        };
+        track_mr_progress(cr_service, &mut mr).await?;
 
+        if !matches!(mr.state, MergeState::Merged) {
+            println!("{}", "Stopping - reviews above this one were not merged".yellow());
+            break;
+        }
+        landed = Some((mr.review.base.clone(), tip));
+        merged.push(branch);
+    }
+
+    Ok(merged)
+}
+
+/// Rebases `branch` onto the freshly updated `root`, dropping the commits of its just merged
+/// parent (which ended at `merged_tip`), and force-pushes the result
+fn restack_onto_root(git: &Git, remote: &str, branch: &str, root: &str, merged_tip: &str) -> Result<()> {
+    let onto = update_root(git, remote, root)?;
+
+    if let Err(e) = git.rebase(vec!["--onto", &onto, merged_tip, branch]) {
+        if git.rebase_in_progress()? { git.rebase(vec!["--abort"])?; }
+        return Err(anyhow!("Could not rebase {} onto {} - run 'stk sync', resolve the conflicts and merge again\n{}", branch, onto, e));
+    }
+    // Only branches which were pushed before have a review to update
+    if has_remote_branch(git, remote, branch) {
+        git.push(vec!["--force-with-lease", remote, branch])?;
+    }
+
+    // Keep the stack metadata in step with what we just did
+    if onto == root { git.set_parent(branch, root)?; }
+    git.set_base(branch, &git.rev_parse(vec![&onto])?)?;
     Ok(())
 }
 
-async fn track_mr_progress(mr: &mut MergeRequest) -> Result<()> {
+/// Brings the local `root` up to date with whatever just landed on the remote.
+/// Returns the ref to stack onto: the local root if there is one, otherwise the remote's.
+fn update_root(git: &Git, remote: &str, root: &str) -> Result<String> {
+    let remote_root = format!("{}/{}", remote, root);
+    // No remote (e.g. no review service) - the merge happened in the local root
+    let fetched = git.remotes()?.iter().any(|r| r == remote) && git.fetch(vec![remote, root]).is_ok();
+
+    if !git.branches()?.iter().any(|b| b == root) {
+        if fetched { return Ok(remote_root); }
+        return Err(anyhow!("Can't find root branch {} locally or on {}", root, remote));
+    }
+    if fetched {
+        git.switch(root)?;
+        git.merge(vec!["--ff-only", &remote_root])?;
+    }
+    Ok(root.to_string())
+}
+
+/// Re-fetches `review` until it shows `sha` as its head. Until the review service has seen the
+/// push, the mergeability it reports still belongs to the old commits.
+async fn await_head(cr_service: &dyn ReviewService, mut review: Review, sha: &str) -> Result<Review> {
+    let mut backoff = Backoff::new();
+    for _ in 0..BACKOFF_TIME_SECONDS.len() {
+        let Some(latest) = cr_service.review(&review.id).await? else { return Ok(review) };
+        review = latest;
+        // Services which don't report heads can't be waited on
+        if review.head.as_deref().is_none_or(|head| head == sha) { return Ok(review); }
+        backoff.wait().await;
+    }
+    Err(anyhow!("Review #{} still doesn't show {} as its head - run 'stk merge' again once it does", review.id, &sha[..sha.len().min(7)]))
+}
+
+fn has_remote_branch(git: &Git, remote: &str, branch: &str) -> bool {
+    git.rev_parse(vec![&format!("refs/remotes/{}/{}", remote, branch)]).is_ok()
+}
+
+/// Deletes the `landed` branches locally and on the remote, then moves their surviving
+/// children onto the updated `root` - created from the remote's if there's no local one - and restacks them
+async fn clean_up(git: &Git, cr_tool: &ReviewTool, remote: &str, root: &str, landed: &[String]) -> Result<()> {
+    println!("{}", "Cleaning up merged branches".green());
+    // Taken before anything changes, so 'stk abort' can put everything back
+    let snapshot = git.snapshot()?;
+    let onto = update_root(git, remote, root)?;
+    // The survivors get stacked on the root - so it has to be a local branch
+    if onto != root {
+        git.branch(vec!["--track", root, &onto])?;
+        println!("  {} {}", root.green(), format!("created from {}", onto).yellow());
+    }
+    let graph = git.stack_graph()?;
+
+    let mut to_restack = Vec::new();
+    let mut retarget = Vec::new();
+    for branch in landed.iter().filter(|b| graph.contains(b)) {
+        for child in graph.children_of(branch).into_iter().filter(|c| !landed.contains(c)) {
+            pin_base(git, &graph, &child, branch)?;
+            git.set_parent(&child, root)?;
+            retarget.push((child.clone(), root.to_string()));
+            to_restack.push(child.clone());
+            to_restack.extend(graph.descendants(&child));
+        }
+    }
+
+    // Can't delete the branch we're on
+    git.switch(root)?;
+    for branch in landed {
+        if graph.contains(branch) {
+            git.branch(vec!["-D", branch])?;
+        }
+        // The review service may have deleted it already
+        if has_remote_branch(git, remote, branch) && git.push(vec![remote, "--delete", branch]).is_ok() {
+            println!("  {} {}", branch.green(), "deleted (local and remote)".yellow());
+        } else {
+            println!("  {} {}", branch.green(), "deleted".yellow());
+        }
+    }
+
+    let mut plan = RestackPlan::new(snapshot, to_restack.clone());
+    plan.retarget = retarget;
+    // Carry on from the next branch up the stack - or the root if the whole stack landed
+    plan.finish_on = Some(to_restack.first().cloned().unwrap_or(root.to_string()));
+    restack_branches(git, Some(cr_tool), plan).await
+}
+
+/// Waits for `review` to be approved with passing checks, then merges it - either by letting the
+/// review service's own auto-merge take over, or by merging it ourselves.
+/// Returns None, after explaining why, if the review can't be merged.
+async fn merge_when_ready(cr_service: &dyn ReviewService, review: Review, options: &MergeOptions) -> Result<Option<MergeRequest>> {
+    let auto_merge = cr_service.enable_auto_merge(&review, options).await?;
+    if auto_merge { print!("{}{}", BACKSPACE, "auto-merge enabled ?".yellow()); }
+
+    let Some(review) = wait_for(cr_service, review, auto_merge).await? else { return Ok(None) };
+    match auto_merge {
+        true => Ok(Some(MergeRequest::new(review))),
+        false => Ok(Some(cr_service.merge(&review, options).await?)),
+    }
+}
+
+/// Polls `review` (with backoff) until it is ready to merge - or, with `until_merged`, until it has
+/// been merged. Returns None, after reporting why, when a check fails or the review is rejected.
+async fn wait_for(cr_service: &dyn ReviewService, mut review: Review, until_merged: bool) -> Result<Option<Review>> {
+    let mut backoff = Backoff::new();
+    let deadline = Instant::now() + WHEN_READY_TIMEOUT;
+    loop {
+        if matches!(review.state, ReviewState::Merged) { return Ok(Some(review)); }
+
+        let blockers = blockers_for(&review);
+        if !blockers.is_empty() {
+            println!("{}{} {}", BACKSPACE, CROSS.red(), blockers.join("; ").red());
+            println!("{}", "Stopping - reviews above this one were not merged".yellow());
+            return Ok(None);
+        }
+        if !until_merged && waiting_on(&review).is_empty() { return Ok(Some(review)); }
+
+        if Instant::now() >= deadline {
+            println!("{}{} {}", BACKSPACE, CROSS.red(), "timed out".red());
+            return Err(anyhow!("Gave up on review #{} after waiting {} minutes for it to be {} - run 'stk merge --when-ready' to wait again",
+                               review.id, WHEN_READY_TIMEOUT.as_secs() / 60, if until_merged { "merged" } else { "approved with passing checks" }));
+        }
+        backoff.wait().await;
+        review = cr_service.review(&review.id).await?
+            .ok_or(anyhow!("Review #{} has disappeared", review.id))?;
+    }
+}
+
+async fn track_mr_progress(cr_service: &dyn ReviewService, mr: &mut MergeRequest) -> Result<()> {
     let spinner_seq = vec!["-", "\\", "|", "/"];
     let mut spinner = spinner_seq.iter().cycle();
 
@@ -45,15 +386,15 @@ async fn track_mr_progress(mr: &mut MergeRequest) -> Result<()> {
         match mr.state {
             MergeState::Pending => {
                 print!("{}", spinner.next().unwrap().yellow());
-                mr.refresh().await?;
-                sleep(Duration::from_millis(250))
+                mr.refresh(cr_service).await?;
+                tokio::time::sleep(Duration::from_millis(250)).await;
             },
             MergeState::Merged => {
                 println!("{}", CHECK.green());
                 break;
             },
-            MergeState::Failed => {
-                println!("{}", CROSS.red());
+            MergeState::Failed(ref reason) => {
+                println!("{} {}", CROSS.red(), reason.red());
                 break;
             },
         }
@@ -61,29 +402,266 @@ async fn track_mr_progress(mr: &mut MergeRequest) -> Result<()> {
     Ok(())
 }
 
-async fn  merge_branch(cr_service: &Box<dyn ReviewService>, remote: &str, branch: &str) -> Result<Vec<Pair<String, Option<MergeRequest>>>> {
-    let git = Git::new();
-    let parent = git.parent_of(&branch, BranchType::Local)?;
-
-    // Recurse down the stack to the root branch before we continue
-    let mut merge_requests = match parent.clone()
-    {
-        Some(p) => Box::pin(merge_branch(cr_service, remote, &p)).await?,
-        None => Vec::new(),
+/// Squashes the stack's reviews down into the lowest one, merges that once and closes the rest
+/// Returns the branches which landed.
+async fn merge_top_down(git: &Git, cr_service: &dyn ReviewService, graph: &StackGraph, remote: &str, method: MergeMethod, when_ready: bool, stack: Vec<Pair<String, Review>>) -> Result<Vec<String>> {
+    let (Some(landing), Some(top)) = (stack.first(), stack.last()) else {
+        println!("  {}", "No open reviews to merge".yellow());
+        return Ok(Vec::new());
     };
+    let top_sha = graph.tip_of(&top.a).unwrap_or_default().to_string();
 
-    // Check to see if there's an open review for this branch - if so, try to merge it!
-    let existing_reviews = cr_service.reviews_for(branch).await?;
-    let merge_req = match existing_reviews.first() {
-        Some(r) => Some(r.merge().await?),
-        None => { None },
-    };
+    // Every review in the stack has to be ready - not just the one that lands
+    if when_ready {
+        for pair in &stack {
+            print!("  {}: waiting ?", pair.a.green());
+            if wait_for(cr_service, pair.b.clone(), false).await?.is_none() { return Ok(Vec::new()); }
+            println!("{}{}", BACKSPACE, CHECK.green());
+        }
+    }
+
+    // Point the lowest review's branch at the top of the stack, so it carries every change
+    if stack.len() > 1 {
+        if !git.is_ancestor(&landing.a, &top.a)? {
+            return Err(anyhow!("{} isn't stacked on the latest {} - run 'stk sync' first", top.a, landing.a));
+        }
+        println!("  Squashing {} reviews into {}", stack.len(), landing.a.green());
+        // Only replace what the review was showing - anything pushed there since would be lost
+        let expected = match &landing.b.head {
+            Some(sha) => sha.clone(),
+            None => git.rev_parse(vec![&format!("refs/remotes/{}/{}", remote, landing.b.branch)])?,
+        };
+        let lease = format!("--force-with-lease=refs/heads/{}:{}", landing.b.branch, expected);
+        git.push(vec![&lease, remote, &format!("{}:refs/heads/{}", top.a, landing.b.branch)])?;
+    }
+
+    print!("  {}: ?", landing.a.green());
+    // The review's mergeability - and its checks - only count once it shows the whole stack
+    let mut review = await_head(cr_service, landing.b.clone(), &top_sha).await?;
+    if when_ready && stack.len() > 1 {
+        let Some(ready) = wait_for(cr_service, review, false).await? else { return Ok(Vec::new()) };
+        review = ready;
+    }
+    let options = MergeOptions { method, expected_head: Some(top_sha) };
+    let mut mr = cr_service.merge(&review, &options).await?;
+    track_mr_progress(cr_service, &mut mr).await?;
+    if !matches!(mr.state, MergeState::Merged) { return Ok(Vec::new()); }
 
-    merge_requests.push(Pair {
-        a: branch.to_string(),
-        b: merge_req
-    });
+    // Everything else landed along with it
+    let link = match &landing.b.url {
+        Some(url) => url.to_string(),
+        None => format!("#{}", landing.b.id),
+    };
+    for pair in &stack[1..] {
+        cr_service.close(&pair.b, &format!("Landed as part of {}", link)).await?;
+        println!("  {}: {} {}", pair.a.green(), CHECK.green(), format!("closed - landed in {}", link).yellow());
+    }
 
-    Ok(merge_requests)
+    Ok(stack.into_iter().map(|p| p.a).collect())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use gr_reviews::{CodeReviewService, ReviewTest};
+    use crate::gr::test_repo::TestRepo;
+
+    /// A review service over the test repo's `origin`, which - like the real ones - only notices
+    /// a push when its review is next fetched
+    #[derive(Default)]
+    struct FakeReviews {
+        /// Review id -> the review as the service last saw it
+        reviews: Mutex<HashMap<String, Review>>,
+        /// The head whose checks fail
+        failing_head: Option<String>,
+        /// What was done to the reviews, in order
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl FakeReviews {
+        fn open(&self, id: &str, branch: &str, base: &str) -> Review {
+            let review = Review {
+                id: id.to_string(),
+                branch: branch.to_string(),
+                base: base.to_string(),
+                head: Some(Git::new().rev_parse(vec![branch]).unwrap()),
+                title: format!("Change {}", id),
+                body: String::new(),
+                service: CodeReviewService::None,
+                reviewers: vec![],
+                approvals: vec![],
+                state: ReviewState::Approved,
+                tests: vec![],
+                url: None,
+            };
+            self.reviews.lock().unwrap().insert(id.to_string(), review.clone());
+            review
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl ReviewService for FakeReviews {
+        async fn merge(&self, review: &Review, options: &MergeOptions) -> Result<MergeRequest> {
+            let seen = self.reviews.lock().unwrap()[&review.id].clone();
+            if options.expected_head != seen.head {
+                return Ok(MergeRequest::new(seen).in_state(MergeState::Failed("head moved".to_string())));
+            }
+            self.calls.lock().unwrap().push(format!("merge #{}", review.id));
+            Ok(MergeRequest::new(Review { state: ReviewState::Merged, ..seen }))
+        }
+
+        async fn review(&self, id: &str) -> Result<Option<Review>> {
+            let mut reviews = self.reviews.lock().unwrap();
+            let Some(review) = reviews.get_mut(id) else { return Ok(None) };
+            let head = Git::new().rev_parse(vec![&format!("refs/remotes/origin/{}", review.branch)])?;
+            review.tests = match self.failing_head.as_deref() == Some(head.as_str()) {
+                true => vec![ReviewTest { name: "ci".to_string(), state: ReviewTestState::Failed }],
+                false => vec![],
+            };
+            review.head = Some(head);
+            Ok(Some(review.clone()))
+        }
+
+        async fn reviews(&self) -> Result<Vec<Review>> { Ok(vec![]) }
+        async fn reviews_for(&self, _branch: &str) -> Result<Vec<Review>> { Ok(vec![]) }
+        async fn all_reviews_for(&self, _branch: &str) -> Result<Vec<Review>> { Ok(vec![]) }
+
+        async fn create_review(&self, _branch: &str, _parent: &str, _title: &str, _body: &str) -> Result<Review> {
+            Err(anyhow!("not needed"))
+        }
+
+        async fn retarget(&self, review: &Review, base: &str) -> Result<Review> {
+            self.calls.lock().unwrap().push(format!("retarget #{} {}", review.id, base));
+            Ok(Review { base: base.to_string(), ..review.clone() })
+        }
+
+        async fn close(&self, review: &Review, _comment: &str) -> Result<()> {
+            self.calls.lock().unwrap().push(format!("close #{}", review.id));
+            Ok(())
+        }
+
+        async fn enable_auto_merge(&self, _review: &Review, _options: &MergeOptions) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    /// main <- a <- b, both pushed to `origin` and under review as #1 and #2
+    fn reviewed_stack(repo: &TestRepo, service: &FakeReviews) -> Vec<Pair<String, Review>> {
+        repo.add_remote("origin");
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.git(&["push", "--quiet", "origin", "a", "b"]);
+        vec![Pair { a: "a".to_string(), b: service.open("1", "a", "main") },
+             Pair { a: "b".to_string(), b: service.open("2", "b", "a") }]
+    }
+
+    #[tokio::test]
+    async fn test_merge_top_down_waits_for_the_pushed_stack() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        let service = FakeReviews::default();
+        let stack = reviewed_stack(&repo, &service);
+
+        let graph = git.stack_graph().unwrap();
+        let landed = merge_top_down(&git, &service, &graph, "origin", MergeMethod::Squash, false, stack).await.unwrap();
+
+        assert_eq!(landed, vec!["a", "b"]);
+        assert_eq!(service.calls(), vec!["merge #1", "close #2"]);
+        // a's review carried the whole stack
+        assert_eq!(repo.sha("refs/remotes/origin/a"), repo.sha("b"));
+    }
+
+    #[tokio::test]
+    async fn test_merge_top_down_when_ready_checks_the_pushed_stack() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        let mut service = FakeReviews::default();
+        let stack = reviewed_stack(&repo, &service);
+        // Each review passes on its own - together they don't
+        service.failing_head = Some(repo.sha("b"));
+
+        let graph = git.stack_graph().unwrap();
+        let landed = merge_top_down(&git, &service, &graph, "origin", MergeMethod::Squash, true, stack).await.unwrap();
+
+        assert!(landed.is_empty());
+        assert!(service.calls().is_empty());
+    }
+
+    fn no_reviews() -> ReviewTool {
+        ReviewTool { service: CodeReviewService::None, remote: "origin".to_string(), credentials: None }
+    }
+
+    /// main <- a <- b <- c, with `a` squash-merged into the remote's main
+    fn stack_with_a_landed(repo: &TestRepo) {
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.stacked_branch("c", "b");
+        repo.git(&["switch", "--quiet", "--detach", "main"]);
+        repo.git(&["merge", "--quiet", "--squash", "a"]);
+        repo.git(&["commit", "--quiet", "-m", "a (#1)"]);
+        repo.git(&["push", "--quiet", "origin", "HEAD:main"]);
+        repo.git(&["switch", "--quiet", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_clean_up_restacks_survivors_onto_the_updated_root() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.add_remote("origin");
+        stack_with_a_landed(&repo);
+        repo.git(&["push", "--quiet", "origin", "a", "b"]);
+
+        clean_up(&git, &no_reviews(), "origin", "main", &["a".to_string()]).await.unwrap();
+
+        // The root caught up with the remote, a is gone everywhere, b and c only have their own commits
+        assert_eq!(repo.log("main~", "main"), vec!["a (#1)"]);
+        assert!(!git.branches().unwrap().contains(&"a".to_string()));
+        assert!(repo.git(&["ls-remote", "--heads", "origin", "a"]).is_empty());
+        assert_eq!(git.parent_of("b", BranchType::Local).unwrap().as_deref(), Some("main"));
+        assert_eq!(repo.log("main", "b"), vec!["Add b.txt"]);
+        assert_eq!(git.parent_of("c", BranchType::Local).unwrap().as_deref(), Some("b"));
+        assert_eq!(repo.log("b", "c"), vec!["Add c.txt"]);
+        assert_eq!(git.current_branch().unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_clean_up_without_a_local_root() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.add_remote("origin");
+        stack_with_a_landed(&repo);
+        repo.git(&["branch", "--quiet", "-D", "main"]);
+
+        clean_up(&git, &no_reviews(), "origin", "main", &["a".to_string()]).await.unwrap();
+
+        assert_eq!(repo.sha("main"), repo.sha("origin/main"));
+        assert_eq!(git.parent_of("b", BranchType::Local).unwrap().as_deref(), Some("main"));
+        assert_eq!(repo.log("main", "b"), vec!["Add b.txt"]);
+        assert_eq!(repo.log("b", "c"), vec!["Add c.txt"]);
+        assert_eq!(git.current_branch().unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_clean_up_after_the_whole_stack_landed() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        // Merged locally - the way reviews without a review service land
+        repo.git(&["switch", "--quiet", "main"]);
+        repo.git(&["merge", "--quiet", "--ff-only", "b"]);
+
+        clean_up(&git, &no_reviews(), "origin", "main", &["a".to_string(), "b".to_string()]).await.unwrap();
+
+        assert_eq!(git.branches().unwrap(), vec!["main"]);
+        assert_eq!(git.current_branch().unwrap(), "main");
+        assert_eq!(repo.log("main~2", "main"), vec!["Add a.txt", "Add b.txt"]);
+    }
+}
@@ -1,19 +1,27 @@
 mod init;
+pub(crate) mod auth;
 mod r#move;
-mod restack;
+pub(crate) mod restack;
 pub(crate) mod submit;
 mod merge;
 mod log;
 pub(crate) mod help;
 pub(crate) mod split;
+pub(crate) mod oplog;
+pub(crate) mod squash;
+#[cfg(test)]
+mod test_repo;
 
 /// whoops - rust really doesn't like you overriding a keyword with a module name
 
-pub use init::initialize_gr;
-pub use r#move::move_relative;
+pub use init::{initialize_gr, migrate_stack_parents};
+pub use auth::auth;
+pub use r#move::{move_relative, move_onto};
 pub use split::split;
-pub use restack::sync;
+pub use restack::{sync, sync_continue, sync_abort};
 pub use submit::submit;
 pub use submit::reviews;
 pub use merge::merge;
-pub use log::log;
\ No newline at end of file
+pub use log::log;
+pub use oplog::{oplog, record_operation, undo};
+pub use squash::{fold, squash};
\ No newline at end of file
@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use colored::Colorize;
 use candy::candy::Candy;
 use candy::events::CandyEvent::Submit;
-use gr_git::{BranchType, Git};
+use gr_git::{BranchType, Git, StackGraph};
+use gr_reviews::ReviewTool;
+use crate::gr::restack::{assert_no_plan, restack_branches, RestackPlan};
 
 pub const MOVE_USAGE: [&str; 4] = [
 "stk move <up | bu>
@@ -24,6 +26,16 @@ Move to the top of the stack - if any branch has multiple children, you will be
 select which branch to follow.",
 ];
 
+pub const REPARENT_USAGE: &str = "stk move --onto <branch> [--subtree]
+
+Moves the current branch onto a new parent branch and restacks it there.
+
+By default only the current branch moves - its children stay behind, restacked onto its old
+parent. With --subtree, every branch stacked on the current one moves along with it.
+
+Open reviews are retargeted to their branch's new parent. If a branch conflicts, resolve it
+and run 'stk continue' - or 'stk abort' to put everything back.";
+
 pub fn move_relative(command: &str) -> Result<()> {
     let git = Git::new();
     match command {
@@ -77,4 +89,150 @@ fn move_up(git: &Git) -> Result<()> {
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Moves the current branch onto `onto`. With `subtree` its descendants come along,
+/// otherwise its children are left on its old parent.
+pub async fn move_onto(onto: &str, subtree: bool, cr_tool: Option<&ReviewTool>) -> Result<()> {
+    let git = Git::new();
+    assert_no_plan(&git)?;
+
+    let graph = git.stack_graph()?;
+    let branch = graph.current_branch().to_string();
+    let old_parent = graph.parent_of(&branch, BranchType::Local);
+    let children = graph.children_of(&branch);
+    let descendants = graph.descendants(&branch);
+
+    if !graph.contains(onto) { return Err(anyhow!("No local branch named {}", onto)); }
+    if onto == branch { return Err(anyhow!("Can't move {} onto itself", branch)); }
+    if subtree && descendants.iter().any(|d| d == onto) {
+        return Err(anyhow!("Can't move {} onto {} - it is stacked on top of {}", branch, onto, branch));
+    }
+    if old_parent.as_deref() == Some(onto) {
+        println!("{} is already stacked on {}", branch.green(), onto.green());
+        return Ok(());
+    }
+
+    // Taken before any metadata changes, so 'stk abort' can put everything back
+    let snapshot = git.snapshot()?;
+
+    let mut to_restack = Vec::new();
+    let mut retargets = Vec::new();
+    if !subtree && !children.is_empty() {
+        let Some(old_parent) = &old_parent else {
+            return Err(anyhow!("{} has no parent to leave its children on - use --subtree to move them too", branch));
+        };
+        // Children go first - they still sit on top of the branch's commits
+        for child in &children {
+            pin_base(&git, &graph, child, &branch)?;
+            git.set_parent(child, old_parent)?;
+            retargets.push((child.clone(), old_parent.clone()));
+            to_restack.push(child.clone());
+            to_restack.extend(graph.descendants(child));
+        }
+    }
+
+    if let Some(old_parent) = &old_parent { pin_base(&git, &graph, &branch, old_parent)?; }
+    git.set_parent(&branch, onto)?;
+    retargets.push((branch.clone(), onto.to_string()));
+    to_restack.push(branch.clone());
+    if subtree { to_restack.extend(descendants); }
+
+    println!("Moving {} onto {}", branch.green(), onto.green());
+    let mut plan = RestackPlan::new(snapshot, to_restack);
+    plan.retarget = retargets;
+    restack_branches(&git, cr_tool, plan).await
+}
+
+/// Records where `branch` forked from `parent`, so restacking moves only its own commits
+pub(crate) fn pin_base(git: &Git, graph: &StackGraph, branch: &str, parent: &str) -> Result<()> {
+    let has_base = match graph.base_of(branch) {
+        Some(base) => git.is_ancestor(base, branch)?,
+        None => false,
+    };
+    if !has_base { git.set_base(branch, &git.merge_base(vec![parent, branch])?)?; }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gr::test_repo::TestRepo;
+
+    /// main <- a <- b, and c on main - with `a` checked out
+    fn stacks() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.stacked_branch("c", "main");
+        repo.git(&["switch", "--quiet", "a"]);
+        repo
+    }
+
+    fn parent(git: &Git, branch: &str) -> Option<String> {
+        git.parent_of(branch, BranchType::Local).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_move_onto_leaves_children_behind() {
+        let repo = stacks();
+        let git = Git::new();
+        move_onto("c", false, None).await.unwrap();
+
+        assert_eq!(parent(&git, "a").as_deref(), Some("c"));
+        assert_eq!(repo.log("c", "a"), vec!["Add a.txt"]);
+        // Only b's own commit stays - a's went along with a
+        assert_eq!(parent(&git, "b").as_deref(), Some("main"));
+        assert_eq!(repo.log("main", "b"), vec!["Add b.txt"]);
+        assert_eq!(git.current_branch().unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_move_onto_with_subtree() {
+        let repo = stacks();
+        let git = Git::new();
+        move_onto("c", true, None).await.unwrap();
+
+        assert_eq!(repo.log("c", "a"), vec!["Add a.txt"]);
+        assert_eq!(parent(&git, "b").as_deref(), Some("a"));
+        assert_eq!(repo.log("a", "b"), vec!["Add b.txt"]);
+        assert_eq!(repo.log("main", "b"), vec!["Add c.txt", "Add a.txt", "Add b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_move_onto_refuses_impossible_moves() {
+        let repo = stacks();
+        let before = repo.sha("a");
+
+        assert!(move_onto("a", false, None).await.is_err());
+        assert!(move_onto("nowhere", false, None).await.is_err());
+        assert!(move_onto("b", true, None).await.is_err());
+        assert_eq!(repo.sha("a"), before);
+        assert_eq!(parent(&Git::new(), "a").as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn test_pin_base() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        // Stacked by hand - so no base was recorded
+        repo.git(&["switch", "--quiet", "-c", "plain"]);
+        repo.commit("plain.txt");
+        git.set_parent("plain", "main").unwrap();
+        let fork = repo.sha("main");
+
+        repo.git(&["switch", "--quiet", "main"]);
+        repo.commit("later.txt");
+        pin_base(&git, &git.stack_graph().unwrap(), "plain", "main").unwrap();
+        assert_eq!(git.stack_graph().unwrap().base_of("plain"), Some(fork.as_str()));
+
+        // A base that is still in the branch's history is kept
+        let stacked = repo.stacked_branch("stacked", "main");
+        let base = repo.sha("main");
+        repo.git(&["switch", "--quiet", "main"]);
+        repo.commit("even-later.txt");
+        pin_base(&git, &git.stack_graph().unwrap(), "stacked", "main").unwrap();
+        assert_eq!(git.stack_graph().unwrap().base_of("stacked"), Some(base.as_str()));
+        assert_eq!(repo.sha("stacked"), stacked);
+    }
+}
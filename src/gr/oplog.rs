@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use candy::candy::Candy;
+use gr_git::{Git, Snapshot};
+
+pub(crate) const OPLOG_USAGE: &str = "stk oplog
+
+Lists the operations stk has recorded for this repo, newest first.
+
+Before every command which rewrites branches (create, commit, split, sync, submit, merge, ...)
+stk snapshots all local branches, their stack parents and HEAD. Use 'stk undo <id>' to return
+to the state before any of them.";
+
+pub(crate) const UNDO_USAGE: &str = "stk undo [operation id]
+
+Restores all local branches, their stack parents and HEAD to how they were before the given
+operation ran - or before the most recent one when no id is given. See 'stk oplog' for ids.
+
+Branches created since then are deleted. Undo is recorded like any other operation,
+so running 'stk undo' twice in a row redoes what was undone.";
+
+/// The log lives in the repo's git dir, next to the other per-repo gr state
+const OPLOG_FILE: &str = "oplog.toml";
+
+/// Oldest operations are dropped beyond this
+const MAX_OPERATIONS: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct Operation {
+    id: u64,
+    /// The stk command line that was about to run
+    command: String,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    /// State of the repo _before_ the command ran
+    snapshot: Snapshot,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct OpLog {
+    operations: Vec<Operation>,
+}
+
+impl OpLog {
+    fn path(git: &Git) -> Result<PathBuf> {
+        Ok(git.gr_dir()?.join(OPLOG_FILE))
+    }
+
+    fn load(git: &Git) -> Result<OpLog> {
+        let path = Self::path(git)?;
+        if !path.exists() { return Ok(OpLog::default()); }
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, git: &Git) -> Result<()> {
+        std::fs::write(Self::path(git)?, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn record(&mut self, command: &str, snapshot: Snapshot) {
+        let id = self.operations.last().map(|op| op.id + 1).unwrap_or(1);
+        self.operations.push(Operation { id, command: command.to_string(), timestamp: now(), snapshot });
+
+        let excess = self.operations.len().saturating_sub(MAX_OPERATIONS);
+        self.operations.drain(0..excess);
+    }
+}
+
+/// Snapshots the repo before a mutating `command` runs
+pub fn record_operation(command: &str) -> Result<()> {
+    let git = Git::new();
+    let mut log = OpLog::load(&git)?;
+    log.record(command, git.snapshot()?);
+    log.save(&git)
+}
+
+/// Prints the recorded operations, newest first
+pub fn oplog() -> Result<()> {
+    let git = Git::new();
+    let log = OpLog::load(&git)?;
+
+    if log.operations.is_empty() {
+        println!("{}", "No operations recorded yet".yellow());
+        return Ok(());
+    }
+
+    for op in log.operations.iter().rev() {
+        println!("{} {} {}", format!("#{:<4}", op.id).cyan(), format!("{:>8}", age(op.timestamp)).bright_black(), op.command);
+    }
+    Ok(())
+}
+
+/// Restores the repo to the state before operation `id` - or the most recent operation
+pub fn undo(id: Option<&str>) -> Result<()> {
+    let git = Git::new();
+    let candy = Candy::new();
+    let mut log = OpLog::load(&git)?;
+
+    let op = match id {
+        Some(id) => {
+            let id = id.trim_start_matches('#').parse::<u64>().map_err(|_| anyhow!("Not an operation id: {}", id))?;
+            log.operations.iter().find(|op| op.id == id).ok_or(anyhow!("No operation #{} - see 'stk oplog'", id))?
+        }
+        None => log.operations.last().ok_or(anyhow!("Nothing to undo"))?,
+    };
+
+    let current = git.snapshot()?;
+    let changes = describe_changes(&current, &op.snapshot);
+    if changes.is_empty() {
+        println!("{}", "Nothing to undo - the repo already matches that snapshot".yellow());
+        return Ok(());
+    }
+
+    println!("Restoring to before {} {} ({})", format!("#{}", op.id).cyan(), op.command, age(op.timestamp));
+    for change in &changes { println!("  {}", change); }
+    if !candy.yn("Restore?") {
+        println!("{}", "Aborted".red());
+        return Ok(());
+    }
+
+    let (op_id, snapshot) = (op.id, op.snapshot.clone());
+    // Undo is an operation too - so it can be undone
+    log.record(&format!("undo #{}", op_id), current);
+    log.save(&git)?;
+
+    git.restore(&snapshot)?;
+    println!("{}", format!("Restored to before #{}", op_id).green());
+    Ok(())
+}
+
+/// One line per branch (or HEAD) which differs between `from` and `to`
+fn describe_changes(from: &Snapshot, to: &Snapshot) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    for b in &to.branches {
+        match from.branch(&b.name) {
+            None => changes.push(format!("{}: {}", b.name.cyan(), "restored".green())),
+            Some(cur) if cur.sha != b.sha => changes.push(format!("{}: {} -> {}", b.name.cyan(), short(&cur.sha), short(&b.sha))),
+            Some(cur) if cur.parent != b.parent => changes.push(format!("{}: parent {} -> {}",
+                                                                        b.name.cyan(),
+                                                                        cur.parent.clone().unwrap_or("none".to_string()),
+                                                                        b.parent.clone().unwrap_or("none".to_string()))),
+            Some(_) => {}
+        }
+    }
+    for b in &from.branches {
+        if to.branch(&b.name).is_none() {
+            changes.push(format!("{}: {}", b.name.cyan(), "deleted".red()));
+        }
+    }
+    if from.head != to.head {
+        changes.push(format!("{}: {} -> {}", "HEAD".cyan(), from.head, to.head));
+    }
+    changes
+}
+
+fn short(sha: &str) -> String {
+    sha.chars().take(7).collect::<String>().bright_black().to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Human-readable time since `timestamp`, e.g. "5m ago"
+fn age(timestamp: u64) -> String {
+    let secs = now().saturating_sub(timestamp);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gr_git::BranchType;
+    use crate::gr::test_repo::TestRepo;
+
+    #[test]
+    fn test_recorded_operation_restores_the_repo() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.stacked_branch("feature", "main");
+        let before = repo.sha("feature");
+
+        record_operation("stk commit").unwrap();
+        repo.commit("more.txt");
+        repo.stacked_branch("extra", "feature");
+        git.set_parent("feature", "extra").unwrap();
+
+        let log = OpLog::load(&git).unwrap();
+        assert_eq!(log.operations.len(), 1);
+        let op = &log.operations[0];
+        assert_eq!((op.id, op.command.as_str()), (1, "stk commit"));
+
+        let changes = describe_changes(&git.snapshot().unwrap(), &op.snapshot);
+        assert_eq!(changes.len(), 3, "{:?}", changes);
+
+        git.restore(&op.snapshot).unwrap();
+        assert_eq!(repo.sha("feature"), before);
+        assert_eq!(git.parent_of("feature", BranchType::Local).unwrap().as_deref(), Some("main"));
+        assert!(!git.branches().unwrap().contains(&"extra".to_string()));
+        assert_eq!(git.current_branch().unwrap(), "feature");
+        assert!(describe_changes(&git.snapshot().unwrap(), &op.snapshot).is_empty());
+    }
+
+    #[test]
+    fn test_oplog_keeps_the_latest_operations() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        for i in 0..MAX_OPERATIONS + 2 {
+            let mut log = OpLog::load(&git).unwrap();
+            log.record(&format!("stk op {}", i), git.snapshot().unwrap());
+            log.save(&git).unwrap();
+        }
+
+        let log = OpLog::load(&git).unwrap();
+        assert_eq!(log.operations.len(), MAX_OPERATIONS);
+        assert_eq!(log.operations[0].id, 3);
+        assert_eq!(log.operations.last().unwrap().id, MAX_OPERATIONS as u64 + 2);
+        assert!(repo.dir.join(".git/gr").join(OPLOG_FILE).exists());
+    }
+}
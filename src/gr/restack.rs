@@ -1,15 +1,54 @@
-use anyhow::Result;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use anyhow::{anyhow, Result};
 use colored::Colorize;
+use serde::{Deserialize, Serialize};
 use candy::candy::Candy;
 use candy::symbols::{CHECK, CROSS};
-use gr_git::{BranchType, ExecGit, Git};
+use gr_git::{BranchType, Git, Snapshot, StackGraph};
+use gr_reviews::{review_service_for, ReviewService, ReviewTool, ReviewState};
 
+pub(crate) const CONTINUE_USAGE: &str = "stk continue
+
+Resumes a sync or move which stopped on a conflict.
+Resolve the conflicts and 'git add' the results first - stk finishes the rebase and then
+restacks the rest of the stack.";
+
+pub(crate) const ABORT_USAGE: &str = "stk abort
+
+Cancels a sync or move which stopped on a conflict, returning every branch in the stack
+to where it was before the command began.";
+
+#[derive(Serialize, Deserialize)]
 enum SyncStatus {
     Success,
     NoDiff,
+    Merged(MergedBy),
     ConflictWith(String),
 }
 
+/// How we figured out that a branch already landed in its parent
+#[derive(Serialize, Deserialize)]
+enum MergedBy {
+    /// Every commit has a patch-id equivalent in the parent (rebase / cherry-pick merges)
+    PatchId,
+    /// The branch's combined diff is a single commit in the parent (squash merges)
+    SquashedDiff,
+    /// The review service says so
+    Review,
+}
+
+impl Display for MergedBy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergedBy::PatchId => write!(f, "commits found in parent"),
+            MergedBy::SquashedDiff => write!(f, "squashed into parent"),
+            MergedBy::Review => write!(f, "review merged"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 struct Pair<A, B> {
     a: A,
     b: B,
@@ -23,48 +62,209 @@ impl SyncResult {
     pub fn status(&self) -> &SyncStatus { &self.b }
 }
 
-pub fn sync() -> Result<()> {
+/// A sync in progress, persisted so it can be resumed (or rolled back) after a conflict
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RestackPlan {
+    /// The repo before the sync began - `stk abort` returns here
+    snapshot: Snapshot,
+    /// Branches still to sync, parents first. The first one is being worked on.
+    remaining: Vec<String>,
+    /// Outcomes for the branches already synced
+    results: Vec<SyncResult>,
+    /// Reviews to point at a new base once every branch is restacked: (branch, new base)
+    #[serde(default)]
+    pub(crate) retarget: Vec<(String, String)>,
+    /// Reviews to close once every branch is restacked: (branch, branch it was folded into)
+    #[serde(default)]
+    pub(crate) close: Vec<(String, String)>,
+    /// Branch to check out when done - when not the one we started on
+    #[serde(default)]
+    pub(crate) finish_on: Option<String>,
+}
+
+impl RestackPlan {
+    /// Restacks `branches` (parents first). `snapshot` is what `stk abort` restores,
+    /// so take it before changing any metadata.
+    pub(crate) fn new(snapshot: Snapshot, branches: Vec<String>) -> RestackPlan {
+        RestackPlan { snapshot, remaining: branches, results: Vec::new(), retarget: Vec::new(), close: Vec::new(), finish_on: None }
+    }
+
+    /// The plan lives in the repo's git dir, next to the other per-repo gr state
+    fn path(git: &Git) -> Result<PathBuf> {
+        Ok(git.gr_dir()?.join("restack.toml"))
+    }
+
+    fn load(git: &Git) -> Result<Option<RestackPlan>> {
+        let path = Self::path(git)?;
+        if !path.exists() { return Ok(None); }
+        Ok(Some(toml::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    fn save(&self, git: &Git) -> Result<()> {
+        std::fs::write(Self::path(git)?, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn clear(git: &Git) -> Result<()> {
+        let path = Self::path(git)?;
+        if path.exists() { std::fs::remove_file(path)?; }
+        Ok(())
+    }
+}
+
+/// Syncs the current stack. `cr_tool` is optional - without it, merged branches are
+/// only detected from git history.
+pub async fn sync(cr_tool: Option<&ReviewTool>) -> Result<()> {
     let git = Git::new();
+    assert_no_plan(&git)?;
 
-    // Get the current branch
-    let branch = git.current_branch()?;
+    // Sync the current branch's whole stack - parents before children
+    let graph = git.stack_graph()?;
+    let plan = RestackPlan::new(git.snapshot()?, graph.current_stack());
 
-    // Sync the current branch (and its parents)
-    let results = pull_and_rebase(branch.as_str())?;
-    for res in &results { println!("{}: {}", res.branch().green(), sync_result_to_status_char(res.status())); }
-    println!();
-    // Try to fix any conflicts first
-    for res in &results {
-        match res.status() {
-            SyncStatus::ConflictWith(b) => { ask_to_fix_conflicts(b, res.branch())?; }
-            _ => continue
+    run_plan(&git, cr_tool, plan).await
+}
+
+/// Runs `plan` like sync does - stopping for `stk continue` / `stk abort` on conflicts
+pub(crate) async fn restack_branches(git: &Git, cr_tool: Option<&ReviewTool>, plan: RestackPlan) -> Result<()> {
+    run_plan(git, cr_tool, plan).await
+}
+
+/// Only one restack at a time - a half-finished one has to be continued or aborted first
+pub(crate) fn assert_no_plan(git: &Git) -> Result<()> {
+    if RestackPlan::load(git)?.is_some() {
+        return Err(anyhow!("A sync is already in progress - resolve it with 'stk continue' or 'stk abort'"));
+    }
+    Ok(())
+}
+
+/// Resumes a sync which stopped on a conflict
+pub async fn sync_continue(cr_tool: Option<&ReviewTool>) -> Result<()> {
+    let git = Git::new();
+    let Some(mut plan) = RestackPlan::load(&git)? else { return Err(anyhow!("No sync in progress")) };
+
+    if git.rebase_in_progress()? {
+        git.rebase_continue()?;
+    }
+
+    // Finish the bookkeeping for the branch which conflicted - unless its rebase never
+    // completed (e.g. the user aborted it by hand), in which case we just try again.
+    if let Some(branch) = plan.remaining.first().cloned() {
+        match git.parent_of(&branch, BranchType::Local)? {
+            Some(parent) if git.is_ancestor(&parent, &branch)? => {
+                git.set_base(&branch, &git.rev_parse(vec![&parent])?)?;
+                let status = diff_status(&git, &branch, &parent)?;
+                plan.results.push(SyncResult::new(&branch, status));
+                plan.remaining.remove(0);
+            }
+            Some(_) => {}
+            None => {
+                plan.results.push(SyncResult::new(&branch, SyncStatus::Success));
+                plan.remaining.remove(0);
+            }
         }
     }
 
-    // Okay, if conflicts are all resolved, then delete merged branches
-    for res in &results {
+    run_plan(&git, cr_tool, plan).await
+}
+
+/// Cancels a sync which stopped on a conflict, restoring every branch to its pre-sync state
+pub fn sync_abort() -> Result<()> {
+    let git = Git::new();
+    let Some(plan) = RestackPlan::load(&git)? else { return Err(anyhow!("No sync in progress")) };
+
+    if git.rebase_in_progress()? {
+        git.rebase(vec!["--abort"])?;
+    }
+    git.restore(&plan.snapshot)?;
+    RestackPlan::clear(&git)?;
+
+    println!("{}", "Sync aborted - all branches restored".green());
+    Ok(())
+}
+
+/// Works through the plan's remaining branches, stopping (and saving the plan) on conflicts
+async fn run_plan(git: &Git, cr_tool: Option<&ReviewTool>, mut plan: RestackPlan) -> Result<()> {
+    let graph = git.stack_graph()?;
+    let cr_service = match cr_tool {
+        Some(tool) => Some(review_service_for(tool)?),
+        None => None,
+    };
+
+    while let Some(branch) = plan.remaining.first().cloned() {
+        let status = pull_and_rebase(git, &graph, &cr_service, &branch).await?;
+
+        if matches!(status, SyncStatus::ConflictWith(_)) {
+            // Leave the rebase stopped for the user - and remember where we were
+            plan.save(git)?;
+            for res in &plan.results { println!("{}: {}", res.branch().green(), sync_result_to_status_char(res.status())); }
+            println!("{}: {}", branch.green(), sync_result_to_status_char(&status));
+            println!();
+            ask_to_fix_conflicts(&branch, &plan.remaining[1..]);
+            return Ok(());
+        }
+        plan.results.push(SyncResult::new(&branch, status));
+        plan.remaining.remove(0);
+    }
+    RestackPlan::clear(git)?;
+
+    for res in &plan.results { println!("{}: {}", res.branch().green(), sync_result_to_status_char(res.status())); }
+    println!();
+
+    // Back to where the user started
+    match &plan.finish_on {
+        Some(branch) => git.switch(branch)?,
+        None if !plan.snapshot.detached && git.branches()?.contains(&plan.snapshot.head) => git.switch(&plan.snapshot.head)?,
+        None => {}
+    }
+
+    if let Some(service) = &cr_service {
+        update_reviews(service.as_ref(), &plan).await?;
+    }
+
+    // Okay, conflicts are all resolved, so delete merged branches
+    for res in &plan.results {
         match res.status() {
-            SyncStatus::NoDiff => ask_to_delete(res.branch())?,
+            SyncStatus::NoDiff => ask_to_delete(res.branch(), false)?,
+            // squash-merged commits aren't in the parent's history - git needs convincing
+            SyncStatus::Merged(_) => ask_to_delete(res.branch(), true)?,
             _ => continue
         }
     }
 
+    println!("{}", "Complete".green());
     Ok(())
 }
 
-fn ask_to_fix_conflicts(parent: &str, branch: &str) -> Result<()> {
-    let git = Git::new();
-    let exec_git = ExecGit::new();
-
-    println!("Could not rebase branch {} <- {}", parent.green(), branch.red());
-    println!("Please fix the conflicts, then attempt to sync again.");
-    git.switch(branch)?;
-    exec_git.pull(vec!["--rebase".to_string()])?;
+/// Applies the review changes the plan's command asked for
+async fn update_reviews(service: &dyn ReviewService, plan: &RestackPlan) -> Result<()> {
+    for (branch, base) in &plan.retarget {
+        for review in service.reviews_for(branch).await? {
+            if review.base == *base { continue; }
+            service.retarget(&review, base).await?;
+            println!("Review for {} now targets {}", branch.cyan(), base.green());
+        }
+    }
 
+    for (branch, folded_into) in &plan.close {
+        for review in service.reviews_for(branch).await? {
+            service.close(&review, &format!("Folded into {} - this change continues in its review.", folded_into)).await?;
+            println!("Closed review for {} (folded into {})", branch.cyan(), folded_into.green());
+        }
+    }
     Ok(())
 }
 
-fn ask_to_delete(branch: &str) -> Result<()> {
+fn ask_to_fix_conflicts(branch: &str, waiting: &[String]) {
+    println!("Could not restack branch {}", branch.red());
+    if !waiting.is_empty() {
+        println!("Still to restack: {}", waiting.join(", ").yellow());
+    }
+    println!("Fix the conflicts and 'git add' them, then run {} - or {} to undo the whole sync.",
+             "stk continue".green(), "stk abort".red());
+}
+
+fn ask_to_delete(branch: &str, force: bool) -> Result<()> {
     let git = Git::new();
     let parent = match git.parent_of(branch, BranchType::Local)?{
         Some(p) => p,
@@ -77,14 +277,18 @@ fn ask_to_delete(branch: &str) -> Result<()> {
         // Get our children and rebase them onto our parent
         let children = git.children_of(branch)?;
         for child in &children {
-            git.switch(child)?;
-            git.branch(vec![&format!("--set-upstream-to={}", &parent)])?;
+            git.set_parent(child, &parent)?;
+            // Recorded bases mean only the child's own commits move
+            if git.recursive_rebase(child, vec![]).is_err() {
+                git.rebase(vec!["--abort"])?;
+                println!("Could not restack {} onto {} - run sync again to resolve", child.red(), parent.green());
+            }
         }
         // Move to an _only_ child OR our parent
         if children.len() == 1 { git.switch(&children.get(0).unwrap())?; }
         else { git.switch(&parent)?; }
         // Delete the branch
-        git.branch(vec!["-d", branch])?;
+        git.branch(vec![if force { "-D" } else { "-d" }, branch])?;
     }
     Ok(())
 }
@@ -93,58 +297,208 @@ fn sync_result_to_status_char(status: &SyncStatus) -> String {
     match status {
         SyncStatus::Success => CHECK.green(),
         SyncStatus::NoDiff => CHECK.green(),
+        SyncStatus::Merged(by) => format!("{} {}", CHECK.green(), format!("merged ({})", by).yellow()).normal(),
         SyncStatus::ConflictWith(_) => CROSS.red(),
     }.to_string()
 }
 
-fn pull_and_rebase(branch: &str) -> Result<Vec<SyncResult>> {
-    let git = Git::new();
+/// Syncs a single branch: roots pull from their remote, stacked branches restack onto their parent
+async fn pull_and_rebase(git: &Git, graph: &StackGraph, cr_service: &Option<Box<dyn ReviewService>>, branch: &str) -> Result<SyncStatus> {
+    let parent = graph.parent_of(branch, BranchType::Local);
 
-    // Is 'branch' local, or remote?
-    let branch_is_remote = git.remotes()?.into_iter().any(|remote| branch.starts_with(&remote));
+    // Branches that already landed in their parent would only conflict when restacked - flag them instead
+    if let Some(p) = &parent {
+        if let Some(by) = detect_merge(git, graph, cr_service, branch, p).await? {
+            return Ok(SyncStatus::Merged(by));
+        }
+    }
 
-    // If 'branch' is remote, return - we don't need to sync remote branches!
-    if branch_is_remote { return Ok(vec![SyncResult::new(branch, SyncStatus::Success)]); }
+    // Okay, we're ready to sync - checkout 'branch' and update it!
+    git.switch(branch)?;
+    let rebase_res = match &parent {
+        Some(_) => git.restack(branch),
+        None if graph.upstream_of(branch).is_some() => git.pull(vec!["--rebase"]).map(|_| ()),
+        None => Ok(()),
+    };
 
-    // Next step requires recursing to our parent - if one is present
-    let parent = git.parent_of(branch, BranchType::Local)?;
+    if let Err(e) = rebase_res {
+        // Anything other than a rebase stopped on conflicts is a real failure
+        if !git.rebase_in_progress()? { return Err(e); }
+        let onto = parent.or(graph.upstream_of(branch)).unwrap_or_default();
+        return Ok(SyncStatus::ConflictWith(onto));
+    }
 
-    // Update parent if any
-    let mut results = match &parent {
-        None => vec![],  // No parent - we're at the bottom and ready to begin!
-        Some(onto) => pull_and_rebase(onto)?  // Recurse to sync our parent!
-    };
+    match &parent {
+        Some(p) => diff_status(git, branch, p),
+        None => Ok(SyncStatus::Success),
+    }
+}
 
-    // Okay, we're ready to sync - checkout 'branch' and update it!
-    git.switch(branch)?;
-    let pull_res = git.pull(vec!["--rebase"]);
-    let rebase_res = git.rebase(vec![]);
+/// Success - unless the branch has no commits of its own left, so we can probably delete it
+fn diff_status(git: &Git, branch: &str, parent: &str) -> Result<SyncStatus> {
+    let is_different = git.commit_diff(branch, parent)?
+        .split("\n")
+        .filter(|s| !s.is_empty())
+        .count() > 0;
 
-    if pull_res.is_err() || rebase_res.is_err() {
-        // reset git to 'good' state
-        git.rebase(vec!["--abort"])?;
-        results.push(SyncResult::new(branch, SyncStatus::ConflictWith(parent.unwrap().to_string())));
-    } else {
-        // Success! Check to see if there's any diff post-merge
-        let is_different = match &parent {
-            None => { false }
-            Some(p) => {
-                git.commit_diff(branch, p)?
-                    .split("\n")
-                    .filter(|s| !s.is_empty())
-                    .count() > 0
-            }
-        };
+    if is_different { Ok(SyncStatus::Success) } else { Ok(SyncStatus::NoDiff) }
+}
+
+/// Checks whether `branch` has already landed in `parent` - even if its commits were
+/// rebased or squashed on the way, which plain ancestry checks can't see.
+async fn detect_merge(git: &Git, graph: &StackGraph, cr_service: &Option<Box<dyn ReviewService>>, branch: &str, parent: &str) -> Result<Option<MergedBy>> {
+    // Nothing to detect - an empty branch is handled as 'NoDiff' after restacking
+    let cherries = git.cherry(vec![parent, branch])?;
+    if cherries.is_empty() { return Ok(None); }
+
+    // 1. Every commit has a patch-equivalent twin in the parent
+    if cherries.lines().all(|l| l.starts_with('-')) { return Ok(Some(MergedBy::PatchId)); }
 
-        if is_different {
-            results.push(SyncResult::new(branch, SyncStatus::Success));
-        } else {
-            // Success - but no diff, so we can probably delete this branch.
-            results.push(SyncResult::new(branch, SyncStatus::NoDiff));
+    // 2. Squash the branch into a single (unreferenced) commit and look for its twin instead
+    let base = match graph.base_of(branch).filter(|b| git.is_ancestor(b, branch).unwrap_or(false)) {
+        Some(base) => base.to_string(),
+        None => git.merge_base(vec![parent, branch])?,
+    };
+    let tree = format!("{}^{{tree}}", branch);
+    let squashed = git.commit_tree(vec![&tree, "-p", &base, "-m", &format!("squashed {}", branch)])?;
+    // Limited to commits after `base` - otherwise unrelated history shows up too, should `base` not be in the parent
+    let twin = format!("- {}", squashed);
+    if git.cherry(vec![parent, &squashed, &base])?.lines().any(|l| l == twin) { return Ok(Some(MergedBy::SquashedDiff)); }
+
+    // 3. Fall back to asking the review service - e.g. the merge was amended on the way in.
+    // Only a review of the branch as it is now counts: the name may have been reused, or commits added since.
+    if let Some(service) = cr_service {
+        let tip = git.rev_parse(vec![branch])?;
+        let reviews = service.all_reviews_for(branch).await?;
+        if reviews.iter().any(|r| matches!(r.state, ReviewState::Merged) && r.head.as_deref() == Some(tip.as_str())) {
+            return Ok(Some(MergedBy::Review));
         }
     }
 
-
-    Ok(results)
+    Ok(None)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gr::test_repo::TestRepo;
+
+    /// `feature` stacked on `main`, both changing the same file - so syncing stops on a conflict
+    async fn stopped_sync(repo: &TestRepo) -> String {
+        repo.stacked_branch("feature", "main");
+        let feature = repo.commit_file("shared.txt", "feature");
+        repo.git(&["switch", "--quiet", "main"]);
+        repo.commit_file("shared.txt", "main");
+        repo.git(&["switch", "--quiet", "feature"]);
+
+        sync(None).await.unwrap();
+        feature
+    }
+
+    /// main <- feature with three commits, and main moved on since
+    fn feature_with_commits(repo: &TestRepo) {
+        repo.stacked_branch("feature", "main");
+        repo.commit("two");
+        repo.commit("three");
+        repo.git(&["switch", "--quiet", "main"]);
+        repo.commit("unrelated");
+    }
+
+    async fn merged_by(git: &Git, branch: &str) -> Option<MergedBy> {
+        let graph = git.stack_graph().unwrap();
+        detect_merge(git, &graph, &None, branch, "main").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_detect_rebase_merge() {
+        let repo = TestRepo::new();
+        feature_with_commits(&repo);
+        repo.git(&["cherry-pick", "main..feature"]);
+
+        assert!(matches!(merged_by(&Git::new(), "feature").await, Some(MergedBy::PatchId)));
+    }
+
+    #[tokio::test]
+    async fn test_detect_squash_merge() {
+        let repo = TestRepo::new();
+        feature_with_commits(&repo);
+        repo.git(&["merge", "--squash", "feature"]);
+        repo.git(&["commit", "--quiet", "-m", "Feature (#1)"]);
+
+        assert!(matches!(merged_by(&Git::new(), "feature").await, Some(MergedBy::SquashedDiff)));
+    }
+
+    #[tokio::test]
+    async fn test_unmerged_branch_is_kept() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        feature_with_commits(&repo);
+        // Only some of the branch landed
+        repo.git(&["cherry-pick", "feature~2"]);
+        assert!(merged_by(&git, "feature").await.is_none());
+
+        repo.git(&["switch", "--quiet", "feature"]);
+        sync(None).await.unwrap();
+        assert!(git.branches().unwrap().contains(&"feature".to_string()));
+        assert_eq!(repo.log("main", "feature"), vec!["Add two", "Add three"]);
+    }
+
+    #[test]
+    fn test_plan_round_trip() {
+        let _repo = TestRepo::new();
+        let git = Git::new();
+        assert!(RestackPlan::load(&git).unwrap().is_none());
+
+        let mut plan = RestackPlan::new(git.snapshot().unwrap(), vec!["a".to_string(), "b".to_string()]);
+        plan.retarget = vec![("a".to_string(), "main".to_string())];
+        plan.close = vec![("b".to_string(), "a".to_string())];
+        plan.finish_on = Some("a".to_string());
+        plan.save(&git).unwrap();
+        assert!(assert_no_plan(&git).is_err());
+
+        let loaded = RestackPlan::load(&git).unwrap().unwrap();
+        assert_eq!(loaded.remaining, vec!["a", "b"]);
+        assert_eq!(loaded.retarget, plan.retarget);
+        assert_eq!(loaded.close, plan.close);
+        assert_eq!(loaded.finish_on.as_deref(), Some("a"));
+        assert_eq!(loaded.snapshot.head, "main");
+
+        RestackPlan::clear(&git).unwrap();
+        assert!(RestackPlan::load(&git).unwrap().is_none());
+        assert!(assert_no_plan(&git).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sync_abort_restores_the_stack() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        let feature = stopped_sync(&repo).await;
+
+        let plan = RestackPlan::load(&git).unwrap().expect("the conflict should have saved the plan");
+        assert_eq!(plan.remaining, vec!["feature"]);
+        assert!(git.rebase_in_progress().unwrap());
+
+        sync_abort().unwrap();
+        assert!(!git.rebase_in_progress().unwrap());
+        assert!(RestackPlan::load(&git).unwrap().is_none());
+        assert_eq!(repo.sha("feature"), feature);
+        assert_eq!(git.current_branch().unwrap(), "feature");
+    }
+
+    #[tokio::test]
+    async fn test_sync_continue_finishes_the_stack() {
+        let repo = TestRepo::new();
+        let git = Git::new();
+        stopped_sync(&repo).await;
+
+        std::fs::write(repo.dir.join("shared.txt"), "resolved").unwrap();
+        repo.git(&["add", "shared.txt"]);
+        sync_continue(None).await.unwrap();
+
+        assert!(RestackPlan::load(&git).unwrap().is_none());
+        assert!(git.is_ancestor("main", "feature").unwrap());
+        assert_eq!(git.stack_graph().unwrap().base_of("feature"), Some(repo.sha("main").as_str()));
+        assert_eq!(std::fs::read_to_string(repo.dir.join("shared.txt")).unwrap(), "resolved");
+        assert_eq!(git.current_branch().unwrap(), "feature");
+    }
+}
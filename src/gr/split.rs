@@ -59,7 +59,7 @@ pub fn split() -> Result<()> {
             for (i, branch) in branches.iter().enumerate() {
                 // Basing off of "parent", create a new branch and cherry pick the necessary commits over
                 let branch_name = format!("{}-{}", cur_branch, i + 1);
-                git.checkout(vec!["-t", &parent, "-b", &branch_name])?;
+                git.create_branch(&branch_name, &parent)?;
                 for commit in branch {
                     git.cherry_pick(vec![&commit.sha])?;
                 }
@@ -69,8 +69,7 @@ pub fn split() -> Result<()> {
             // Next, rebase the original branch's children onto our final branch (now the "parent")
             let children = git.children_of(&cur_branch)?;
             for child in &children {
-                git.switch(child)?;
-                git.branch(vec![&format!("--set-upstream-to={}", &parent)])?;
+                git.set_parent(child, &parent)?;
             }
 
             // Now, delete the original branch
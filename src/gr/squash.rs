@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use gr_git::{BranchType, Git};
+use gr_reviews::ReviewTool;
+use crate::gr::r#move::pin_base;
+use crate::gr::restack::{assert_no_plan, restack_branches, RestackPlan};
+
+pub(crate) const FOLD_USAGE: &str = "stk fold
+
+Folds the current branch into its parent: the parent takes on the branch's commits and the
+branch is deleted. Children of the folded branch, and the parent's other children, are
+restacked onto the parent, and the folded branch's open review is closed. Run 'stk submit' afterwards to update the parent's review.";
+
+pub(crate) const SQUASH_USAGE: &str = "stk squash <from>..<to>
+
+Combines a run of stacked branches into one. <from> must be a stack ancestor of <to>;
+<from> takes on every commit up to <to> and the branches above it are deleted.
+
+Branches stacked on any of the deleted ones - or on <from> - are restacked onto <from>, and the
+deleted branches' open reviews are closed. Run 'stk submit' afterwards to update <from>'s review.";
+
+/// Folds the current branch into its parent
+pub async fn fold(cr_tool: Option<&ReviewTool>) -> Result<()> {
+    let git = Git::new();
+    let branch = git.current_branch()?;
+    let parent = git.parent_of(&branch, BranchType::Local)?
+        .ok_or(anyhow!("{} has no parent to fold into", branch))?;
+
+    squash_run(&git, &parent, &branch, cr_tool).await
+}
+
+/// Combines the stack branches `from..to` into `from`
+pub async fn squash(range: &str, cr_tool: Option<&ReviewTool>) -> Result<()> {
+    let git = Git::new();
+    let (from, to) = range.split_once("..")
+        .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+        .ok_or(anyhow!("Expected a range like <from>..<to>, got '{}'", range))?;
+
+    squash_run(&git, from, to, cr_tool).await
+}
+
+async fn squash_run(git: &Git, from: &str, to: &str, cr_tool: Option<&ReviewTool>) -> Result<()> {
+    assert_no_plan(git)?;
+    let graph = git.stack_graph()?;
+
+    for b in [from, to] {
+        if !graph.contains(b) { return Err(anyhow!("No local branch named {}", b)); }
+    }
+
+    // The run, bottom up: from, ..., to
+    let ancestors = graph.ancestors(to);
+    let Some(from_idx) = ancestors.iter().position(|a| a == from) else {
+        return Err(anyhow!("{} is not stacked below {}", from, to));
+    };
+    let mut run = ancestors[..=from_idx].to_vec();
+    run.reverse();
+    run.push(to.to_string());
+
+    // Fast-forwarding only works when every branch already sits on top of its parent
+    for pair in run.windows(2) {
+        if !git.is_ancestor(&pair[0], &pair[1])? {
+            return Err(anyhow!("{} isn't stacked on the latest {} - run 'stk sync' first", pair[1], pair[0]));
+        }
+    }
+
+    // Taken before anything changes, so 'stk abort' can put everything back
+    let snapshot = git.snapshot()?;
+    let folded = &run[1..];
+
+    // Branches hanging off the run (other than the run itself) move onto the result
+    let mut to_restack = Vec::new();
+    let mut retarget = Vec::new();
+    for b in folded {
+        for child in graph.children_of(b).into_iter().filter(|c| !folded.contains(c)) {
+            pin_base(git, &graph, &child, b)?;
+            git.set_parent(&child, from)?;
+            retarget.push((child.clone(), from.to_string()));
+            to_restack.push(child.clone());
+            to_restack.extend(graph.descendants(&child));
+        }
+    }
+    // from's other children stay on it - but it's about to take on the run's commits
+    for sibling in graph.children_of(from).into_iter().filter(|c| *c != run[1]) {
+        pin_base(git, &graph, &sibling, from)?;
+        to_restack.push(sibling.clone());
+        to_restack.extend(graph.descendants(&sibling));
+    }
+
+    git.switch(from)?;
+    git.merge(vec!["--ff-only", to])?;
+    for b in folded {
+        git.branch(vec!["-D", b])?;
+        println!("Folded {} into {}", b.yellow(), from.green());
+    }
+
+    let mut plan = RestackPlan::new(snapshot, to_restack);
+    plan.retarget = retarget;
+    plan.close = folded.iter().map(|b| (b.clone(), from.to_string())).collect();
+    plan.finish_on = Some(from.to_string());
+    restack_branches(git, cr_tool, plan).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gr::test_repo::TestRepo;
+
+    fn parent(git: &Git, branch: &str) -> Option<String> {
+        git.parent_of(branch, BranchType::Local).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fold_into_the_parent() {
+        // main <- a <- b <- c, and s on a - with b checked out
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.stacked_branch("c", "b");
+        repo.stacked_branch("s", "a");
+        repo.git(&["switch", "--quiet", "b"]);
+
+        fold(None).await.unwrap();
+
+        assert!(!git.branches().unwrap().contains(&"b".to_string()));
+        assert_eq!(repo.log("main", "a"), vec!["Add a.txt", "Add b.txt"]);
+        assert_eq!(parent(&git, "c").as_deref(), Some("a"));
+        assert_eq!(repo.log("a", "c"), vec!["Add c.txt"]);
+        assert_eq!(parent(&git, "s").as_deref(), Some("a"));
+        assert_eq!(repo.log("a", "s"), vec!["Add s.txt"]);
+        assert_eq!(git.current_branch().unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_squash_a_run() {
+        // main <- a <- b <- c <- d, with e on b and s on a
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.stacked_branch("c", "b");
+        repo.stacked_branch("d", "c");
+        repo.stacked_branch("e", "b");
+        repo.stacked_branch("s", "a");
+
+        squash("a..c", None).await.unwrap();
+
+        let branches = git.branches().unwrap();
+        assert!(!branches.contains(&"b".to_string()) && !branches.contains(&"c".to_string()));
+        assert_eq!(repo.log("main", "a"), vec!["Add a.txt", "Add b.txt", "Add c.txt"]);
+        // Whatever hung off the run - or off `a` itself - now sits on the squashed `a`
+        for child in ["d", "e", "s"] {
+            assert_eq!(parent(&git, child).as_deref(), Some("a"));
+            assert_eq!(repo.log("a", child), vec![format!("Add {}.txt", child)]);
+        }
+        assert_eq!(git.current_branch().unwrap(), "a");
+        assert!(assert_no_plan(&git).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_squash_refuses_bad_runs() {
+        // main <- a <- b and main <- c, with a commit on a that b doesn't have yet
+        let repo = TestRepo::new();
+        let git = Git::new();
+        repo.stacked_branch("a", "main");
+        repo.stacked_branch("b", "a");
+        repo.stacked_branch("c", "main");
+        repo.git(&["switch", "--quiet", "a"]);
+        repo.commit("later");
+        let (a, b) = (repo.sha("a"), repo.sha("b"));
+
+        for range in ["a", "a..", "..b", "a...b"] {
+            assert!(squash(range, None).await.is_err(), "{}", range);
+        }
+        // Not below - or not in the same stack at all
+        assert!(squash("b..a", None).await.is_err());
+        assert!(squash("c..b", None).await.is_err());
+        assert!(squash("a..nowhere", None).await.is_err());
+        // b needs syncing first
+        assert!(squash("a..b", None).await.is_err());
+
+        assert_eq!((repo.sha("a"), repo.sha("b")), (a, b));
+        assert_eq!(parent(&git, "b").as_deref(), Some("a"));
+        assert_eq!(git.current_branch().unwrap(), "a");
+    }
+}
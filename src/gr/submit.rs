@@ -1,10 +1,10 @@
 
-use gr_reviews::{CodeReviewService, review_service_for};
+use gr_reviews::{review_service_for, ReviewTool};
 use gr_reviews::ReviewService;
 use gr_reviews::Review;
 use anyhow::{anyhow, Result};
 use colored::Colorize;
-use gr_git::Git;
+use gr_git::{Git, StackGraph};
 use gr_git::BranchType;
 use itertools::Itertools;
 use candy::symbols::SMALL_SQUARE;
@@ -14,20 +14,21 @@ use candy::events::CandyEvent::Submit;
 use crate::indent::Indentable;
 
 /// Retrieves the list of reviews for the current repo
-pub async fn reviews(cr_tool: &CodeReviewService) -> Result<Vec<Review>> {
+pub async fn reviews(cr_tool: &ReviewTool) -> Result<Vec<Review>> {
     let service = review_service_for(cr_tool)?;
     service.reviews().await
 }
 
 /// Creates / Updates code reviews for the current stack of branches
 /// e.g. the current branch and all of its ancestors down to the root
-pub async fn submit(cr_tool: &CodeReviewService, remote: &str) -> Result<()> {
+pub async fn submit(cr_tool: &ReviewTool, remote: &str) -> Result<()> {
     let git = Git::new();
+    let graph = git.stack_graph()?;
     let cr_service = review_service_for(cr_tool)?;
 
     println!("{}", "Submitting stack".green());
     // recursively, from the lowest branch, submit our branches
-    let reviews = submit_branch(&cr_service, remote, &git.current_branch()?).await?;
+    let reviews = submit_branch(&cr_service, &graph, remote, graph.current_branch()).await?;
 
     for rv in reviews {
         println!("  {}: {}", rv.id.cyan(), rv.url.unwrap().to_string().green());
@@ -36,18 +37,17 @@ pub async fn submit(cr_tool: &CodeReviewService, remote: &str) -> Result<()> {
    Ok(())
 }
 
-async fn submit_branch(cr_service: &Box<dyn ReviewService>, remote: &str, branch: &str) -> Result<Vec<Review>> {
-    let git = Git::new();
-    let parent = git.parent_of(&branch, BranchType::Local)?;
+async fn submit_branch(cr_service: &Box<dyn ReviewService>, graph: &StackGraph, remote: &str, branch: &str) -> Result<Vec<Review>> {
+    let parent = graph.parent_of(&branch, BranchType::Local);
 
     // Recurse down the stack to the root branch before we continue
     let mut reviews = match parent.clone()
     {
-        Some(p) => Box::pin(submit_branch(cr_service, remote, &p)).await?,
+        Some(p) => Box::pin(submit_branch(cr_service, graph, remote, &p)).await?,
         None => Vec::new(),
     };
     // Return unless we have a diff vs our parent to submit
-    if !needs_submitting(branch)? {
+    if !needs_submitting(graph, branch)? {
         return Ok(reviews);
     }
 
@@ -56,8 +56,8 @@ async fn submit_branch(cr_service: &Box<dyn ReviewService>, remote: &str, branch
 
     // TODO: Spinner while we push the branch to the remote and create the PR
 
-    // 1. push to the remote, creating a remote branch!
-    push_branch(remote, branch)?;
+    // 1. push to the remote, creating a remote branch - or whatever the review service reviews
+    cr_service.publish(remote, branch, parent.as_deref().unwrap_or_default()).await?;
 
     // 2a. Check to see if there's a PR for this branch
     let existing_reviews = cr_service.reviews_for(branch).await?;
@@ -100,20 +100,9 @@ pub(crate) fn get_commit_message(branch: &str, parent: &str) -> Result<Vec<Strin
     Ok(commit_messages)
 }
 
-fn push_branch(remote: &str, branch: &str) -> Result<()> {
-    let git = Git::new();
-
-    // if we fail to push normal-like, let's try a force push before we give up
-    match git.push(vec![remote, branch]) {
-        Ok(_) => (),
-        Err(_e) => { git.push(vec![remote, branch, "-f"])?; }
-    }
-    Ok(())
-}
-
-fn needs_submitting(branch: &str) -> Result<bool> {
+fn needs_submitting(graph: &StackGraph, branch: &str) -> Result<bool> {
     let git = Git::new();
-    let parent = git.parent_of(&branch, BranchType::All)?;
+    let parent = graph.parent_of(&branch, BranchType::All);
     match parent {
         None => Ok(false), // No parent, so nothing to submit
         Some(p) => Ok(git.commit_diff(branch, &p)?.split("\n").all(|l| !l.is_empty())),  // If we have no diff, we don't need to submit
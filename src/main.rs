@@ -8,8 +8,10 @@ use candy::candy::{Candy};
 use candy::events::CandyEvent;
 use candy::events::CandyEvent::Select;
 use gr_git::{BranchType, ExecGit, Git};
-use gr::{initialize_gr, move_relative};
-use crate::gr::{merge, sync, reviews, submit, log, help, split};
+use gr_reviews::{MergeOrder, ReviewTestState};
+use candy::symbols::CROSS;
+use gr::{auth, initialize_gr, migrate_stack_parents, move_relative, move_onto};
+use crate::gr::{merge, sync, reviews, submit, log, help, split, oplog, record_operation, undo, sync_continue, sync_abort, fold, squash};
 use gr::submit::get_commit_message;
 use help::{show_usage, show_help};
 
@@ -42,6 +44,21 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
     let git = Git::new();
     let candy = Candy::new();
 
+    // Stacks used to be recorded via upstream tracking - import them once per repo
+    // Best effort - a failed import mustn't stop whatever was asked for
+    if let Ok(true) = git.in_repo() {
+        if let Err(e) = migrate_stack_parents(&git) {
+            println!("{} {}", "Couldn't import existing stacks:".yellow(), e);
+        }
+    }
+
+    // Snapshot the repo before anything that rewrites branches, so it can be undone
+    if is_mutating(&command, args) {
+        let mut cmdline = vec![command.clone()];
+        args.iter().rev().for_each(|a| cmdline.push(a.to_owned()));
+        record_operation(&format!("stk {}", cmdline.join(" ")))?;
+    }
+
     match command.as_str() {
         "bco" | "switch" => {
             let branch = args.first().unwrap_or(&select_branch()?).to_owned();
@@ -59,7 +76,7 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
                 },
             };
 
-            git.checkout(vec!["-t", &cur_branch, "-b", &branch])?;
+            git.create_branch(&branch, &cur_branch)?;
             println!("Created branch: {}", branch.green());
         }
         "cc" | "commit" => {
@@ -75,6 +92,9 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
                 None => show_usage(),
             }
         }
+        "auth" => {
+            auth(args.pop().as_deref())?;
+        }
         "init" => {
             initialize_gr()?;
             println!("Initialized gr config");
@@ -82,13 +102,29 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
         "log" => {
             log()?;
         }
+        "oplog" => {
+            oplog()?;
+        }
+        "undo" => {
+            undo(args.pop().as_deref())?;
+        }
         "merge" => {
             let conf = &config::read_config()?;
-            merge(&conf.code_review_tool, &conf.origin).await?;
+            let mut order = conf.merge_order.unwrap_or_default();
+            let mut when_ready = false;
+            while let Some(opt) = args.pop() {
+                match opt.as_str() {
+                    "--top-down" => order = MergeOrder::TopDown,
+                    "--bottom-up" => order = MergeOrder::BottomUp,
+                    "--when-ready" => when_ready = true,
+                    other => return Err(anyhow!("Unknown merge option '{}' - expected --top-down, --bottom-up or --when-ready", other)),
+                }
+            }
+            merge(&conf.review_tool(), &conf.origin, conf.merge_method.unwrap_or_default(), order, when_ready).await?;
         }
         "rv" | "reviews" => {
             let config = config::read_config()?;
-            let revs = reviews(&config.code_review_tool).await?;
+            let revs = reviews(&config.review_tool()).await?;
 
             for r in revs
             {
@@ -100,6 +136,14 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
                 println!("{} {}", r.title, format!("({})", r.state).yellow());
                 println!("  {} -> {}", r.branch.cyan(), r.base.magenta());
                 println!("  {}", url);
+                // Anything holding the review up
+                for t in &r.tests {
+                    match t.state {
+                        ReviewTestState::Passed => {},
+                        ReviewTestState::Pending => println!("  {} {}", "?".yellow(), t.name),
+                        ReviewTestState::Failed => println!("  {} {}", CROSS.red(), t.name),
+                    }
+                }
             }
         }
         "split" => {
@@ -107,12 +151,47 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
         }
         "submit" => {
             let cfg = config::read_config()?;
-            submit(&cfg.code_review_tool, &cfg.origin).await?;
+            submit(&cfg.review_tool(), &cfg.origin).await?;
         }
         "sync" => {
             println!("{}", "Syncing current stack...".green());
-            sync()?;
-            println!("{}", "Complete".green());
+            // Config is optional here - without it we just can't ask the review service about merges
+            let tool = config::read_config().ok().map(|c| c.review_tool());
+            sync(tool.as_ref()).await?;
+        }
+        "continue" => {
+            let tool = config::read_config().ok().map(|c| c.review_tool());
+            sync_continue(tool.as_ref()).await?;
+        }
+        "abort" => {
+            sync_abort()?;
+        }
+        "fold" => {
+            let tool = config::read_config().ok().map(|c| c.review_tool());
+            fold(tool.as_ref()).await?;
+        }
+        "squash" => {
+            let range = args.pop().ok_or(anyhow!("Missing range - stk squash <from>..<to>"))?;
+            let tool = config::read_config().ok().map(|c| c.review_tool());
+            squash(&range, tool.as_ref()).await?;
+        }
+        "move" => {
+            let opts = args.iter().rev().cloned().collect::<Vec<String>>();
+            match opts.iter().position(|a| a == "--onto") {
+                Some(i) => {
+                    let onto = opts.get(i + 1).ok_or(anyhow!("Missing branch - stk move --onto <branch>"))?;
+                    // Config is optional here - without it there are no reviews to retarget
+                    let tool = config::read_config().ok().map(|c| c.review_tool());
+                    move_onto(onto, opts.iter().any(|a| a == "--subtree"), tool.as_ref()).await?;
+                }
+                None => match opts.first() {
+                    Some(direction) => {
+                        move_relative(direction)?;
+                        println!("Checked out branch: {}", git.current_branch()?.green());
+                    }
+                    None => show_help("move"),
+                }
+            }
         }
         "top" | "up" | "down" | "bottom" | "bu" | "bd" => {
             move_relative(&command)?;
@@ -163,6 +242,13 @@ async fn process_command(command: String, args: &mut Vec<String>) -> Result<()>
     Ok(())
 }
 
+/// Commands which rewrite branches or stack metadata - these get an oplog entry
+fn is_mutating(command: &str, args: &[String]) -> bool {
+    match command {
+        "move" => args.iter().any(|a| a == "--onto"),
+        _ => matches!(command, "abort" | "bc" | "continue" | "create" | "cc" | "commit" | "fold" | "init" | "merge" | "split" | "squash" | "submit" | "sync"),
+    }
+}
 
 fn select_branch() -> Result<String> {
     let git = Git::new();